@@ -2,6 +2,10 @@ use std::path::Path;
 
 use clap::App;
 
+use diff::Output;
+use manifest::Format;
+use store::Layout;
+
 static ABOUT: &str = "
 A command-line tool for organizing files using links.
 
@@ -21,25 +25,162 @@ pub fn app() -> App<'static, 'static> {
         (about: ABOUT)
         (@subcommand init =>
             (about: "Creates a new hoard")
+            (@arg LAYOUT: -l --layout +takes_value {layout_exists}
+                "how the object store is laid out (fanout, flat)")
             (@arg NAME: "the name of the hoard"))
         (@subcommand add =>
             (about: "Adds objects to the hoard")
+            (@arg NAME: -n --name +takes_value "the name of the object, when adding a single file")
             (@arg PATH: ... {path_exists} "the path of the object"))
+        (@subcommand import =>
+            (about: "Imports a directory tree from outside the hoard, storing duplicates once")
+            (@arg SRC: +required {path_exists} "the directory to import")
+            (@arg DEST: "where to recreate the tree, by default a directory named after it")
+            (@arg MOVE: -m --move "moves the files instead of copying them"))
+        (@subcommand dupes =>
+            (about: "Lists files in the working tree with the same contents"))
+        (@subcommand dedupe =>
+            (about: "Replaces copies of the same contents with links to one object")
+            (@arg DRY_RUN: -n --("dry-run") "only shows what would be linked"))
+        (@subcommand similar =>
+            (about: "Lists images and recordings that look or sound alike")
+            (@arg OBJECT: "the path, name, or hash of the object to compare against")
+            (@arg THRESHOLD: -t --threshold +takes_value {number}
+                "the largest distance between similar objects (default 10)"))
+        (@subcommand export =>
+            (about: "Copies a view out of the hoard as real files, with a manifest and hashes")
+            (@arg VIEW: -v --view +takes_value "the directory of the layout to export, by default everything")
+            (@arg TO: -t --to +takes_value +required "the directory, or .tar or .zip archive, to create"))
+        (@subcommand remote =>
+            (@setting SubcommandRequiredElseHelp)
+            (about: "Manages other hoards to sync with")
+            (@subcommand add =>
+                (about: "Adds another hoard on a mounted path")
+                (@arg ENCRYPTED: -e --encrypted "creates an encrypted store at the path instead")
+                (@arg NAME: +required "the name of the remote")
+                (@arg PATH: +required "the root of the other hoard"))
+            (@subcommand ls =>
+                (about: "Lists the remotes")))
+        (@subcommand fetch =>
+            (about: "Copies the objects of a remote that are missing here")
+            (@arg REMOTE: +required "the name of the remote"))
+        (@subcommand pull =>
+            (about: "Copies missing objects from a remote and merges their names and metadata")
+            (@arg REMOTE: +required "the name of the remote"))
+        (@subcommand push =>
+            (about: "Copies missing objects to a remote and merges their names and metadata")
+            (@arg REMOTE: +required "the name of the remote"))
+        (@subcommand get =>
+            (about: "Copies the contents of absent objects from a remote")
+            (@arg FROM: -f --from +takes_value "the remote to copy from, by default the first that has them")
+            (@arg OBJECT: +required ... "the name, hash, or path of an object, or a query"))
+        (@subcommand drop =>
+            (about: "Removes the local contents of objects that enough remotes also have")
+            (@arg OBJECT: +required ... "the name, hash, or path of an object, or a query"))
+        (@subcommand whereis =>
+            (about: "Lists the hoards that hold the contents of an object")
+            (@arg OBJECT: +required "the name, hash, or path of the object"))
+        (@subcommand fsck =>
+            (about: "Checks the stored contents of every object against its hash"))
+        (@subcommand watch =>
+            (about: "Adds new files and records moves in the working tree as they happen")
+            (@arg QUIET: -q --quiet +takes_value {number}
+                "the seconds a new file is left alone before it is added (default 2)"))
+        (@subcommand history =>
+            (about: "Lists the manifests recorded by watch and snapshot"))
         (@subcommand mv =>
             (about: "Renames objects in the hoard")
-            (@arg NAME: ... "the unique name of the object"))
+            (@arg NAME: +required "the unique name of the object")
+            (@arg NEW_NAME: +required "the new name of the object"))
         (@subcommand rm =>
             (about: "Removes objects from the hoard")
-            (@arg NAME: ... "the unique name of the object"))
+            (@arg PURGE: -p --purge "also removes their contents, if enough remotes have a copy")
+            (@arg NAME: +required ... "the name, hash, or path of the object"))
+        (@subcommand snapshot =>
+            (about: "Records the links in the working tree as the manifest")
+            (@arg MERGE: -m --merge "only updates the objects whose paths changed, keeping the rest")
+            (@arg FILE: "the manifest to write, by default the one of the hoard"))
+        (@subcommand diff =>
+            (about: "Shows how the paths of objects differ between two manifests")
+            (@arg OUTPUT: -o --output +takes_value {output_exists}
+                "how to show the differences (human, unified, json)")
+            (@arg A: +required "a manifest file, the root of the hoard for its working tree, or @N for a history entry")
+            (@arg B: +required "the same for the other side"))
+        (@subcommand merge =>
+            (about: "Merges the changes two manifests made to the same base")
+            (@arg REPORT: -r --report "reports conflicts as JSON instead of marking them in the manifest")
+            (@arg TO: -t --to +takes_value "the manifest to write, by default OURS")
+            (@arg BASE: +required "a manifest file, the root of the hoard for its working tree, or @N for a history entry")
+            (@arg OURS: +required "the same for our side")
+            (@arg THEIRS: +required "the same for their side"))
         (@subcommand apply =>
             (about: "Syncs the repo to the index"))
         (@subcommand edit =>
-            (about: "Opens an editor and syncs the repo to the index"))
+            (about: "Opens an editor and syncs the repo to the index")
+            (@arg FORMAT: -f --format +takes_value {format_exists}
+                "the format to edit the manifest in (json, toml, yaml, tree)"))
+        (@subcommand manifest =>
+            (@setting SubcommandRequiredElseHelp)
+            (about: "Inspects the manifest")
+            (@subcommand validate =>
+                (about: "Checks the manifest for mistakes without applying it")))
         (@subcommand info =>
             (about: "Lists information about an object")
-            (@arg OBJECT: "the path, name, or hash of the object"))
+            (@arg OBJECT: +required "the path, name, or hash of the object"))
         (@subcommand query =>
-            (about: "Lists all objects that match the query")))
+            (about: "Lists all objects that match the query")
+            (@arg QUERY: ... "the terms of the query, such as 'tag:unread'"))
+        (@subcommand tag =>
+            (@setting SubcommandRequiredElseHelp)
+            (about: "Manages the tags of objects")
+            (@subcommand add =>
+                (about: "Adds tags to an object")
+                (@arg OBJECT: +required "the name or hash of the object")
+                (@arg TAG: +required ... "the tags to add"))
+            (@subcommand rm =>
+                (about: "Removes tags from an object")
+                (@arg OBJECT: +required "the name or hash of the object")
+                (@arg TAG: +required ... "the tags to remove"))
+            (@subcommand ls =>
+                (about: "Lists the tags of an object, or of every object")
+                (@arg OBJECT: "the name or hash of the object")))
+        (@subcommand meta =>
+            (@setting SubcommandRequiredElseHelp)
+            (about: "Manages the metadata fields of objects")
+            (@subcommand set =>
+                (about: "Sets a field of an object")
+                (@arg OBJECT: +required "the name or hash of the object")
+                (@arg KEY: +required "the name of the field")
+                (@arg VALUE: "the value of the field, or nothing to remove it"))
+            (@subcommand get =>
+                (about: "Prints the fields of an object")
+                (@arg OBJECT: +required "the name or hash of the object")
+                (@arg KEY: "the name of the field to print"))))
+}
+
+fn format_exists(input: String) -> std::result::Result<(), String> {
+    Format::from_name(&input)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn output_exists(input: String) -> std::result::Result<(), String> {
+    Output::from_name(&input)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn layout_exists(input: String) -> std::result::Result<(), String> {
+    Layout::from_name(&input)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn number(input: String) -> std::result::Result<(), String> {
+    input
+        .parse::<u32>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a number", input))
 }
 
 fn path_exists(input: String) -> std::result::Result<(), String> {
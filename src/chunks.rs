@@ -0,0 +1,152 @@
+//! Splits the contents of objects into chunks shared between objects,
+//! for hoards that keep several edits of the same large file, when the
+//! chunked store is enabled:
+//!
+//! ```toml
+//! [store]
+//! chunked = true
+//! ```
+//!
+//! Contents are split with FastCDC, whose cut points depend on the
+//! contents around them rather than on their offset, so that a small
+//! edit only changes the chunks around it. Every chunk is stored once in
+//! `.hoard/objects/chunks` under its own hash, and the list of chunks
+//! that make up an object is kept as a recipe in
+//! `.hoard/objects/recipes`, named after the hash of the whole contents,
+//! which stays the identity of the object.
+//!
+//! Chunked objects are part of the cold tier described in the `cold`
+//! module: after `apply`, objects that are not linked into the working
+//! tree are split into chunks, and `by-hash` only keeps the contents of
+//! the objects in use. Those are reassembled from their chunks when
+//! `apply` or `get` needs them, and act as a cache, as their chunks are
+//! kept until the object is dropped or removed.
+//!
+use std::io::{Read, Write};
+
+use fastcdc::v2020::StreamCDC;
+use hex;
+use sha2::{Digest, Sha256};
+
+use hoard::FileHash;
+use Result;
+
+/// The smallest, average and largest size of a chunk.
+const MIN: u32 = 64 * 1024;
+const AVG: u32 = 256 * 1024;
+const MAX: u32 = 1024 * 1024;
+
+/// The chunks that make up the contents of an object, in order.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Recipe {
+    pub size: u64,
+    pub chunks: Vec<FileHash>,
+}
+
+/// Returns the hash of a chunk.
+pub fn hash_of(data: &[u8]) -> Result<FileHash> {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    FileHash::from_str(&hex::encode(&hasher.result()[..]))
+}
+
+/// Splits the contents read from `reader` into chunks, passing each one
+/// to `store` along with its hash. Returns the recipe of the contents
+/// and their hash.
+pub fn split<R, F>(reader: R, mut store: F) -> Result<(Recipe, FileHash)>
+where
+    R: Read,
+    F: FnMut(&FileHash, &[u8]) -> Result<()>,
+{
+    let mut hasher = Sha256::new();
+    let mut recipe = Recipe::default();
+    for chunk in StreamCDC::new(reader, MIN, AVG, MAX) {
+        let chunk = chunk?;
+        let hash = hash_of(&chunk.data)?;
+        store(&hash, &chunk.data)?;
+        hasher.input(&chunk.data);
+        recipe.size += chunk.length as u64;
+        recipe.chunks.push(hash);
+    }
+    let hash = FileHash::from_str(&hex::encode(&hasher.result()[..]))?;
+    Ok((recipe, hash))
+}
+
+/// Writes the chunks of a recipe, read with `load`, into `writer`, and
+/// returns the hash of the contents. Fails if a chunk doesn't match its
+/// hash.
+pub fn join<W, F>(recipe: &Recipe, mut load: F, writer: &mut W) -> Result<FileHash>
+where
+    W: Write,
+    F: FnMut(&FileHash) -> Result<Vec<u8>>,
+{
+    let mut hasher = Sha256::new();
+    for hash in recipe.chunks.iter() {
+        let data = load(hash)?;
+        if hash_of(&data)? != *hash {
+            bail!("chunk {} is damaged", &hash[..12]);
+        }
+        hasher.input(&data);
+        writer.write_all(&data)?;
+    }
+    FileHash::from_str(&hex::encode(&hasher.result()[..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_split_join() {
+        let arg1 = noise(4 * 1024 * 1024, 1);
+        let mut arg2 = arg1.clone();
+        let edit = noise(100, 2);
+        arg2.splice(2_000_000..2_000_000, edit);
+
+        let mut stored = HashMap::new();
+        let (recipe1, hash1) = split(&arg1[..], |hash, data| {
+            stored.insert(hash.clone(), data.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        let (recipe2, hash2) = split(&arg2[..], |hash, data| {
+            stored.insert(hash.clone(), data.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+        let shared = recipe2
+            .chunks
+            .iter()
+            .filter(|hash| recipe1.chunks.contains(hash))
+            .count();
+        assert!(shared + 2 >= recipe2.chunks.len());
+        assert_eq!(recipe1.size, arg1.len() as u64);
+        assert_ne!(hash1, hash2);
+
+        let mut result = Vec::new();
+        let load = |hash: &FileHash| Ok(stored[hash].clone());
+        assert_eq!(join(&recipe2, load, &mut result).unwrap(), hash2);
+        assert_eq!(result, arg2);
+
+        let damaged = |hash: &FileHash| {
+            let mut data = stored[hash].clone();
+            data[0] ^= 1;
+            Ok(data)
+        };
+        assert!(join(&recipe1, damaged, &mut Vec::new()).is_err());
+    }
+}
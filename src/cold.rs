@@ -0,0 +1,95 @@
+//! Keeps the contents of objects that are not linked into the working
+//! tree compressed, when the `cold` tier is enabled:
+//!
+//! ```toml
+//! [store]
+//! cold = true
+//! ```
+//!
+//! After `apply`, every object whose contents have no other link than
+//! the one in the store is compressed with zstd into
+//! `.hoard/objects/cold`, under the same layout as `by-hash` with a
+//! `.zst` extension. Contents that don't shrink by at least a tenth,
+//! such as most pictures and videos, are left as they are.
+//!
+//! Cold objects are listed as absent, with the attributes of their
+//! contents kept in their metadata, and are decompressed again by
+//! `apply` when the manifest gives them a path, or by `get`. Their hash
+//! is always that of the uncompressed contents, which `fsck` checks.
+//!
+use std::io::{Read, Write};
+
+use hex;
+use sha2::{Digest, Sha256};
+use zstd::stream;
+
+use hoard::FileHash;
+use Result;
+
+/// The zstd level used, which favours size over speed as cold objects
+/// are rarely written.
+const LEVEL: i32 = 9;
+
+/// The number of bytes decompressed at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// Whether contents of the given size, compressed to the other, are worth
+/// keeping compressed.
+pub fn worthwhile(size: u64, compressed: u64) -> bool {
+    compressed * 10 <= size * 9
+}
+
+/// Compresses the contents read from `reader` into `writer`.
+pub fn compress<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+    stream::copy_encode(reader, writer, LEVEL)?;
+    Ok(())
+}
+
+/// Decompresses the contents read from `reader` into `writer`, and
+/// returns their hash.
+pub fn decompress<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<FileHash> {
+    let mut decoder = stream::read::Decoder::new(reader)?;
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; CHUNK];
+    loop {
+        let read = decoder.read(&mut chunk)?;
+        if read == 0 {
+            return FileHash::from_str(&hex::encode(&hasher.result()[..]));
+        }
+        hasher.input(&chunk[..read]);
+        writer.write_all(&chunk[..read])?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn cold_round_trip() {
+        let arg1: Vec<u8> = b"a page of a comic\n"
+            .iter()
+            .cycle()
+            .take(CHUNK * 3)
+            .cloned()
+            .collect();
+        let mut hasher = Sha256::new();
+        hasher.input(&arg1);
+        let arg2 = FileHash::from_str(&hex::encode(&hasher.result()[..])).unwrap();
+
+        let mut compressed = Vec::new();
+        compress(&mut &arg1[..], &mut compressed).unwrap();
+        let mut decompressed = Vec::new();
+        let result = decompress(&mut &compressed[..], &mut decompressed).unwrap();
+
+        assert!(worthwhile(arg1.len() as u64, compressed.len() as u64));
+        assert_eq!(result, arg2);
+        assert_eq!(decompressed, arg1);
+        let mut damaged = compressed.clone();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0xff;
+        let hash = decompress(&mut &damaged[..], &mut io::sink());
+        assert!(hash.map(|hash| hash != arg2).unwrap_or(true));
+    }
+}
@@ -0,0 +1,146 @@
+//! Per-repository settings, read from `.hoard/config.toml`.
+//!
+//! ```toml
+//! [manifest]
+//! format = "yaml"
+//!
+//! [naming]
+//! policy = "stem"
+//!
+//! [store]
+//! layout = "flat"
+//! cold = true
+//! chunked = true
+//!
+//! [remotes.server]
+//! path = "/mnt/server/media"
+//!
+//! [copies]
+//! min = 2
+//! ```
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use error::ResultExt;
+use manifest::Format;
+use naming::Policy;
+use store::Layout;
+use Result;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub manifest: ManifestConfig,
+    pub naming: NamingConfig,
+    pub store: StoreConfig,
+    pub remotes: BTreeMap<String, RemoteConfig>,
+    pub copies: CopiesConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestConfig {
+    /// The format used when a new manifest is written.
+    pub format: Option<Format>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamingConfig {
+    /// How added objects are named, as described in the `naming` module.
+    pub policy: Policy,
+    /// The template used by the `template` policy.
+    pub template: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// How the object store is laid out, as described in the `store`
+    /// module. It is set when the hoard is created.
+    pub layout: Layout,
+    /// Whether unlinked objects are kept compressed, as described in the
+    /// `cold` module.
+    pub cold: bool,
+    /// Whether unlinked objects are split into chunks shared between
+    /// objects instead, as described in the `chunks` module.
+    pub chunked: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// The root of the other hoard, as described in the `remote` module.
+    pub path: PathBuf,
+    /// The id of the other hoard, recorded when it was added.
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CopiesConfig {
+    /// The number of copies every object should have, as described in
+    /// the `copies` module.
+    pub min: u32,
+    pub rules: Vec<CopiesRule>,
+}
+
+impl Default for CopiesConfig {
+    fn default() -> Self {
+        CopiesConfig {
+            min: 1,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CopiesRule {
+    /// The objects the rule applies to.
+    pub query: String,
+    pub min: u32,
+}
+
+impl Config {
+    /// Loads the configuration of the hoard at the given root, falling
+    /// back to the defaults if there is no configuration file.
+    pub fn load<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let path = root.as_ref().join(".hoard/config.toml");
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let text = fs::read_to_string(&path).with_path(&path)?;
+        let config = toml::from_str(&text).with_path(&path)?;
+        Ok(config)
+    }
+
+    /// Adds a remote to the configuration file of the hoard at the given
+    /// root. The file is appended to, so that its comments are kept.
+    pub fn add_remote<P: AsRef<Path>>(root: P, name: &str, path: &Path, id: &str) -> Result<()> {
+        let config = root.as_ref().join(".hoard/config.toml");
+        let mut text = if config.exists() {
+            fs::read_to_string(&config).with_path(&config)?
+        } else {
+            String::new()
+        };
+        if !text.is_empty() {
+            if !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push('\n');
+        }
+        let path = toml::Value::String(path.to_string_lossy().into_owned());
+        text.push_str(&format!(
+            "[remotes.{}]\npath = {}\nid = \"{}\"\n",
+            name, path, id
+        ));
+        fs::write(&config, text).with_path(&config)?;
+        Ok(())
+    }
+}
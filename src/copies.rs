@@ -0,0 +1,135 @@
+//! Tracks which hoards hold the contents of each object, and how many
+//! copies every object should have.
+//!
+//! Every hoard is identified by the id in `.hoard/id`, which is created
+//! the first time it is needed. The metadata of an object records, by
+//! id, whether each hoard held its contents when it was last seen, so
+//! that `whereis` can also report on remotes that are not mounted.
+//!
+//! The minimum number of copies, counting this hoard, is set for every
+//! object, and raised for the objects matching a query:
+//!
+//! ```toml
+//! [copies]
+//! min = 2
+//!
+//! [[copies.rules]]
+//! query = "tag:family"
+//! min = 3
+//! ```
+//!
+//! Contents are only removed by `drop` or `rm --purge` when the remotes
+//! are confirmed to hold enough identical copies of them.
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hex;
+use sha2::{Digest, Sha256};
+
+use config::CopiesConfig;
+use error::ResultExt;
+use metadata::Location;
+use query::{Attributes, Query};
+use Result;
+
+/// Returns the id of the hoard at the given root, creating it if the
+/// hoard doesn't have one yet.
+pub fn store_id(root: &Path) -> Result<String> {
+    let path = root.join(".hoard/id");
+    if path.exists() {
+        let id = fs::read_to_string(&path).with_path(&path)?;
+        return Ok(id.trim().to_string());
+    }
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let mut hasher = Sha256::new();
+    hasher.input(root.to_string_lossy().as_bytes());
+    hasher.input(nanos.to_string().as_bytes());
+    hasher.input(process::id().to_string().as_bytes());
+    let id = hex::encode(&hasher.result()[..8]);
+    fs::write(&path, format!("{}\n", id)).with_path(&path)?;
+    Ok(id)
+}
+
+/// Returns the current time in seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the number of copies an object with the given attributes
+/// should have, which is the highest minimum that applies to it.
+pub fn required(config: &CopiesConfig, attributes: &Attributes) -> Result<usize> {
+    let mut min = config.min;
+    for rule in config.rules.iter() {
+        if rule.min > min && Query::parse(&rule.query)?.matches(attributes) {
+            min = rule.min;
+        }
+    }
+    Ok(min as usize)
+}
+
+/// Merges their locations into ours, keeping the most recent record of
+/// every hoard.
+pub fn merge(ours: &mut BTreeMap<String, Location>, theirs: &BTreeMap<String, Location>) {
+    for (id, location) in theirs.iter() {
+        let newer = ours.get(id).is_none_or(|ours| location.since > ours.since);
+        if newer {
+            ours.insert(id.clone(), *location);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::CopiesRule;
+
+    #[test]
+    fn copies_required() {
+        let arg1 = CopiesConfig {
+            min: 2,
+            rules: vec![
+                CopiesRule {
+                    query: "tag:family".to_string(),
+                    min: 3,
+                },
+                CopiesRule {
+                    query: "kind:video".to_string(),
+                    min: 1,
+                },
+            ],
+        };
+        let mut arg2 = Attributes::new();
+        arg2.insert("kind", "video");
+        let mut arg3 = arg2.clone();
+        arg3.insert("tag", "family");
+
+        assert_eq!(required(&arg1, &arg2).unwrap(), 2);
+        assert_eq!(required(&arg1, &arg3).unwrap(), 3);
+    }
+
+    #[test]
+    fn copies_merge() {
+        let location = |present, since| Location { present, since };
+        let mut arg1 = BTreeMap::new();
+        arg1.insert("a".to_string(), location(true, 10));
+        arg1.insert("b".to_string(), location(true, 30));
+        let mut arg2 = BTreeMap::new();
+        arg2.insert("a".to_string(), location(false, 20));
+        arg2.insert("b".to_string(), location(false, 20));
+        arg2.insert("c".to_string(), location(true, 5));
+
+        merge(&mut arg1, &arg2);
+
+        assert_eq!(arg1["a"], location(false, 20));
+        assert_eq!(arg1["b"], location(true, 30));
+        assert_eq!(arg1["c"], location(true, 5));
+    }
+}
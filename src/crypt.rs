@@ -0,0 +1,519 @@
+//! An object store whose contents are encrypted, for keeping copies on
+//! shared or external disks.
+//!
+//! An encrypted store is created by `remote add --encrypted`, and keeps
+//! everything in the `.hoard` directory at its root:
+//!
+//! - `encrypted.json`, the parameters of the key derivation
+//! - `blobs/ab/cdef…`, the encrypted contents
+//!
+//! Keys are derived from a passphrase with Argon2id. The passphrase is
+//! read from `HOARD_PASSPHRASE`, or asked for when it is not set. The
+//! contents are stored under a keyed hash of their hash, so that the
+//! store doesn't reveal which files it holds, and are encrypted with
+//! ChaCha20-Poly1305 in chunks, so that large files are streamed rather
+//! than read into memory. Every object starts with its hash, encrypted,
+//! so that the store can be listed.
+//!
+//! Names and metadata are never written to the store. Contents are only
+//! pushed to it, and copied back into the hoard, decrypted, by `get`.
+//!
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit};
+use hex;
+use hmac::{Hmac, Mac};
+use rpassword;
+use serde_json;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use copies;
+use error::ResultExt;
+use hoard::FileHash;
+use store::Store;
+use Result;
+
+/// The environment variable holding the passphrase of encrypted stores.
+pub const PASSPHRASE: &str = "HOARD_PASSPHRASE";
+
+/// The start of every encrypted object, followed by the nonce prefix.
+const MAGIC: &[u8] = b"hoard-crypt1";
+
+/// The number of bytes encrypted at a time, and the size of the tag
+/// that follows each chunk.
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+
+/// The message whose keyed hash is kept to check the passphrase.
+const CHECK: &[u8] = b"hoard passphrase check";
+
+/// How the keys of an encrypted store are derived from its passphrase.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct KeyParams {
+    kdf: String,
+    salt: String,
+    /// The memory used in KiB, the number of passes and of lanes.
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+    /// A keyed hash of a fixed message, to tell a wrong passphrase.
+    check: String,
+}
+
+/// The keys of an encrypted store.
+struct Keys {
+    cipher: ChaCha20Poly1305,
+    names: Vec<u8>,
+}
+
+/// Derives the key for the contents and the key for their names from
+/// the passphrase.
+fn derive(passphrase: &str, params: &KeyParams) -> Result<Keys> {
+    if params.kdf != "argon2id" {
+        bail!("unknown key derivation '{}'", params.kdf);
+    }
+    let salt = hex::decode(&params.salt)?;
+    let argon2 = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(
+            params.memory,
+            params.iterations,
+            params.parallelism,
+            Some(64),
+        )
+        .map_err(|e| format_err!("key derivation: {}", e))?,
+    );
+    let mut key = [0u8; 64];
+    argon2
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| format_err!("key derivation: {}", e))?;
+    Ok(Keys {
+        cipher: ChaCha20Poly1305::new(Key::from_slice(&key[..32])),
+        names: key[32..].to_vec(),
+    })
+}
+
+/// Returns the keyed hash of the data in hex.
+fn mac(key: &[u8], data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any size");
+    mac.input(data);
+    hex::encode(&mac.result().code()[..])
+}
+
+/// Reads the passphrase from the environment, or asks for it, twice if
+/// `confirm` is set.
+fn passphrase(prompt: &str, confirm: bool) -> Result<String> {
+    let passphrase = match env::var(PASSPHRASE) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase = rpassword::prompt_password(prompt)?;
+            if confirm && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+                bail!("the passphrases do not match");
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        bail!("the passphrase cannot be empty");
+    }
+    Ok(passphrase)
+}
+
+/// Whether the directory at the given root is an encrypted store.
+pub fn is_encrypted(root: &Path) -> bool {
+    root.join(".hoard/encrypted.json").is_file()
+}
+
+pub struct EncryptedStore {
+    path: PathBuf,
+    keys: Keys,
+}
+
+impl EncryptedStore {
+    /// Creates an encrypted store at the given root, asking for a new
+    /// passphrase.
+    pub fn init(root: &Path) -> Result<Self> {
+        let prompt = format!("New passphrase for {}: ", root.display());
+        let passphrase = passphrase(&prompt, true)?;
+        let (memory, iterations) = (Params::DEFAULT_M_COST, Params::DEFAULT_T_COST);
+        EncryptedStore::create(root, &passphrase, memory, iterations)
+    }
+
+    /// Opens the encrypted store at the given root, asking for its
+    /// passphrase.
+    pub fn unlock(root: &Path) -> Result<Self> {
+        let prompt = format!("Passphrase for {}: ", root.display());
+        EncryptedStore::open(root, &passphrase(&prompt, false)?)
+    }
+
+    /// Creates the store, deriving its keys with the given memory in KiB
+    /// and number of passes.
+    fn create(root: &Path, passphrase: &str, memory: u32, iterations: u32) -> Result<Self> {
+        let dir = root.join(".hoard");
+        if dir.exists() {
+            bail!("'{}' already holds a hoard or a store", root.display());
+        }
+        fs::create_dir_all(dir.join("blobs")).with_path(&dir)?;
+
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut params = KeyParams {
+            kdf: "argon2id".to_string(),
+            salt: hex::encode(&salt[..]),
+            memory,
+            iterations,
+            parallelism: Params::DEFAULT_P_COST,
+            check: String::new(),
+        };
+        let keys = derive(passphrase, &params)?;
+        params.check = mac(&keys.names, CHECK);
+        let path = dir.join("encrypted.json");
+        fs::write(&path, serde_json::to_string_pretty(&params)?).with_path(&path)?;
+        copies::store_id(root)?;
+
+        Ok(EncryptedStore {
+            path: dir.join("blobs"),
+            keys,
+        })
+    }
+
+    fn open(root: &Path, passphrase: &str) -> Result<Self> {
+        let path = root.join(".hoard/encrypted.json");
+        let file = fs::File::open(&path).with_path(&path)?;
+        let params: KeyParams = serde_json::from_reader(file).with_path(&path)?;
+        let keys = derive(passphrase, &params)?;
+        if mac(&keys.names, CHECK) != params.check {
+            bail!("wrong passphrase for {}", root.display());
+        }
+        Ok(EncryptedStore {
+            path: root.join(".hoard/blobs"),
+            keys,
+        })
+    }
+
+    /// Returns where the contents with the given hash are stored.
+    fn blob(&self, hash: &FileHash) -> PathBuf {
+        let name = mac(&self.keys.names, hash.as_bytes());
+        self.path.join(&name[..2]).join(&name[2..])
+    }
+}
+
+impl Store for EncryptedStore {
+    fn has(&self, hash: &FileHash) -> bool {
+        self.blob(hash).is_file()
+    }
+
+    fn get(&self, hash: &FileHash, dst: &Path) -> Result<()> {
+        let src = self.blob(hash);
+        let mut reader = io::BufReader::new(fs::File::open(&src).with_path(&src)?);
+        let tmp = dst.with_extension("tmp");
+        let mut writer = io::BufWriter::new(fs::File::create(&tmp).with_path(&tmp)?);
+        let result = decrypt(&self.keys.cipher, hash, &mut reader, &mut writer)
+            .and_then(|decrypted| Ok(writer.flush().map(|_| decrypted)?));
+        drop(writer);
+        match result {
+            Ok(ref decrypted) if decrypted == hash => {
+                fs::rename(&tmp, dst).with_path(dst)?;
+                Ok(())
+            }
+            Ok(_) => {
+                fs::remove_file(&tmp).with_path(&tmp)?;
+                bail!("{}: copy does not match its hash", src.display())
+            }
+            Err(e) => {
+                fs::remove_file(&tmp).with_path(&tmp)?;
+                Err(format_err!("{}: {}", src.display(), e))
+            }
+        }
+    }
+
+    fn put(&mut self, src: &Path, hash: &FileHash) -> Result<()> {
+        let dst = self.blob(hash);
+        if dst.is_file() {
+            return Ok(());
+        }
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_path(parent)?;
+        }
+        let mut reader = io::BufReader::new(fs::File::open(src).with_path(src)?);
+        let tmp = dst.with_extension("tmp");
+        let mut writer = io::BufWriter::new(fs::File::create(&tmp).with_path(&tmp)?);
+        let encrypted = encrypt(&self.keys.cipher, hash, &mut reader, &mut writer)?;
+        writer.flush().with_path(&tmp)?;
+        drop(writer);
+        if encrypted != *hash {
+            fs::remove_file(&tmp).with_path(&tmp)?;
+            bail!("{}: contents do not match their hash", src.display());
+        }
+        fs::rename(&tmp, &dst).with_path(&dst)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &FileHash) -> Result<()> {
+        let path = self.blob(hash);
+        if path.is_file() {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<FileHash>> {
+        let mut hashes = Vec::new();
+        for entry in WalkDir::new(&self.path) {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type().is_file() || path.extension().is_some() {
+                continue;
+            }
+            let mut reader = fs::File::open(path).with_path(path)?;
+            let (_, hash) = start(&self.keys.cipher, &mut reader).with_path(path)?;
+            if self.blob(&hash) == path {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    fn verify(&self, hash: &FileHash) -> Result<bool> {
+        let src = self.blob(hash);
+        let mut reader = match fs::File::open(&src) {
+            Ok(file) => io::BufReader::new(file),
+            Err(_) => return Ok(false),
+        };
+        let decrypted = decrypt(&self.keys.cipher, hash, &mut reader, &mut io::sink());
+        Ok(decrypted.ok().as_ref() == Some(hash))
+    }
+}
+
+/// Reads up to `size` bytes, stopping early only at the end.
+fn read_chunk<R: Read>(reader: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Encrypts the contents read from `reader` into `writer`, bound to the
+/// given hash, and returns the hash of what was read.
+fn encrypt<R: Read, W: Write>(
+    cipher: &ChaCha20Poly1305,
+    hash: &FileHash,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<FileHash> {
+    let mut prefix = [0u8; 7];
+    OsRng.fill_bytes(&mut prefix);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&prefix)?;
+
+    let aad = hash.as_bytes();
+    let mut stream = EncryptorBE32::from_aead(cipher.clone(), GenericArray::from_slice(&prefix));
+    let encrypted = stream
+        .encrypt_next(aad)
+        .map_err(|_| format_err!("could not encrypt"))?;
+    writer.write_all(&encrypted)?;
+    let mut hasher = Sha256::new();
+    let mut chunk = read_chunk(reader, CHUNK)?;
+    loop {
+        hasher.input(&chunk);
+        let next = read_chunk(reader, CHUNK)?;
+        let payload = Payload { msg: &chunk, aad };
+        if next.is_empty() {
+            let encrypted = stream
+                .encrypt_last(payload)
+                .map_err(|_| format_err!("could not encrypt"))?;
+            writer.write_all(&encrypted)?;
+            return FileHash::from_str(&hex::encode(&hasher.result()[..]));
+        }
+        let encrypted = stream
+            .encrypt_next(payload)
+            .map_err(|_| format_err!("could not encrypt"))?;
+        writer.write_all(&encrypted)?;
+        chunk = next;
+    }
+}
+
+/// Starts decrypting an object, returning the stream and the hash the
+/// object was stored under.
+fn start<R: Read>(
+    cipher: &ChaCha20Poly1305,
+    reader: &mut R,
+) -> Result<(DecryptorBE32<ChaCha20Poly1305>, FileHash)> {
+    let header = read_chunk(reader, MAGIC.len() + 7)?;
+    if header.len() < MAGIC.len() + 7 || &header[..MAGIC.len()] != MAGIC {
+        bail!("not an encrypted object");
+    }
+    let prefix = GenericArray::from_slice(&header[MAGIC.len()..]);
+    let mut stream = DecryptorBE32::from_aead(cipher.clone(), prefix);
+    let hash = stream
+        .decrypt_next(&read_chunk(reader, 64 + TAG)?[..])
+        .map_err(|_| format_err!("encrypted object is damaged"))?;
+    let hash = FileHash::from_str(&String::from_utf8(hash)?)?;
+    Ok((stream, hash))
+}
+
+/// Decrypts the contents read from `reader` into `writer`, and returns
+/// their hash. Fails if the contents were changed, cut short, or belong
+/// to another hash.
+fn decrypt<R: Read, W: Write>(
+    cipher: &ChaCha20Poly1305,
+    hash: &FileHash,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<FileHash> {
+    let (mut stream, stored) = start(cipher, reader)?;
+    if stored != *hash {
+        bail!("encrypted object holds other contents");
+    }
+
+    let aad = hash.as_bytes();
+    let mut hasher = Sha256::new();
+    let mut chunk = read_chunk(reader, CHUNK + TAG)?;
+    loop {
+        let next = read_chunk(reader, CHUNK + TAG)?;
+        let payload = Payload { msg: &chunk, aad };
+        let decrypted = if next.is_empty() {
+            let decrypted = stream
+                .decrypt_last(payload)
+                .map_err(|_| format_err!("encrypted object is damaged"))?;
+            hasher.input(&decrypted);
+            writer.write_all(&decrypted)?;
+            return FileHash::from_str(&hex::encode(&hasher.result()[..]));
+        } else {
+            stream
+                .decrypt_next(payload)
+                .map_err(|_| format_err!("encrypted object is damaged"))?
+        };
+        hasher.input(&decrypted);
+        writer.write_all(&decrypted)?;
+        chunk = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn cipher() -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&[7u8; 32]))
+    }
+
+    fn hash_of(data: &[u8]) -> FileHash {
+        FileHash::from_str(&hex::encode(&Sha256::digest(data)[..])).unwrap()
+    }
+
+    #[test]
+    fn crypt_round_trip() {
+        let arg1: Vec<u8> = (0..CHUNK * 2 + 100).map(|i| i as u8).collect();
+        let arg2 = hash_of(&arg1);
+
+        let mut encrypted = Vec::new();
+        let result = encrypt(&cipher(), &arg2, &mut &arg1[..], &mut encrypted).unwrap();
+        let mut decrypted = Vec::new();
+        let hash = decrypt(&cipher(), &arg2, &mut &encrypted[..], &mut decrypted).unwrap();
+
+        assert_eq!(result, arg2);
+        assert_eq!(hash, arg2);
+        assert_eq!(decrypted, arg1);
+        let (_, stored) = start(&cipher(), &mut &encrypted[..]).unwrap();
+        assert_eq!(stored, arg2);
+        assert!(!encrypted.windows(64).any(|w| w == &arg1[..64]));
+    }
+
+    #[test]
+    fn crypt_damaged() {
+        let arg1 = vec![1u8; CHUNK + 10];
+        let arg2 = hash_of(&arg1);
+        let mut encrypted = Vec::new();
+        encrypt(&cipher(), &arg2, &mut &arg1[..], &mut encrypted).unwrap();
+
+        let mut changed = encrypted.clone();
+        changed[MAGIC.len() + 20] ^= 1;
+        let truncated = &encrypted[..MAGIC.len() + 7 + 64 + TAG + CHUNK + TAG];
+        let other = hash_of(b"other");
+
+        let decrypt = |data: &[u8], hash: &FileHash| {
+            decrypt(&cipher(), hash, &mut &data[..], &mut io::sink())
+        };
+        assert!(decrypt(&changed, &arg2).is_err());
+        assert!(decrypt(truncated, &arg2).is_err());
+        assert!(decrypt(&encrypted, &other).is_err());
+        assert!(decrypt(&encrypted, &arg2).is_ok());
+    }
+
+    #[test]
+    fn crypt_derive() {
+        let arg1 = KeyParams {
+            kdf: "argon2id".to_string(),
+            salt: "00112233445566778899aabbccddeeff".to_string(),
+            memory: 8,
+            iterations: 1,
+            parallelism: 1,
+            check: String::new(),
+        };
+
+        let result1 = derive("correct horse", &arg1).unwrap();
+        let result2 = derive("correct horse", &arg1).unwrap();
+        let result3 = derive("battery staple", &arg1).unwrap();
+
+        assert_eq!(mac(&result1.names, CHECK), mac(&result2.names, CHECK));
+        assert_ne!(mac(&result1.names, CHECK), mac(&result3.names, CHECK));
+    }
+
+    #[test]
+    fn crypt_passphrase_empty() {
+        env::set_var(PASSPHRASE, "");
+
+        let result = passphrase("", true);
+
+        env::remove_var(PASSPHRASE);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn crypt_store() {
+        let dir = env::temp_dir().join(format!("hoard-crypt-store-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("src");
+        fs::write(&src, "contents").unwrap();
+        let arg1 = hash_of(b"contents");
+        let other = hash_of(b"other");
+        let root = dir.join("store");
+
+        let mut store = EncryptedStore::create(&root, "correct horse", 8, 1).unwrap();
+        store.put(&src, &arg1).unwrap();
+        let mismatch = store.put(&src, &other).is_err() && !store.has(&other);
+        let store = EncryptedStore::open(&root, "correct horse").unwrap();
+        let wrong = EncryptedStore::open(&root, "battery staple");
+
+        let result = store.list().unwrap();
+
+        let dst = dir.join("dst");
+        store.get(&arg1, &dst).unwrap();
+        let text = fs::read_to_string(&dst).unwrap();
+        fs::write(store.blob(&arg1), "damaged").unwrap();
+        let damaged = store.get(&arg1, &dir.join("damaged"));
+        let leftovers: Vec<_> = WalkDir::new(&dir)
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .filter(|path| path.extension().map(|e| e == "tmp").unwrap_or(false))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, vec![arg1.clone()]);
+        assert_eq!(text, "contents");
+        assert!(mismatch);
+        assert!(wrong.is_err());
+        assert!(damaged.is_err());
+        assert!(leftovers.is_empty());
+    }
+}
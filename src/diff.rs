@@ -0,0 +1,285 @@
+//! Compares two desired or actual states of a hoard, for `diff`.
+//!
+//! Each side is a manifest file, the working tree, or an entry of the
+//! history as `@N`. The changes `apply` would make to turn the first into
+//! the second are grouped by object: the paths an object gains, the ones
+//! it loses, and the moves between them, along with the objects that
+//! only one side gives paths to.
+//!
+//! When an object both loses and gains paths, a lost path is paired
+//! with a gained one of the same file name first, such as when a file is
+//! moved to another directory, and the rest are paired in order.
+//!
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use serde_json;
+
+use state::{self, ChangeType, Index, State};
+use Result;
+
+/// How to show the differences.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Human,
+    Unified,
+    Json,
+}
+
+impl Output {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "human" => Ok(Output::Human),
+            "unified" => Ok(Output::Unified),
+            "json" => Ok(Output::Json),
+            _ => bail!("unknown diff output '{}'", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// The paths of an object that differ between both sides.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Paths {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub moved: Vec<Move>,
+}
+
+impl Paths {
+    /// Pairs the paths an object lost with the ones it gained into moves.
+    fn new(mut added: Vec<PathBuf>, removed: Vec<PathBuf>) -> Self {
+        let mut paths = Paths::default();
+        let mut unpaired = Vec::new();
+        for from in removed {
+            let same = added
+                .iter()
+                .position(|to| to.file_name() == from.file_name());
+            match same {
+                Some(i) => paths.moved.push(Move {
+                    from,
+                    to: added.remove(i),
+                }),
+                None => unpaired.push(from),
+            }
+        }
+
+        let mut added = added.into_iter();
+        for from in unpaired {
+            match added.next() {
+                Some(to) => paths.moved.push(Move { from, to }),
+                None => paths.removed.push(from),
+            }
+        }
+        paths.added.extend(added);
+        paths.moved.sort_by(|a, b| a.from.cmp(&b.from));
+        paths
+    }
+}
+
+/// The differences between two states, from the first to the second.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Diff {
+    /// The objects only the first side gives paths to, and their paths.
+    pub removed: BTreeMap<String, BTreeSet<PathBuf>>,
+    /// The objects only the second side gives paths to.
+    pub added: BTreeMap<String, BTreeSet<PathBuf>>,
+    pub changed: BTreeMap<String, Paths>,
+}
+
+impl Diff {
+    /// Compares two states whose paths are relative to the root of the
+    /// hoard.
+    pub fn new(a: &State, b: &State, index: &Index) -> Self {
+        let mut diff = Diff::default();
+        for (name, paths) in a.inner.iter().filter(|(_, paths)| !paths.is_empty()) {
+            if b.inner.get(name).is_none_or(BTreeSet::is_empty) {
+                diff.removed.insert(name.clone(), paths.clone());
+            }
+        }
+        for (name, paths) in b.inner.iter().filter(|(_, paths)| !paths.is_empty()) {
+            if a.inner.get(name).is_none_or(BTreeSet::is_empty) {
+                diff.added.insert(name.clone(), paths.clone());
+            }
+        }
+
+        // Only the objects named in the desired state are resolved, so the
+        // objects of the first side are all named in it, without paths if
+        // the second side lacks them.
+        let mut desire = State {
+            inner: b.inner.clone(),
+            extra: BTreeSet::new(),
+        };
+        for name in a.inner.keys() {
+            desire.inner.entry(name.clone()).or_default();
+        }
+        let actual = State {
+            inner: a.inner.clone(),
+            extra: BTreeSet::new(),
+        };
+
+        let mut added: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+        let mut removed: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+        let changes = state::resolve(&desire, &actual, index);
+        for change in changes.iter() {
+            let path = change.path().to_path_buf();
+            match *change.change_type() {
+                ChangeType::Create(ref object) => {
+                    added.entry(object.name()).or_default().push(path);
+                }
+                ChangeType::Delete(ref object) => {
+                    removed.entry(object.name()).or_default().push(path);
+                }
+                ChangeType::Modify(ref new, ref old) => {
+                    added.entry(new.name()).or_default().push(path.clone());
+                    removed.entry(old.name()).or_default().push(path);
+                }
+                ChangeType::Ignore => {}
+            }
+        }
+
+        let names: BTreeSet<&str> = added.keys().chain(removed.keys()).cloned().collect();
+        for name in names {
+            if diff.removed.contains_key(name) || diff.added.contains_key(name) {
+                continue;
+            }
+            let paths = Paths::new(
+                added.remove(name).unwrap_or_default(),
+                removed.remove(name).unwrap_or_default(),
+            );
+            diff.changed.insert(name.to_string(), paths);
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty() && self.changed.is_empty()
+    }
+
+    /// Shows the differences, labelling both sides in the unified form.
+    pub fn render(&self, output: Output, a: &str, b: &str) -> Result<String> {
+        match output {
+            Output::Human => Ok(self.human()),
+            Output::Unified => Ok(self.unified(a, b)),
+            Output::Json => Ok(serde_json::to_string_pretty(self)? + "\n"),
+        }
+    }
+
+    fn human(&self) -> String {
+        let mut text = String::new();
+        for (name, paths) in self.removed.iter() {
+            writeln!(text, "removed: {} ({} path(s))", name, paths.len()).unwrap();
+        }
+        for (name, paths) in self.added.iter() {
+            writeln!(text, "added: {} ({} path(s))", name, paths.len()).unwrap();
+        }
+        for (name, paths) in self.changed.iter() {
+            for change in paths.moved.iter() {
+                let (from, to) = (change.from.display(), change.to.display());
+                writeln!(text, "{}: moved {} -> {}", name, from, to).unwrap();
+            }
+            for path in paths.added.iter() {
+                writeln!(text, "{}: added {}", name, path.display()).unwrap();
+            }
+            for path in paths.removed.iter() {
+                writeln!(text, "{}: removed {}", name, path.display()).unwrap();
+            }
+        }
+        text
+    }
+
+    fn unified(&self, a: &str, b: &str) -> String {
+        let mut text = String::new();
+        if self.is_empty() {
+            return text;
+        }
+        writeln!(text, "--- {}\n+++ {}", a, b).unwrap();
+        for (name, paths) in self.removed.iter() {
+            writeln!(text, "@@ {} @@", name).unwrap();
+            for path in paths.iter() {
+                writeln!(text, "-{}", path.display()).unwrap();
+            }
+        }
+        for (name, paths) in self.added.iter() {
+            writeln!(text, "@@ {} @@", name).unwrap();
+            for path in paths.iter() {
+                writeln!(text, "+{}", path.display()).unwrap();
+            }
+        }
+        for (name, paths) in self.changed.iter() {
+            writeln!(text, "@@ {} @@", name).unwrap();
+            for change in paths.moved.iter() {
+                writeln!(text, "-{}\n+{}", change.from.display(), change.to.display()).unwrap();
+            }
+            for path in paths.removed.iter() {
+                writeln!(text, "-{}", path.display()).unwrap();
+            }
+            for path in paths.added.iter() {
+                writeln!(text, "+{}", path.display()).unwrap();
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn diff_paths_pairs_moves() {
+        let arg1 = paths(&["b/one.jpg", "c/renamed.jpg", "d/copy.jpg"]);
+        let arg2 = paths(&["a/one.jpg", "a/two.jpg"]);
+        let result = Paths::new(arg1, arg2);
+
+        let moved = vec![
+            Move {
+                from: PathBuf::from("a/one.jpg"),
+                to: PathBuf::from("b/one.jpg"),
+            },
+            Move {
+                from: PathBuf::from("a/two.jpg"),
+                to: PathBuf::from("c/renamed.jpg"),
+            },
+        ];
+        assert_eq!(result.moved, moved);
+        assert_eq!(result.added, paths(&["d/copy.jpg"]));
+        assert!(result.removed.is_empty());
+    }
+
+    #[test]
+    fn diff_render() {
+        let mut arg1 = Diff::default();
+        arg1.removed
+            .insert("old".to_string(), paths(&["x/old"]).into_iter().collect());
+        arg1.changed.insert(
+            "one".to_string(),
+            Paths::new(paths(&["b/one"]), paths(&["a/one", "c/one"])),
+        );
+
+        let result = arg1.render(Output::Human, "A", "B").unwrap();
+        let expected = "removed: old (1 path(s))\n\
+                        one: moved a/one -> b/one\n\
+                        one: removed c/one\n";
+        assert_eq!(result, expected);
+
+        let result = arg1.render(Output::Unified, "A", "B").unwrap();
+        let expected = "--- A\n+++ B\n@@ old @@\n-x/old\n@@ one @@\n-a/one\n+b/one\n-c/one\n";
+        assert_eq!(result, expected);
+
+        let result = arg1.render(Output::Json, "A", "B").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(json["changed"]["one"]["moved"][0]["to"], "b/one");
+        assert!(Output::from_name("side-by-side").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Finds files in the working tree with the same contents.
+//!
+//! Files are grouped by size first, so that only files which could be
+//! equal are hashed.
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use walkdir::{DirEntry, WalkDir};
+
+use error::ResultExt;
+use hoard::FileHash;
+use state::Index;
+use Result;
+
+/// A file in a group of duplicates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Copy {
+    pub path: PathBuf,
+    pub ino: u64,
+    /// The number of hard links to the file, including ones outside the
+    /// working tree.
+    pub nlink: u64,
+    /// Whether the file is a link to an object in the hoard.
+    pub linked: bool,
+}
+
+/// Files in the working tree with the same contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    pub hash: FileHash,
+    pub size: u64,
+    pub copies: Vec<Copy>,
+}
+
+impl Group {
+    /// The number of distinct files, as opposed to links to the same
+    /// file.
+    pub fn files(&self) -> usize {
+        let mut inos: Vec<_> = self.copies.iter().map(|c| c.ino).collect();
+        inos.sort();
+        inos.dedup();
+        inos.len()
+    }
+}
+
+/// Finds every set of paths below the root that have the same contents
+/// but are not all links to the same file.
+pub fn find<P: AsRef<Path>>(root: P, index: &Index) -> Result<Vec<Group>> {
+    fn is_index(entry: &DirEntry) -> bool {
+        entry.file_name() == ".hoard"
+    }
+
+    let by_ino = index.by_ino();
+    let mut by_size: BTreeMap<u64, Vec<Copy>> = BTreeMap::new();
+    for entry in WalkDir::new(root.as_ref())
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|e| !is_index(e))
+    {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.len() == 0 {
+            continue;
+        }
+        by_size.entry(metadata.len()).or_default().push(Copy {
+            path: entry.path().to_path_buf(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+            linked: by_ino.contains_key(&metadata.ino()),
+        });
+    }
+
+    let mut groups = Vec::new();
+    for (size, copies) in by_size {
+        if copies.len() < 2 {
+            continue;
+        }
+        // Links to the same file only need to be hashed once.
+        let mut hashes: HashMap<u64, FileHash> = HashMap::new();
+        let mut by_hash: BTreeMap<FileHash, Vec<Copy>> = BTreeMap::new();
+        for copy in copies {
+            let hash = match hashes.get(&copy.ino) {
+                Some(hash) => hash.clone(),
+                None => FileHash::of(&copy.path).with_path(&copy.path)?,
+            };
+            hashes.insert(copy.ino, hash.clone());
+            by_hash.entry(hash).or_default().push(copy);
+        }
+        for (hash, copies) in by_hash {
+            let group = Group { hash, size, copies };
+            if group.files() > 1 {
+                groups.push(group);
+            }
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dupes_find_success() {
+        let arg1 = "test/res/dupes_find/success";
+        let arg2 = Index { objects: vec![] };
+
+        let result = find(arg1, &arg2).unwrap();
+
+        assert_eq!(result.len(), 1);
+        let paths: Vec<_> = result[0].copies.iter().map(|c| c.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new(arg1).join("item1"),
+                Path::new(arg1).join("sub/item2"),
+            ]
+        );
+        assert!(result[0].copies.iter().all(|c| !c.linked));
+    }
+}
@@ -0,0 +1,363 @@
+//! Changes to the entry of a single object in the files of a manifest.
+//!
+//! Commands that change the manifest, such as `rename`, `rm` and
+//! `watch`, edit the entry of the object in every file that lists it,
+//! whether the manifest itself, a manifest it includes or a fragment,
+//! and leave the rest of each file as it was written, comments
+//! included. An edited file is read back before it is written, and is
+//! left alone with an error naming it when it does not list what was
+//! expected, such as when the entry is not written one per line.
+//!
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use error::ResultExt;
+use manifest::{Format, Manifest, Source};
+use tree;
+use Result;
+
+/// A file listing an object, with the paths it gives it.
+pub struct Owner {
+    pub file: PathBuf,
+    /// The directory the paths in the file are relative to.
+    pub prefix: PathBuf,
+    /// The paths, relative to the root of the hoard.
+    pub paths: BTreeSet<PathBuf>,
+}
+
+/// Lists the files that make up the manifest at the given path and list
+/// the object, in the order they are merged. A missing manifest lists
+/// nothing.
+pub fn owners<P, R>(manifest: P, root: R, name: &str) -> Result<Vec<Owner>>
+where
+    P: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let mut owners = Vec::new();
+    for Source {
+        path,
+        prefix,
+        manifest,
+    } in Manifest::sources(manifest, root)?
+    {
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(_) if !path.exists() => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(paths) = manifest.objects.get(name) {
+            owners.push(Owner {
+                paths: paths.iter().map(|p| prefix.join(p)).collect(),
+                file: path,
+                prefix,
+            });
+        }
+    }
+    Ok(owners)
+}
+
+/// Replaces the entry of an object in a manifest file by one for `new`,
+/// with the given paths relative to the root, or removes it. An object
+/// the file does not list is added to it.
+pub fn replace(
+    file: &Path,
+    prefix: &Path,
+    name: &str,
+    new: Option<(&str, &BTreeSet<PathBuf>)>,
+) -> Result<()> {
+    let format = Format::from_path(file)?;
+    let text = fs::read_to_string(file).with_path(file)?;
+    let mut expected = Manifest::from_file(file)?;
+    expected.objects.remove(name);
+    let mut relative = BTreeSet::new();
+    if let Some((new, paths)) = new {
+        for path in paths.iter() {
+            match path.strip_prefix(prefix) {
+                Ok(path) => relative.insert(path.to_path_buf()),
+                Err(_) => bail!(
+                    "{} only lists paths in {}/, edit the manifest to link '{}' to {}",
+                    file.display(),
+                    prefix.display(),
+                    new,
+                    path.display()
+                ),
+            };
+        }
+        expected.objects.insert(new.to_string(), relative.clone());
+    }
+    let new = new.map(|(new, _)| (new, &relative));
+
+    // The first of the edits that gives the expected manifest is made.
+    let edits = match format {
+        // JSON has no comments, and can be written again as a whole.
+        Format::Json => vec![expected.to_string(format)?],
+        Format::Toml | Format::Yaml => keyed(&text, format, name, new)?,
+        Format::Tree => by_line(&text, name, new).into_iter().collect(),
+    };
+    let edited = edits
+        .into_iter()
+        .find(|edited| Manifest::parse(edited, format).ok().as_ref() == Some(&expected));
+    match edited {
+        Some(edited) => {
+            fs::write(file, edited).with_path(file)?;
+            Ok(())
+        }
+        None => bail!(
+            "the entry of '{}' in {} cannot be edited, change it by hand",
+            name,
+            file.display()
+        ),
+    }
+}
+
+/// Edits a TOML or YAML entry, which starts with the name as a key at
+/// the start of a line, by replacing only its key, which is enough when
+/// it is renamed, or by replacing all of its lines.
+fn keyed(
+    text: &str,
+    format: Format,
+    name: &str,
+    new: Option<(&str, &BTreeSet<PathBuf>)>,
+) -> Result<Vec<String>> {
+    let lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let separator = if format == Format::Toml { " = " } else { ":" };
+    let replacement: Vec<String> = match new {
+        Some((new, paths)) => {
+            let mut entry = Manifest::default();
+            entry.objects.insert(new.to_string(), paths.clone());
+            entry
+                .to_string(format)?
+                .lines()
+                .filter(|line| *line != "---")
+                .map(str::to_string)
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    // Plain tables and arrays of tables, such as the rules, end the
+    // entries of a TOML manifest.
+    let end = match format {
+        Format::Toml => lines
+            .iter()
+            .position(|line| line.starts_with('['))
+            .unwrap_or(lines.len()),
+        _ => lines.len(),
+    };
+    let start = lines[..end]
+        .iter()
+        .enumerate()
+        .find_map(|(i, line)| key_length(line, name, separator).map(|length| (i, length)));
+
+    let mut edits = Vec::new();
+    let range = match start {
+        Some((start, length)) => {
+            let key = replacement
+                .first()
+                .and_then(|line| line.rfind(separator).map(|end| &line[..end]));
+            if let Some(key) = key {
+                let mut renamed = lines.clone();
+                renamed[start].replace_range(..length, key);
+                edits.push(renamed);
+            }
+            match format {
+                Format::Toml => toml_length(&lines[start..]),
+                _ => Some(yaml_length(&lines[start..])),
+            }
+            .map(|length| start..start + length)
+        }
+        None => Some(end..end),
+    };
+    if let Some(range) = range {
+        let mut replaced = lines.clone();
+        replaced.splice(range, replacement);
+        // A YAML manifest without any entries is an empty map.
+        let is_empty = |line: &String| line.trim().is_empty() || line.starts_with('#');
+        if format == Format::Yaml && replaced.iter().all(is_empty) {
+            replaced.push("{}".to_string());
+        }
+        edits.push(replaced);
+    }
+
+    Ok(edits
+        .into_iter()
+        .map(|lines| {
+            let mut edited = lines.join("\n");
+            edited.push('\n');
+            edited
+        })
+        .collect())
+}
+
+/// The length of the key the line starts with, when it is the name, bare
+/// or quoted.
+fn key_length(line: &str, name: &str, separator: &str) -> Option<usize> {
+    let quoted = ::serde_json::to_string(name).unwrap_or_default();
+    let keys = [name.to_string(), quoted, format!("'{}'", name)];
+    keys.iter()
+        .find(|key| {
+            line.strip_prefix(key.as_str())
+                .is_some_and(|rest| rest.trim_start().starts_with(separator.trim()))
+        })
+        .map(|key| key.len())
+}
+
+/// The number of lines of a TOML value, which ends where its brackets
+/// are closed.
+fn toml_length(lines: &[String]) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, line) in lines.iter().enumerate() {
+        let mut escaped = false;
+        for c in line.chars() {
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(q) if c == '\\' && q == '"' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '#' => break,
+                None if c == '[' => depth += 1,
+                None if c == ']' => depth -= 1,
+                None => {}
+            }
+        }
+        if depth == 0 && quote.is_none() {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// The number of lines of a YAML entry, whose list is indented below
+/// it or starts with dashes, without the blank lines after it.
+fn yaml_length(lines: &[String]) -> usize {
+    let length = 1 + lines[1..]
+        .iter()
+        .take_while(|line| line.is_empty() || line.starts_with(' ') || line.starts_with('-'))
+        .count();
+    let blank = lines[..length]
+        .iter()
+        .rev()
+        .take_while(|line| line.trim().is_empty())
+        .count();
+    length - blank
+}
+
+/// Rewrites the lines of a tree entry that link to a path that is kept,
+/// removes the others and adds the new paths at the end.
+fn by_line(text: &str, name: &str, new: Option<(&str, &BTreeSet<PathBuf>)>) -> Option<String> {
+    let (_, entries) = tree::parse_entries(text).ok()?;
+    let mut lines: Vec<Option<String>> = text.lines().map(|line| Some(line.to_string())).collect();
+    let mut remaining = new.map(|(_, paths)| paths.clone()).unwrap_or_default();
+    let mut unlinked = new.is_some_and(|(_, paths)| paths.is_empty());
+
+    let mut dropped = Vec::new();
+    for entry in entries.iter().filter(|entry| entry.name == name) {
+        let line = &mut lines[entry.line - 1];
+        let indent = {
+            let text = line.as_ref()?;
+            text[..text.len() - text.trim_start().len()].to_string()
+        };
+        *line = match (new, &entry.file) {
+            (Some((new, _)), &Some((ref file, ref path))) if remaining.remove(path) => {
+                Some(format!("{}{}", indent, tree::entry(file, new)))
+            }
+            (Some((new, _)), &None) if unlinked => {
+                unlinked = false;
+                Some(format!("{}@unlinked {}", indent, tree::quote(new)))
+            }
+            _ => {
+                dropped.push((entry, indent));
+                None
+            }
+        };
+    }
+
+    // A path moved within its directory takes the place of the old one.
+    for (entry, indent) in dropped {
+        if let (Some((new, _)), &Some((ref file, ref path))) = (new, &entry.file) {
+            let moved = remaining
+                .iter()
+                .find(|moved| !file.contains('/') && moved.parent() == path.parent())
+                .cloned();
+            if let Some(moved) = moved {
+                let file = moved.file_name().unwrap_or_default().to_string_lossy();
+                lines[entry.line - 1] = Some(format!("{}{}", indent, tree::entry(&file, new)));
+                remaining.remove(&moved);
+            }
+        }
+    }
+
+    let mut edited: Vec<String> = lines.into_iter().flatten().collect();
+    if let Some((new, _)) = new {
+        for path in remaining.iter() {
+            edited.push(tree::entry(&path.to_string_lossy(), new));
+        }
+        if unlinked {
+            edited.push(format!("@unlinked {}", tree::quote(new)));
+        }
+    }
+    let mut edited = edited.join("\n");
+    edited.push('\n');
+    Some(edited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn edit_replace() {
+        let dir = env::temp_dir().join(format!("hoard-edit-replace-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let arg1 = [
+            ("toml", "# Kept.\nitem1 = [\n  \"a/item1\", # Also kept.\n]\n\"item 2\" = [\"b\"]\n\n[[rules]]\ndir = \"unread/\"\nquery = \"tag:unread\"\n"),
+            ("yaml", "# Kept.\nitem1:\n  - a/item1\n\n'item 2': [b]\nrules:\n  - dir: unread/\n    query: tag:unread\n"),
+            ("tree", "# Kept.\na/\n  # Also kept.\n  item1\nb = item 2\n@rule unread/ tag:unread\n"),
+        ];
+        let arg2: BTreeSet<PathBuf> = ["a/item1", "c/item1"].iter().map(PathBuf::from).collect();
+
+        for &(extension, text) in arg1.iter() {
+            let path = dir.join(format!("manifest.{}", extension));
+            fs::write(&path, text).unwrap();
+            replace(&path, Path::new(""), "item1", Some(("renamed", &arg2))).unwrap();
+            replace(&path, Path::new(""), "item 2", None).unwrap();
+
+            let result = fs::read_to_string(&path).unwrap();
+            let manifest = Manifest::from_file(&path).unwrap();
+            assert!(result.starts_with("# Kept.\n"), "{}", result);
+            assert_eq!(manifest.objects.len(), 1, "{}", result);
+            assert_eq!(manifest.objects["renamed"], arg2);
+            assert_eq!(manifest.rules.len(), 1);
+        }
+        assert!(fs::read_to_string(dir.join("manifest.tree"))
+            .unwrap()
+            .contains("  # Also kept.\n  item1 = renamed\n"));
+
+        let path = dir.join("manifest.tree");
+        let arg4: BTreeSet<PathBuf> = ["a/moved", "c/item1"].iter().map(PathBuf::from).collect();
+        replace(&path, Path::new(""), "renamed", Some(("renamed", &arg4))).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("  # Also kept.\n  moved = renamed\n"));
+
+        let path = dir.join("manifest.toml");
+        let arg3: BTreeSet<PathBuf> = ["x"].iter().map(PathBuf::from).collect();
+        fs::write(&path, "a = [\"x\"] # Kept.\n").unwrap();
+        replace(&path, Path::new(""), "a", Some(("b", &arg3))).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "b = [\"x\"] # Kept.\n");
+
+        let path = dir.join("manifest.yaml");
+        replace(&path, Path::new(""), "renamed", None).unwrap();
+        assert!(Manifest::from_file(&path).unwrap().objects.is_empty());
+
+        let path = dir.join("manifest.tree");
+        let result = replace(&path, Path::new("a"), "renamed", Some(("renamed", &arg2)));
+        assert!(result.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
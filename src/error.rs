@@ -18,4 +18,26 @@ where
 
 impl<T, E: fmt::Display> ResultExt<T, E> for result::Result<T, E> where
     result::Result<T, E>: failure::ResultExt<T, E>
-{}
+{
+}
+
+/// An error at a specific line and column of a file.
+#[derive(Debug)]
+pub struct LocatedError {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LocatedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.path, self.line, self.column, self.message
+        )
+    }
+}
+
+impl failure::Fail for LocatedError {}
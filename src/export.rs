@@ -0,0 +1,221 @@
+//! Copies part of a hoard out as real files, for people who don't use
+//! hoard.
+//!
+//! An export is a plain directory, a tar archive or a zip archive,
+//! chosen by the extension of its path. Next to the exported files, its
+//! root holds:
+//!
+//! - `MANIFEST.json`, a manifest naming the object at every path, with
+//!   paths relative to the root of the export
+//! - `SHA256SUMS`, the hash of every file in the format read by
+//!   `sha256sum -c`
+//!
+//! Importing an export verifies every file against `SHA256SUMS`, and
+//! names the objects after the manifest.
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tar;
+use zip;
+
+use error::ResultExt;
+use hoard::FileHash;
+use manifest::{Format, Manifest, VERSION};
+use Result;
+
+/// The name of the manifest at the root of an export.
+pub const MANIFEST: &str = "MANIFEST.json";
+
+/// The name of the hash list at the root of an export.
+pub const CHECKSUMS: &str = "SHA256SUMS";
+
+/// The kinds of export.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Dir,
+    Tar,
+    Zip,
+}
+
+impl Target {
+    /// Determines the kind of export from the extension of the path,
+    /// which is a directory unless it ends in `.tar` or `.zip`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let extension = path
+            .as_ref()
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("tar") => Target::Tar,
+            Some("zip") => Target::Zip,
+            _ => Target::Dir,
+        }
+    }
+}
+
+/// A file in an export.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// The name of the object.
+    pub name: String,
+    /// The path of the file, relative to the root of the export.
+    pub path: PathBuf,
+    /// The stored contents of the object.
+    pub source: PathBuf,
+    pub hash: FileHash,
+}
+
+/// Writes the entries, along with their manifest and hash list, to the
+/// given path, which must not exist yet.
+pub fn write(entries: &[Entry], to: &Path) -> Result<()> {
+    if to.exists() {
+        bail!("'{}' already exists", to.display());
+    }
+
+    let mut manifest = Manifest {
+        version: Some(VERSION),
+        ..Manifest::default()
+    };
+    for entry in entries.iter() {
+        manifest
+            .objects
+            .entry(entry.name.clone())
+            .or_default()
+            .insert(entry.path.clone());
+    }
+    let manifest = manifest.to_string(Format::Json)?;
+    let checksums = checksums(entries);
+    let extra: &[(&str, &str)] = &[(MANIFEST, &manifest), (CHECKSUMS, &checksums)];
+
+    match Target::from_path(to) {
+        Target::Dir => {
+            fs::create_dir_all(to).with_path(to)?;
+            for entry in entries.iter() {
+                let path = to.join(&entry.path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).with_path(parent)?;
+                }
+                fs::copy(&entry.source, &path).with_path(&path)?;
+            }
+            for &(name, text) in extra {
+                fs::write(to.join(name), text).with_path(to.join(name))?;
+            }
+        }
+        Target::Tar => {
+            let file = fs::File::create(to).with_path(to)?;
+            let mut builder = tar::Builder::new(file);
+            for entry in entries.iter() {
+                builder
+                    .append_path_with_name(&entry.source, &entry.path)
+                    .with_path(&entry.path)?;
+            }
+            for &(name, text) in extra {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(text.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(now());
+                builder.append_data(&mut header, name, text.as_bytes())?;
+            }
+            builder.into_inner()?.sync_all().with_path(to)?;
+        }
+        Target::Zip => {
+            let file = fs::File::create(to).with_path(to)?;
+            let mut writer = zip::ZipWriter::new(file);
+            let options = zip::write::FileOptions::default();
+            for entry in entries.iter() {
+                writer.start_file(entry.path.to_string_lossy(), options)?;
+                let mut source = fs::File::open(&entry.source).with_path(&entry.source)?;
+                io::copy(&mut source, &mut writer).with_path(&entry.source)?;
+            }
+            for &(name, text) in extra {
+                writer.start_file(name, options)?;
+                writer.write_all(text.as_bytes())?;
+            }
+            writer.finish()?.sync_all().with_path(to)?;
+        }
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Lists the hash of every entry, sorted by path.
+fn checksums(entries: &[Entry]) -> String {
+    let mut lines: Vec<_> = entries
+        .iter()
+        .map(|entry| format!("{}  {}\n", entry.hash.as_str(), entry.path.display()))
+        .collect();
+    lines.sort_by(|a, b| a[66..].cmp(&b[66..]));
+    lines.concat()
+}
+
+/// Reads the hash list of an export, keyed by path.
+pub fn read_checksums<P: AsRef<Path>>(path: P) -> Result<BTreeMap<PathBuf, FileHash>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).with_path(path)?;
+    parse_checksums(&text).with_path(path).map_err(Into::into)
+}
+
+fn parse_checksums(text: &str) -> Result<BTreeMap<PathBuf, FileHash>> {
+    let mut checksums = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        // The file name is preceded by a space, and then another space
+        // or a `*` for files hashed in binary mode.
+        let (hash, file) = match (line.get(..64), line.get(66..)) {
+            (Some(hash), Some(file)) if line[64..].starts_with(' ') => (hash, file),
+            _ => bail!("line {}: malformed checksum", n + 1),
+        };
+        checksums.insert(PathBuf::from(file), FileHash::from_str(&hash.to_string())?);
+    }
+    Ok(checksums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_target() {
+        assert_eq!(Target::from_path("out.tar"), Target::Tar);
+        assert_eq!(Target::from_path("out.ZIP"), Target::Zip);
+        assert_eq!(Target::from_path("out"), Target::Dir);
+    }
+
+    #[test]
+    fn export_checksums() {
+        let hash = "e80aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7";
+        let arg1 = vec![
+            Entry {
+                name: "item2".to_string(),
+                path: PathBuf::from("sub/item2"),
+                source: PathBuf::new(),
+                hash: FileHash::from_str(&hash.to_string()).unwrap(),
+            },
+            Entry {
+                name: "item1".to_string(),
+                path: PathBuf::from("item1"),
+                source: PathBuf::new(),
+                hash: FileHash::from_str(&hash.to_string()).unwrap(),
+            },
+        ];
+
+        let result = checksums(&arg1);
+
+        assert_eq!(result, format!("{}  item1\n{}  sub/item2\n", hash, hash));
+        let parsed = parse_checksums(&result).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[Path::new("sub/item2")], arg1[0].hash);
+        assert!(parse_checksums("not a checksum\n").is_err());
+    }
+}
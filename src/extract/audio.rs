@@ -0,0 +1,219 @@
+//! ID3 tags, FLAC stream information and Vorbis comments.
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::{be_u32, be_u64, latin1, le_u32, seconds, utf16, year_of, Fields};
+use Result;
+
+/// Reads ID3v2.3 and ID3v2.4 tags, falling back to an ID3v1 tag at the
+/// end of the file.
+pub fn id3(path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if head.len() >= 10 && head.starts_with(b"ID3") && (head[3] == 3 || head[3] == 4) {
+        let size = syncsafe(&head[6..10]) as usize;
+        let mut tag = vec![0; size];
+        let mut file = fs::File::open(path)?;
+        file.seek(SeekFrom::Start(10))?;
+        file.read_exact(&mut tag)?;
+        return Ok(Some(id3v2(&tag, head[3])));
+    }
+
+    // MPEG audio frames start with an 11 bit sync word.
+    if head.len() < 2 || head[0] != 0xFF || head[1] & 0xE0 != 0xE0 {
+        return Ok(None);
+    }
+
+    let mut fields = Fields::new();
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() >= 128 {
+        let mut tag = [0; 128];
+        file.seek(SeekFrom::End(-128))?;
+        file.read_exact(&mut tag)?;
+        if tag.starts_with(b"TAG") {
+            let text = |range: ::std::ops::Range<usize>| {
+                latin1(&tag[range])
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string()
+            };
+            fields.insert("title".to_string(), text(3..33));
+            fields.insert("artist".to_string(), text(33..63));
+            fields.insert("album".to_string(), text(63..93));
+            fields.insert("year".to_string(), text(93..97));
+        }
+    }
+    Ok(Some(fields))
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| value << 7 | u32::from(byte & 0x7F))
+}
+
+/// Reads the text frames of an ID3v2 tag body.
+fn id3v2(tag: &[u8], version: u8) -> Fields {
+    let mut fields = Fields::new();
+    let mut offset = 0;
+    while offset + 10 <= tag.len() && tag[offset] != 0 {
+        let id = &tag[offset..offset + 4];
+        let size = if version == 4 {
+            syncsafe(&tag[offset + 4..offset + 8])
+        } else {
+            be_u32(&tag[offset + 4..offset + 8])
+        } as usize;
+        let body = &tag[offset + 10..(offset + 10 + size).min(tag.len())];
+        offset += 10 + size;
+
+        let key = match id {
+            b"TIT2" => "title",
+            b"TPE1" => "artist",
+            b"TALB" => "album",
+            b"TRCK" => "track",
+            b"TCON" => "genre",
+            b"TYER" | b"TDRC" => "date",
+            _ => continue,
+        };
+        if let Some((&encoding, text)) = body.split_first() {
+            let text = match encoding {
+                1 => utf16(text, false),
+                2 => utf16(text, true),
+                _ => latin1(text),
+            };
+            let text = text.trim_end_matches('\0').to_string();
+            fields.insert(key.to_string(), text);
+        }
+    }
+
+    if let Some(year) = fields.remove("date").and_then(|date| year_of(&date)) {
+        fields.insert("year".to_string(), year);
+    }
+    fields
+}
+
+/// Reads the duration from the stream information of a FLAC file along
+/// with its Vorbis comments.
+pub fn flac(path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if !head.starts_with(b"fLaC") {
+        return Ok(None);
+    }
+
+    let mut fields = Fields::new();
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(4))?;
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let length = (be_u32(&header) & 0x00FF_FFFF) as usize;
+        let mut block = vec![0; length];
+        file.read_exact(&mut block)?;
+
+        match header[0] & 0x7F {
+            0 if block.len() >= 18 => {
+                let rate = be_u32(&block[10..14]) >> 12;
+                let samples = be_u64(&block[10..18]) & 0x0F_FFFF_FFFF;
+                if rate > 0 {
+                    fields.insert(
+                        "duration".to_string(),
+                        seconds(samples as f64 / f64::from(rate)),
+                    );
+                }
+            }
+            4 => fields.extend(comments(&block)),
+            _ => {}
+        }
+        if last {
+            break;
+        }
+    }
+    Ok(Some(fields))
+}
+
+/// Reads the Vorbis comments of an Ogg Vorbis or Opus file.
+///
+/// Only comments that fit in the leading bytes of the file are read,
+/// which is where they are placed by encoders.
+pub fn ogg(_path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if !head.starts_with(b"OggS") {
+        return Ok(None);
+    }
+
+    for signature in [&b"\x03vorbis"[..], &b"OpusTags"[..]].iter() {
+        if let Some(start) = head.windows(signature.len()).position(|w| w == *signature) {
+            return Ok(Some(comments(&head[start + signature.len()..])));
+        }
+    }
+    Ok(Some(Fields::new()))
+}
+
+/// Reads a Vorbis comment block, which is a vendor string followed by
+/// `KEY=value` pairs.
+fn comments(block: &[u8]) -> Fields {
+    fn string(block: &[u8], offset: &mut usize) -> Option<String> {
+        let length = le_u32(block.get(*offset..*offset + 4)?) as usize;
+        let text = block.get(*offset + 4..*offset + 4 + length)?;
+        *offset += 4 + length;
+        Some(String::from_utf8_lossy(text).to_string())
+    }
+
+    let mut fields = Fields::new();
+    let mut offset = 0;
+    if string(block, &mut offset).is_none() {
+        return fields;
+    }
+    let count = match block.get(offset..offset + 4) {
+        Some(bytes) => le_u32(bytes),
+        None => return fields,
+    };
+    offset += 4;
+
+    for _ in 0..count {
+        let comment = match string(block, &mut offset) {
+            Some(comment) => comment,
+            None => break,
+        };
+        let mut parts = comment.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.to_lowercase(), value.to_string()),
+            _ => continue,
+        };
+        let key = match key.as_str() {
+            "title" | "artist" | "album" | "genre" | "date" => key,
+            "tracknumber" => "track".to_string(),
+            _ => continue,
+        };
+        fields.entry(key).or_insert(value);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_id3v2() {
+        let mut arg1 = Vec::new();
+        arg1.extend_from_slice(b"TIT2\x00\x00\x00\x06\x00\x00\x03Title");
+        arg1.extend_from_slice(b"TDRC\x00\x00\x00\x0b\x00\x00\x032001-02-03");
+
+        let result = id3v2(&arg1, 4);
+
+        assert_eq!(result["title"], "Title");
+        assert_eq!(result["year"], "2001");
+    }
+
+    #[test]
+    fn audio_comments() {
+        let mut arg1 = Vec::new();
+        arg1.extend_from_slice(b"\x06\x00\x00\x00vendor\x02\x00\x00\x00");
+        arg1.extend_from_slice(b"\x0b\x00\x00\x00ARTIST=Band");
+        arg1.extend_from_slice(b"\x0d\x00\x00\x00TRACKNUMBER=3");
+
+        let result = comments(&arg1);
+
+        assert_eq!(result["artist"], "Band");
+        assert_eq!(result["track"], "3");
+    }
+}
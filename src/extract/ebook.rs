@@ -0,0 +1,272 @@
+//! EPUB package documents and PDF document information dictionaries.
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use regex::bytes::Regex as BytesRegex;
+use regex::{Captures, Regex};
+use zip::ZipArchive;
+
+use super::{latin1, utf16, year_of, Fields};
+use Result;
+
+/// Reads the title, author, date and series from the OPF package
+/// document of an EPUB.
+pub fn epub(path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    // The first entry of an EPUB is an uncompressed `mimetype` file.
+    let signature = b"mimetypeapplication/epub+zip";
+    let start = &head[..head.len().min(128)];
+    if !head.starts_with(b"PK\x03\x04") || !start.windows(signature.len()).any(|w| w == signature) {
+        return Ok(None);
+    }
+
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+    let mut container = String::new();
+    archive
+        .by_name("META-INF/container.xml")?
+        .read_to_string(&mut container)?;
+
+    lazy_static! {
+        static ref ROOTFILE: Regex = Regex::new(r#"<rootfile[^>]*full-path="([^"]+)""#).unwrap();
+    }
+    let opf_path = match ROOTFILE.captures(&container) {
+        Some(captures) => unescape(&captures[1]),
+        None => bail!("container.xml has no rootfile"),
+    };
+    let mut opf = String::new();
+    archive.by_name(&opf_path)?.read_to_string(&mut opf)?;
+
+    Ok(Some(package(&opf)))
+}
+
+/// Extracts fields from the text of an OPF package document.
+fn package(opf: &str) -> Fields {
+    lazy_static! {
+        static ref ELEMENT: Regex =
+            Regex::new(r"(?s)<dc:(title|creator|date)\b[^>]*>(.*?)</dc:").unwrap();
+        static ref CALIBRE: Regex =
+            Regex::new(r#"<meta\s+name="calibre:(series|series_index)"\s+content="([^"]*)""#)
+                .unwrap();
+        static ref COLLECTION: Regex =
+            Regex::new(r#"(?s)<meta\s+property="belongs-to-collection"[^>]*>(.*?)</meta>"#)
+                .unwrap();
+        static ref POSITION: Regex =
+            Regex::new(r#"(?s)<meta\s+[^>]*property="group-position"[^>]*>(.*?)</meta>"#).unwrap();
+    }
+
+    let mut fields = Fields::new();
+    for captures in ELEMENT.captures_iter(opf) {
+        let key = match &captures[1] {
+            "creator" => "author",
+            key => key,
+        };
+        fields
+            .entry(key.to_string())
+            .or_insert_with(|| unescape(captures[2].trim()));
+    }
+    for captures in CALIBRE.captures_iter(opf) {
+        fields.insert(captures[1].to_string(), unescape(&captures[2]));
+    }
+    if !fields.contains_key("series") {
+        if let Some(captures) = COLLECTION.captures(opf) {
+            fields.insert("series".to_string(), unescape(captures[1].trim()));
+        }
+        if let Some(captures) = POSITION.captures(opf) {
+            fields.insert("series_index".to_string(), unescape(captures[1].trim()));
+        }
+    }
+    fields
+}
+
+/// Replaces the predefined and numeric XML entities.
+fn unescape(text: &str) -> String {
+    lazy_static! {
+        static ref ENTITY: Regex = Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|[a-z]+);").unwrap();
+    }
+    ENTITY
+        .replace_all(text, |captures: &Captures| {
+            let entity = &captures[1];
+            let code = if let Some(hex) = entity.strip_prefix("#x") {
+                u32::from_str_radix(hex, 16).ok()
+            } else if let Some(decimal) = entity.strip_prefix('#') {
+                decimal.parse().ok()
+            } else {
+                match entity {
+                    "amp" => Some(38),
+                    "lt" => Some(60),
+                    "gt" => Some(62),
+                    "quot" => Some(34),
+                    "apos" => Some(39),
+                    _ => None,
+                }
+            };
+            code.and_then(::std::char::from_u32)
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| captures[0].to_string())
+        })
+        .to_string()
+}
+
+/// Reads the title, author and creation date from the document
+/// information dictionary of a PDF.
+pub fn pdf(path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if !head.starts_with(b"%PDF-") {
+        return Ok(None);
+    }
+
+    lazy_static! {
+        static ref INFO: BytesRegex = BytesRegex::new(r"(?-u)/Info\s+(\d+)\s+(\d+)\s+R").unwrap();
+    }
+
+    let data = fs::read(path)?;
+    let reference = INFO
+        .captures_iter(&data)
+        .last()
+        .map(|c| (latin1(&c[1]), latin1(&c[2])));
+    let (number, generation) = match reference {
+        Some(reference) => reference,
+        None => return Ok(Some(Fields::new())),
+    };
+
+    let object = BytesRegex::new(&format!(
+        r"(?s-u)(?:^|[^0-9]){}\s+{}\s+obj\s*<<(.*?)>>\s*endobj",
+        number, generation
+    ))?;
+    let dictionary = match object.captures_iter(&data).last() {
+        Some(captures) => captures[1].to_vec(),
+        None => return Ok(Some(Fields::new())),
+    };
+
+    let mut fields = Fields::new();
+    for &(key, name) in [
+        ("title", "Title"),
+        ("author", "Author"),
+        ("date", "CreationDate"),
+    ]
+    .iter()
+    {
+        if let Some(value) = string_entry(&dictionary, name) {
+            fields.insert(key.to_string(), value);
+        }
+    }
+    if let Some(date) = fields.remove("date") {
+        let date = date.trim_start_matches("D:");
+        if let Some(year) = year_of(date) {
+            fields.insert("year".to_string(), year);
+        }
+    }
+    Ok(Some(fields))
+}
+
+/// Reads a literal `(...)` or hexadecimal `<...>` string entry from a
+/// PDF dictionary.
+fn string_entry(dictionary: &[u8], name: &str) -> Option<String> {
+    let key = format!("/{}", name);
+    let start = dictionary
+        .windows(key.len())
+        .position(|window| window == key.as_bytes())?
+        + key.len();
+    let rest = &dictionary[start..];
+    let rest = &rest[rest.iter().position(|b| !b.is_ascii_whitespace())?..];
+
+    let bytes = match rest.first()? {
+        b'(' => literal(&rest[1..]),
+        b'<' => {
+            let end = rest.iter().position(|&b| b == b'>')?;
+            let digits: Vec<u8> = rest[1..end]
+                .iter()
+                .cloned()
+                .filter(|b| b.is_ascii_hexdigit())
+                .collect();
+            digits
+                .chunks(2)
+                .filter_map(|pair| u8::from_str_radix(&latin1(pair), 16).ok())
+                .collect()
+        }
+        _ => return None,
+    };
+
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        Some(utf16(&bytes, true))
+    } else {
+        Some(latin1(&bytes))
+    }
+}
+
+/// Decodes the body of a PDF literal string, up to its closing
+/// parenthesis.
+fn literal(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut depth = 0;
+    let mut iter = bytes.iter().cloned().peekable();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'\\' => match iter.next() {
+                Some(b'n') => out.push(b'\n'),
+                Some(b'r') => out.push(b'\r'),
+                Some(b't') => out.push(b'\t'),
+                Some(b'b') => out.push(8),
+                Some(b'f') => out.push(12),
+                Some(c @ b'0'..=b'7') => {
+                    let mut value = u32::from(c - b'0');
+                    for _ in 0..2 {
+                        match iter.peek() {
+                            Some(&d @ b'0'..=b'7') => {
+                                value = value * 8 + u32::from(d - b'0');
+                                iter.next();
+                            }
+                            _ => break,
+                        }
+                    }
+                    out.push(value as u8);
+                }
+                Some(b'\r') | Some(b'\n') => {}
+                Some(c) => out.push(c),
+                None => break,
+            },
+            b'(' => {
+                depth += 1;
+                out.push(byte);
+            }
+            b')' if depth == 0 => break,
+            b')' => {
+                depth -= 1;
+                out.push(byte);
+            }
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ebook_package() {
+        let arg1 = r#"<metadata>
+            <dc:title>The Fellowship &amp; the Ring</dc:title>
+            <dc:creator opf:role="aut">J. R. R. Tolkien</dc:creator>
+            <dc:date>1954-07-29</dc:date>
+            <meta name="calibre:series" content="The Lord of the Rings"/>
+            <meta name="calibre:series_index" content="1"/>
+        </metadata>"#;
+
+        let result = package(arg1);
+
+        assert_eq!(result["title"], "The Fellowship & the Ring");
+        assert_eq!(result["author"], "J. R. R. Tolkien");
+        assert_eq!(result["date"], "1954-07-29");
+        assert_eq!(result["series"], "The Lord of the Rings");
+        assert_eq!(result["series_index"], "1");
+    }
+
+    #[test]
+    fn ebook_string_entry() {
+        let arg1 = b"/Title (A \\(nested\\) title) /Author <FEFF0041>";
+
+        assert_eq!(string_entry(arg1, "Title").unwrap(), "A (nested) title");
+        assert_eq!(string_entry(arg1, "Author").unwrap(), "A");
+    }
+}
@@ -0,0 +1,174 @@
+//! JPEG Exif data and PNG headers.
+use std::path::Path;
+
+use super::{be_u16, be_u32, latin1, le_u16, le_u32, Fields};
+use Result;
+
+/// Reads the camera and date from the Exif data of a JPEG, along with
+/// its dimensions.
+pub fn jpeg(_path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if !head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Ok(None);
+    }
+
+    let mut fields = Fields::new();
+    let mut offset = 2;
+    while offset + 4 <= head.len() && head[offset] == 0xFF {
+        let marker = head[offset + 1];
+        let length = be_u16(&head[offset + 2..]) as usize;
+        // The length counts its own two bytes, so a shorter one is corrupt.
+        if length < 2 {
+            break;
+        }
+        let end = (offset + 2 + length).min(head.len());
+        let segment = head.get(offset + 4..end).unwrap_or_default();
+        offset += 2 + length;
+
+        match marker {
+            0xE1 if segment.starts_with(b"Exif\0\0") => fields.extend(exif(&segment[6..])),
+            // Start of frame markers, other than DHT, JPG and DAC.
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                if segment.len() >= 5 {
+                    fields.insert("height".to_string(), be_u16(&segment[1..]).to_string());
+                    fields.insert("width".to_string(), be_u16(&segment[3..]).to_string());
+                }
+                break;
+            }
+            // Start of scan, after which there are no more headers.
+            0xDA => break,
+            _ => {}
+        }
+    }
+    Ok(Some(fields))
+}
+
+/// Reads the camera make and model and the date the photo was taken from
+/// a TIFF structure.
+fn exif(tiff: &[u8]) -> Fields {
+    let mut fields = Fields::new();
+    let little = match tiff.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return fields,
+    };
+    let u16_at = |offset: usize| -> Option<u16> {
+        let bytes = tiff.get(offset..offset + 2)?;
+        Some(if little { le_u16(bytes) } else { be_u16(bytes) })
+    };
+    let u32_at = |offset: usize| -> Option<u32> {
+        let bytes = tiff.get(offset..offset + 4)?;
+        Some(if little { le_u32(bytes) } else { be_u32(bytes) })
+    };
+    // Reads an ASCII entry, which is stored inline if it fits in 4 bytes.
+    let ascii = |entry: usize| -> Option<String> {
+        let count = u32_at(entry + 4)? as usize;
+        let start = if count <= 4 {
+            entry + 8
+        } else {
+            u32_at(entry + 8)? as usize
+        };
+        let text = latin1(tiff.get(start..start.checked_add(count)?)?);
+        Some(text.trim_end_matches('\0').trim().to_string())
+    };
+    let entries = |ifd: usize| -> Vec<(u16, usize)> {
+        let count = u16_at(ifd).unwrap_or(0) as usize;
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .filter_map(|entry| u16_at(entry).map(|tag| (tag, entry)))
+            .collect()
+    };
+
+    let mut make = None;
+    let mut model = None;
+    let mut date = None;
+    let mut original = None;
+    let ifd0 = match u32_at(4) {
+        Some(offset) => offset as usize,
+        None => return fields,
+    };
+    for (tag, entry) in entries(ifd0) {
+        match tag {
+            0x010F => make = ascii(entry),
+            0x0110 => model = ascii(entry),
+            0x0132 => date = ascii(entry),
+            0x8769 => {
+                let sub = u32_at(entry + 8).unwrap_or(0) as usize;
+                for (tag, entry) in entries(sub) {
+                    if tag == 0x9003 {
+                        original = ascii(entry);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Models often repeat the make, as in "Canon Canon EOS 5D".
+    let camera = match (make, model) {
+        (Some(make), Some(model)) => {
+            if model.to_lowercase().starts_with(&make.to_lowercase()) {
+                model
+            } else {
+                format!("{} {}", make, model)
+            }
+        }
+        (make, model) => make.or(model).unwrap_or_default(),
+    };
+    fields.insert("camera".to_string(), camera);
+
+    // Exif dates are written as "YYYY:MM:DD HH:MM:SS".
+    if let Some(date) = original.or(date) {
+        let day: String = date.chars().take(10).collect();
+        fields.insert("date".to_string(), day.replace(':', "-"));
+    }
+    fields
+}
+
+/// Reads the dimensions from the header of a PNG.
+pub fn png(_path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if !head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Ok(None);
+    }
+
+    let mut fields = Fields::new();
+    if head.len() >= 24 && &head[12..16] == b"IHDR" {
+        fields.insert("width".to_string(), be_u32(&head[16..]).to_string());
+        fields.insert("height".to_string(), be_u32(&head[20..]).to_string());
+    }
+    Ok(Some(fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_exif() {
+        let mut arg1 = Vec::new();
+        arg1.extend_from_slice(b"MM\x00\x2a\x00\x00\x00\x08");
+        arg1.extend_from_slice(b"\x00\x02");
+        arg1.extend_from_slice(b"\x01\x0f\x00\x02\x00\x00\x00\x04ABC\x00");
+        arg1.extend_from_slice(b"\x01\x32\x00\x02\x00\x00\x00\x14\x00\x00\x00\x26");
+        arg1.extend_from_slice(b"\x00\x00\x00\x00");
+        arg1.extend_from_slice(b"2001:02:03 04:05:06\x00");
+
+        let result = exif(&arg1);
+
+        assert_eq!(result["camera"], "ABC");
+        assert_eq!(result["date"], "2001-02-03");
+    }
+
+    #[test]
+    fn image_jpeg_corrupt() {
+        let arg1 = b"\xff\xd8\xff\xe1\x00\x00Exif\0\0MM";
+        let arg2 = b"\xff\xd8\xff\xe1\x00\x01\xff\xc0\x00\x11";
+        let arg3 = b"\xff\xd8\xff\xc0\x00\x11\x08\x01";
+
+        for arg in [&arg1[..], &arg2[..], &arg3[..]].iter() {
+            let result = jpeg(Path::new(""), arg).unwrap().unwrap();
+            assert!(!result.contains_key("width"));
+        }
+        let result = jpeg(Path::new(""), &arg2[..5]).unwrap().unwrap();
+        assert!(result.is_empty());
+    }
+}
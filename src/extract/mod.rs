@@ -0,0 +1,157 @@
+//! Reads descriptive metadata out of the contents of files.
+//!
+//! Each extractor recognizes its formats by their leading bytes rather
+//! than by extension, and returns the fields it found using a common set
+//! of keys so that queries and views work across formats:
+//!
+//! - `title`, `author`, `series`, `series_index` for ebooks and documents
+//! - `title`, `artist`, `album`, `track`, `genre` for audio
+//! - `camera` and `date` for photos
+//! - `duration` in seconds, `width` and `height` in pixels for media
+//! - `year` for anything with a date
+//!
+//! Everything is parsed in-process, without calling out to other tools.
+//!
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use error::ResultExt;
+use Result;
+
+mod audio;
+mod ebook;
+mod image;
+mod video;
+
+/// The fields extracted from a file.
+pub type Fields = BTreeMap<String, String>;
+
+/// The number of leading bytes given to extractors to recognize a file.
+const HEAD_SIZE: u64 = 64 * 1024;
+
+/// An extractor, given the path and the leading bytes of the file. It
+/// returns `None` if it doesn't recognize the format.
+type Extractor = fn(&Path, &[u8]) -> Result<Option<Fields>>;
+
+const EXTRACTORS: &[Extractor] = &[
+    ebook::epub,
+    ebook::pdf,
+    audio::id3,
+    audio::flac,
+    audio::ogg,
+    image::jpeg,
+    image::png,
+    video::mp4,
+    video::matroska,
+];
+
+/// Extracts the metadata of the file at the given path, using the
+/// first extractor that recognizes its format.
+pub fn extract<P: AsRef<Path>>(path: P) -> Result<Fields> {
+    let path = path.as_ref();
+    let mut head = Vec::new();
+    fs::File::open(path)
+        .with_path(path)?
+        .take(HEAD_SIZE)
+        .read_to_end(&mut head)
+        .with_path(path)?;
+
+    for extractor in EXTRACTORS {
+        if let Some(mut fields) = extractor(path, &head).with_path(path)? {
+            fields.retain(|_, value| !value.trim().is_empty());
+            if !fields.contains_key("year") {
+                if let Some(year) = fields.get("date").and_then(|date| year_of(date)) {
+                    fields.insert("year".to_string(), year);
+                }
+            }
+            return Ok(fields);
+        }
+    }
+    Ok(Fields::new())
+}
+
+/// Returns the leading four digit year of a date.
+fn year_of(date: &str) -> Option<String> {
+    let year: String = date.trim().chars().take(4).collect();
+    if year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()) {
+        Some(year)
+    } else {
+        None
+    }
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) << 8 | u16::from(bytes[1])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from(be_u16(bytes)) << 16 | u32::from(be_u16(&bytes[2..]))
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from(be_u32(bytes)) << 32 | u64::from(be_u32(&bytes[4..]))
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from(bytes[1]) << 8 | u16::from(bytes[0])
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from(le_u16(&bytes[2..])) << 16 | u32::from(le_u16(bytes))
+}
+
+/// Decodes text that is either UTF-8 or Latin-1.
+fn latin1(bytes: &[u8]) -> String {
+    match ::std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| char::from(b)).collect(),
+    }
+}
+
+/// Decodes UTF-16 text, using the byte order mark if there is one.
+fn utf16(bytes: &[u8], big_endian: bool) -> String {
+    let (bytes, big_endian) = match bytes {
+        [0xFE, 0xFF, rest @ ..] => (rest, true),
+        [0xFF, 0xFE, rest @ ..] => (rest, false),
+        _ => (bytes, big_endian),
+    };
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| {
+            if big_endian {
+                be_u16(pair)
+            } else {
+                le_u16(pair)
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Formats a number of seconds, dropping the fraction.
+fn seconds(value: f64) -> String {
+    format!("{}", value.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_unknown() {
+        let arg1 = "test/res/state_from_file/success/test1.json";
+
+        let result = extract(arg1).unwrap();
+
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn extract_year_of() {
+        assert_eq!(year_of("2011-05-03T00:00:00"), Some("2011".to_string()));
+        assert_eq!(year_of("May 2011"), None);
+    }
+}
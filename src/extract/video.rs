@@ -0,0 +1,268 @@
+//! MP4 movie headers and Matroska segment information.
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::{be_u16, be_u32, be_u64, seconds, Fields};
+use hoard::civil_date;
+use Result;
+
+/// Reads the duration, creation year and dimensions of an MP4 or
+/// QuickTime file from its `moov` box, wherever it is in the file.
+pub fn mp4(path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if head.len() < 8 || &head[4..8] != b"ftyp" {
+        return Ok(None);
+    }
+
+    let mut file = fs::File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut offset = 0;
+    while offset + 8 <= length {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header[..8])?;
+        let (size, start) = match u64::from(be_u32(&header)) {
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (be_u64(&header[8..]), 16)
+            }
+            0 => (length - offset, 8),
+            size => (size, 8),
+        };
+        // Boxes running past the end of the file are corrupt.
+        if size < start || size > length - offset {
+            break;
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0; (size - start) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(movie(&moov)));
+        }
+        offset += size;
+    }
+    Ok(Some(Fields::new()))
+}
+
+/// Iterates over the boxes directly inside a box body.
+fn boxes(body: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= body.len() {
+        let size = be_u32(&body[offset..]) as usize;
+        if size < 8 || offset + size > body.len() {
+            break;
+        }
+        out.push((
+            &body[offset + 4..offset + 8],
+            &body[offset + 8..offset + size],
+        ));
+        offset += size;
+    }
+    out
+}
+
+fn movie(moov: &[u8]) -> Fields {
+    // Seconds between 1904-01-01, the MP4 epoch, and 1970-01-01.
+    const EPOCH: i64 = 2_082_844_800;
+
+    let mut fields = Fields::new();
+    let mut width = 0;
+    let mut height = 0;
+    for (kind, body) in boxes(moov) {
+        match kind {
+            b"mvhd" if !body.is_empty() => {
+                let (created, scale, duration) = if body[0] == 1 && body.len() >= 32 {
+                    (be_u64(&body[4..]), be_u32(&body[20..]), be_u64(&body[24..]))
+                } else if body.len() >= 20 {
+                    let created = u64::from(be_u32(&body[4..]));
+                    (created, be_u32(&body[12..]), u64::from(be_u32(&body[16..])))
+                } else {
+                    continue;
+                };
+                if scale > 0 {
+                    let duration = duration as f64 / f64::from(scale);
+                    fields.insert("duration".to_string(), seconds(duration));
+                }
+                if created > 0 {
+                    let (year, _, _) = civil_date(created as i64 - EPOCH);
+                    fields.insert("year".to_string(), year.to_string());
+                }
+            }
+            b"trak" => {
+                for (kind, body) in boxes(body) {
+                    if kind != b"tkhd" || body.is_empty() {
+                        continue;
+                    }
+                    // Width and height are 16.16 fixed point at the end.
+                    let end = if body[0] == 1 { 92 } else { 80 };
+                    if body.len() >= end && be_u16(&body[end - 8..]) > width {
+                        width = be_u16(&body[end - 8..]);
+                        height = be_u16(&body[end - 4..]);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if width > 0 {
+        fields.insert("width".to_string(), width.to_string());
+        fields.insert("height".to_string(), height.to_string());
+    }
+    fields
+}
+
+/// Reads the title, duration, date and dimensions of a Matroska or WebM
+/// file from the segment headers at its start.
+pub fn matroska(_path: &Path, head: &[u8]) -> Result<Option<Fields>> {
+    if !head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Ok(None);
+    }
+
+    let mut fields = Fields::new();
+    let segment = elements(head)
+        .into_iter()
+        .find(|&(id, _)| id == 0x1853_8067)
+        .map(|(_, body)| body)
+        .unwrap_or_default();
+
+    let mut scale = 1_000_000;
+    let mut duration = None;
+    for (id, body) in elements(segment) {
+        match id {
+            // Info
+            0x1549_A966 => {
+                for (id, body) in elements(body) {
+                    match id {
+                        0x2A_D7B1 => scale = uint(body),
+                        0x4489 => duration = float(body),
+                        0x7BA9 => {
+                            let title = String::from_utf8_lossy(body);
+                            fields.insert("title".to_string(), title.to_string());
+                        }
+                        0x4461 if body.len() == 8 => {
+                            // Nanoseconds since 2001-01-01.
+                            let nanos = be_u64(body) as i64;
+                            let (year, _, _) = civil_date(978_307_200 + nanos / 1_000_000_000);
+                            fields.insert("year".to_string(), year.to_string());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // Tracks, then TrackEntry, Video, PixelWidth and PixelHeight.
+            0x1654_AE6B => {
+                for (_, entry) in elements(body).into_iter().filter(|&(id, _)| id == 0xAE) {
+                    for (_, video) in elements(entry).into_iter().filter(|&(id, _)| id == 0xE0) {
+                        for (id, body) in elements(video) {
+                            match id {
+                                0xB0 => fields.insert("width".to_string(), uint(body).to_string()),
+                                0xBA => fields.insert("height".to_string(), uint(body).to_string()),
+                                _ => None,
+                            };
+                        }
+                    }
+                }
+            }
+            // Clusters hold the frames, and come after the headers.
+            0x1F43_B675 => break,
+            _ => {}
+        }
+    }
+    if let Some(duration) = duration {
+        let duration = duration * scale as f64 / 1e9;
+        fields.insert("duration".to_string(), seconds(duration));
+    }
+    Ok(Some(fields))
+}
+
+/// Reads an EBML variable length integer, returning its value and
+/// length. The length marker is kept in IDs and removed from sizes.
+fn vint(bytes: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    if first == 0 {
+        return None;
+    }
+    let length = first.leading_zeros() as usize + 1;
+    let mut value = if keep_marker {
+        u64::from(first)
+    } else {
+        u64::from(first) & (0xFF >> length)
+    };
+    for &byte in bytes.get(1..length)? {
+        value = value << 8 | u64::from(byte);
+    }
+    Some((value, length))
+}
+
+/// Splits an EBML body into its child elements. An element that runs
+/// past the end of the bytes, like the segment, is cut short.
+fn elements(body: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while let Some((id, id_length)) = vint(&body[offset..], true) {
+        let (size, size_length) = match vint(&body[offset + id_length..], false) {
+            Some(size) => size,
+            None => break,
+        };
+        let start = offset + id_length + size_length;
+        let end = (start as u64).saturating_add(size).min(body.len() as u64) as usize;
+        out.push((id, &body[start..end]));
+        offset = end;
+    }
+    out
+}
+
+fn uint(body: &[u8]) -> u64 {
+    body.iter()
+        .fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+fn float(body: &[u8]) -> Option<f64> {
+    match body.len() {
+        4 => Some(f64::from(f32::from_bits(be_u32(body)))),
+        8 => Some(f64::from_bits(be_u64(body))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn video_mp4_corrupt() {
+        let path = env::temp_dir().join(format!("hoard-video-mp4-{}", process::id()));
+        let mut arg1 = b"\x00\x00\x00\x10ftypisom\x00\x00\x00\x00".to_vec();
+        arg1.extend_from_slice(b"\xff\xff\xff\xf0moov\x00\x00\x00\x00");
+        let mut arg2 = b"\x00\x00\x00\x10ftypisom\x00\x00\x00\x00".to_vec();
+        arg2.extend_from_slice(b"\x00\x00\x00\x01moov\xff\xff\xff\xff\xff\xff\xff\xf0");
+        let arg3 = b"\xff\xff\xff\xffftypisom\x00\x00\x00\x00".to_vec();
+
+        for arg in [arg1, arg2, arg3].iter() {
+            fs::write(&path, arg).unwrap();
+            let result = mp4(&path, arg);
+            assert!(result.unwrap().unwrap().is_empty());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn video_matroska() {
+        let mut arg1 = Vec::new();
+        arg1.extend_from_slice(b"\x1a\x45\xdf\xa3\x80");
+        arg1.extend_from_slice(b"\x18\x53\x80\x67\x01\xff\xff\xff\xff\xff\xff\xff");
+        arg1.extend_from_slice(b"\x15\x49\xa9\x66\x8e");
+        arg1.extend_from_slice(b"\x44\x89\x84\x47\x6a\x60\x00");
+        arg1.extend_from_slice(b"\x7b\xa9\x84Film");
+        arg1.extend_from_slice(b"\x16\x54\xae\x6b\x8b\xae\x89\xe0\x87\xb0\x82\x07\x80\xba\x81\x10");
+
+        let result = matroska(Path::new(""), &arg1).unwrap().unwrap();
+
+        assert_eq!(result["title"], "Film");
+        assert_eq!(result["duration"], "60");
+        assert_eq!(result["width"], "1920");
+        assert_eq!(result["height"], "16");
+    }
+}
@@ -0,0 +1,91 @@
+//! The manifests recorded when hoard itself changes the manifest, such
+//! as when `watch` follows files moved around the working tree, or when
+//! `snapshot` records it.
+//!
+//! Every entry is kept in `.hoard/history`, numbered from 1, with the
+//! time it was recorded, a description of the change and the manifest
+//! as it was written, which `history` lists.
+//!
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use copies;
+use error::ResultExt;
+use manifest::Manifest;
+use Result;
+
+/// A recorded manifest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(skip)]
+    pub id: usize,
+    /// The time it was recorded, in seconds since the unix epoch.
+    pub time: u64,
+    pub message: String,
+    pub manifest: Manifest,
+}
+
+fn dir(root: &Path) -> PathBuf {
+    root.join(".hoard/history")
+}
+
+fn path_of(root: &Path, id: usize) -> PathBuf {
+    dir(root).join(format!("{:06}.json", id))
+}
+
+/// Lists the numbers of the recorded entries, in order.
+fn ids(root: &Path) -> Result<Vec<usize>> {
+    let dir = dir(root);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(&dir).with_path(&dir)? {
+        let path = entry.with_path(&dir)?.path();
+        let id = path
+            .file_stem()
+            .filter(|_| path.extension().is_some_and(|ext| ext == "json"))
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+        if let Some(id) = id {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Records the manifest with a description of the change, returning the
+/// number of the new entry.
+pub fn record(root: &Path, manifest: &Manifest, message: &str) -> Result<usize> {
+    let id = ids(root)?.last().map(|id| id + 1).unwrap_or(1);
+    let entry = Entry {
+        id,
+        time: copies::now(),
+        message: message.to_string(),
+        manifest: manifest.clone(),
+    };
+    let path = path_of(root, id);
+    fs::create_dir_all(dir(root)).with_path(dir(root))?;
+    fs::write(&path, serde_json::to_string_pretty(&entry)?).with_path(&path)?;
+    Ok(id)
+}
+
+/// Reads the entry with the given number.
+pub fn get(root: &Path, id: usize) -> Result<Entry> {
+    let path = path_of(root, id);
+    if !path.exists() {
+        bail!("No history entry @{}", id);
+    }
+    let text = fs::read_to_string(&path).with_path(&path)?;
+    let mut entry: Entry = serde_json::from_str(&text)?;
+    entry.id = id;
+    Ok(entry)
+}
+
+/// Reads every entry, oldest first.
+pub fn list(root: &Path) -> Result<Vec<Entry>> {
+    ids(root)?.into_iter().map(|id| get(root, id)).collect()
+}
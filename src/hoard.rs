@@ -1,19 +1,40 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::ops::Deref;
 use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use hex;
-use multi_map::MultiMap as TwoKeyMap;
 use pathdiff;
 use regex::Regex;
+use serde_json;
 use sha2::{Digest, Sha256};
 use walkdir::{DirEntry, WalkDir};
 
+use chunks::{self, Recipe};
+use cold;
+use config::{Config, RemoteConfig};
+use copies;
+use crypt::{self, EncryptedStore};
+use diff::Diff;
+use dupes::{self, Group};
+use edit;
 use error::ResultExt;
-use state::Index;
+use export::{self, Entry};
+use extract;
+use history;
+use manifest::{Format, Manifest, VERSION};
+use metadata::{Metadata, MetadataStore, RESERVED};
+use naming::{self, Policy};
+use query::{Attributes, Query};
+use remote::{self, Sync};
+use similar;
+use sniff;
+use state::{self, Index, Object, State};
+use store::{self, FileStore, Layout, LinkStore, Store};
+use template::Template;
+use validate::{self, Diagnostic};
 use Result;
 
 fn is_empty_dir<P: AsRef<Path>>(path: P) -> bool {
@@ -44,15 +65,17 @@ fn _link(src: &Path, dst: &Path) -> Result<bool> {
         fs::create_dir_all(parent).with_path(parent)?;
     }
 
-    if !dst.exists() {
-        fs::hard_link(src, dst)?;
-        return Ok(true);
-    }
+    let dst_metadata = match dst.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            fs::hard_link(src, dst)?;
+            return Ok(true);
+        }
+    };
 
+    // Placeholders are replaced even if they lead to the same object.
     let src_ino = src.metadata().with_path(src)?.ino();
-    let dst_ino = dst.metadata().with_path(dst)?.ino();
-
-    if src_ino != dst_ino {
+    if dst_metadata.file_type().is_symlink() || src_ino != dst_metadata.ino() {
         fs::remove_file(dst)?;
         fs::hard_link(src, dst)?;
     }
@@ -60,6 +83,80 @@ fn _link(src: &Path, dst: &Path) -> Result<bool> {
     Ok(false)
 }
 
+/// Creates a placeholder for an absent object, which is a relative link
+/// to where its contents would be stored.
+pub fn placeholder(object: &Path, dst: &Path) -> Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent).with_path(parent)?;
+    }
+    if dst.symlink_metadata().is_ok() {
+        fs::remove_file(dst).with_path(dst)?;
+    }
+    let target = dst
+        .parent()
+        .and_then(|parent| pathdiff::diff_paths(object, parent))
+        .unwrap_or_else(|| object.to_path_buf());
+    ::std::os::unix::fs::symlink(&target, dst).with_path(dst)?;
+    Ok(())
+}
+
+/// Returns the attributes of the contents of an object, which are kept in
+/// its metadata while they are not in `by-hash`.
+fn contents_of(object: &Object) -> Result<BTreeMap<String, String>> {
+    Ok(object
+        .attributes()?
+        .iter()
+        .filter(|&(key, _)| key != "name" && key != "hash")
+        .map(|(key, values)| (key.to_string(), values[0].clone()))
+        .collect())
+}
+
+/// Resolves `.` and `..` in a path without touching the filesystem.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component.as_os_str()),
+        }
+    }
+    normal
+}
+
+/// Formats a number of bytes using binary units.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Converts seconds since the unix epoch into a (year, month, day) date.
+pub fn civil_date(secs: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's days-to-civil algorithm.
+    let days = secs.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct FileHash(String);
 
@@ -110,13 +207,9 @@ pub struct FileObject {
 impl FileObject {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
-        let parts = path.components().rev().take(2).collect::<Vec<_>>();
-        let object = parts[0].as_os_str().to_string_lossy();
-        let prefix = parts[1].as_os_str().to_string_lossy();
-        let hash_str = format!("{}{}", prefix, object);
         Ok(FileObject {
             path: path.to_path_buf(),
-            hash: FileHash::from_str(&hash_str)?,
+            hash: store::parse(path)?,
         })
     }
 
@@ -124,10 +217,6 @@ impl FileObject {
         Ok(self.path.metadata().with_path(&self.path)?.ino())
     }
 
-    pub fn name(&self) -> String {
-        "name".to_string()
-    }
-
     pub fn path(&self) -> &Path {
         &self.path
     }
@@ -138,83 +227,505 @@ impl FileObject {
 }
 
 // TODO: consider caching approach instead of eagerly loading.
-struct ObjectStore {
-    path: PathBuf,
-    objects: TwoKeyMap<u64, FileHash, FileObject>,
+pub(crate) struct ObjectStore {
+    files: FileStore,
+    layout: Layout,
+    /// The compressed contents in the cold tier, as described in the
+    /// `cold` module.
+    cold: PathBuf,
+    frozen: HashMap<FileHash, PathBuf>,
+    /// The recipes of chunked contents, as described in the `chunks`
+    /// module, and whether unlinked contents are chunked.
+    chunks: PathBuf,
+    recipes: PathBuf,
+    packed: HashMap<FileHash, PathBuf>,
+    chunked: bool,
 }
 
 impl ObjectStore {
     /// Loads an ObjectDatabase from on disk from the given path of
     /// a hoard repository.
-    fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
-        let path = root.as_ref().join(".hoard/objects");
-        let mut objects = TwoKeyMap::new();
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let path = root.as_ref().join(".hoard/objects/by-hash");
+        let config = Config::load(&root)?.store;
+        let cold = root.as_ref().join(".hoard/objects/cold");
+        let recipes = root.as_ref().join(".hoard/objects/recipes");
+        Ok(ObjectStore {
+            files: FileStore::new(path, config.layout)?,
+            layout: config.layout,
+            frozen: ObjectStore::tier(&cold, "zst")?,
+            cold,
+            chunks: root.as_ref().join(".hoard/objects/chunks"),
+            packed: ObjectStore::tier(&recipes, "json")?,
+            recipes,
+            chunked: config.chunked,
+        })
+    }
 
-        for entry in WalkDir::new(&path) {
+    /// Lists the files with the given extension in a directory of the
+    /// cold tier by the hash they are named after.
+    fn tier(path: &Path, extension: &str) -> Result<HashMap<FileHash, PathBuf>> {
+        let mut files = HashMap::new();
+        if !path.is_dir() {
+            return Ok(files);
+        }
+        for entry in WalkDir::new(path) {
             let entry = entry?;
-            if !entry.path().is_file() {
-                continue;
+            let path = entry.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == extension) {
+                files.insert(store::parse(&path.with_extension(""))?, path.to_path_buf());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Compresses the contents at `src` into the cold tier, returning
+    /// their compressed size.
+    fn compress(&mut self, hash: &FileHash, src: &Path) -> Result<Option<u64>> {
+        let dst = self
+            .cold
+            .join(self.layout.relative(hash))
+            .with_extension("zst");
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_path(parent)?;
+        }
+        let tmp = dst.with_extension("tmp");
+        {
+            let mut reader = io::BufReader::new(fs::File::open(src).with_path(src)?);
+            let mut writer = io::BufWriter::new(fs::File::create(&tmp).with_path(&tmp)?);
+            cold::compress(&mut reader, &mut writer)?;
+            writer.flush().with_path(&tmp)?;
+        }
+
+        let size = src.metadata().with_path(src)?.len();
+        let compressed = tmp.metadata().with_path(&tmp)?.len();
+        let mut reader = io::BufReader::new(fs::File::open(&tmp).with_path(&tmp)?);
+        let checked = cold::decompress(&mut reader, &mut io::sink()).ok();
+        if !cold::worthwhile(size, compressed) || checked.as_ref() != Some(hash) {
+            fs::remove_file(&tmp).with_path(&tmp)?;
+            return Ok(None);
+        }
+        fs::rename(&tmp, &dst).with_path(&dst)?;
+        self.frozen.insert(hash.clone(), dst);
+        Ok(Some(compressed))
+    }
+
+    /// Splits the contents at `src` into chunks, unless they already are,
+    /// returning the size of the chunks that weren't stored yet.
+    fn pack(&mut self, hash: &FileHash, src: &Path) -> Result<Option<u64>> {
+        if self.packed.contains_key(hash) {
+            let intact = self.verify_cold(hash)?;
+            return Ok(if intact { Some(0) } else { None });
+        }
+
+        let mut written = Vec::new();
+        let reader = io::BufReader::new(fs::File::open(src).with_path(src)?);
+        let (recipe, whole) = chunks::split(reader, |chunk, data| {
+            let path = self.chunks.join(self.layout.relative(chunk));
+            if path.exists() {
+                return Ok(());
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).with_path(parent)?;
+            }
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, data).with_path(&tmp)?;
+            fs::rename(&tmp, &path).with_path(&path)?;
+            written.push(path);
+            Ok(())
+        })?;
+        if whole != *hash {
+            for path in written.iter() {
+                fs::remove_file(path).with_path(path)?;
+            }
+            return Ok(None);
+        }
+
+        let dst = self
+            .recipes
+            .join(self.layout.relative(hash))
+            .with_extension("json");
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_path(parent)?;
+        }
+        let tmp = dst.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&recipe)?).with_path(&tmp)?;
+        fs::rename(&tmp, &dst).with_path(&dst)?;
+        self.packed.insert(hash.clone(), dst);
+        if !self.verify_cold(hash)? {
+            return Ok(None);
+        }
+
+        let mut size = 0;
+        for path in written.iter() {
+            size += path.metadata().with_path(path)?.len();
+        }
+        Ok(Some(size))
+    }
+
+    /// Reads the recipe of chunked contents.
+    fn recipe(&self, hash: &FileHash) -> Result<Recipe> {
+        match self.packed.get(hash) {
+            Some(path) => {
+                let text = fs::read_to_string(path).with_path(path)?;
+                Ok(serde_json::from_str(&text)?)
             }
-            let object = FileObject::new(entry.path())?;
-            objects.insert(object.ino()?, object.hash().clone(), object);
+            None => bail!("{} is not chunked", &hash[..12]),
         }
+    }
 
-        Ok(ObjectStore { path, objects })
+    /// Returns the chunks of the contents with the given hash that no
+    /// other chunked contents use.
+    fn exclusive_chunks(&self, hash: &FileHash) -> Result<HashSet<FileHash>> {
+        let mut chunks: HashSet<FileHash> = self.recipe(hash)?.chunks.into_iter().collect();
+        for other in self.packed.keys().filter(|other| *other != hash) {
+            for chunk in self.recipe(other)?.chunks.iter() {
+                chunks.remove(chunk);
+            }
+        }
+        Ok(chunks)
     }
 
-    /// Internal function
-    fn get_by_ino(&self, ino: &u64) -> Option<&FileObject> {
-        self.objects.get(ino)
+    /// Writes the cold copy of the contents with the given hash into
+    /// `writer`, preferring their chunks, and returns its hash.
+    fn read_cold<W: Write>(&self, hash: &FileHash, writer: &mut W) -> Result<FileHash> {
+        if self.packed.contains_key(hash) {
+            let load = |chunk: &FileHash| {
+                let path = self.chunks.join(self.layout.relative(chunk));
+                Ok(fs::read(&path).with_path(&path)?)
+            };
+            return chunks::join(&self.recipe(hash)?, load, writer);
+        }
+        match self.frozen.get(hash) {
+            Some(path) => {
+                let mut reader = io::BufReader::new(fs::File::open(path).with_path(path)?);
+                cold::decompress(&mut reader, writer)
+            }
+            None => bail!("{} is not in the cold tier", &hash[..12]),
+        }
     }
 
-    /// Internal function
-    fn get_by_hash(&self, hash: &FileHash) -> Option<&FileObject> {
-        self.objects.get_alt(hash)
+    /// Whether the cold copy of the contents still matches their hash.
+    fn verify_cold(&self, hash: &FileHash) -> Result<bool> {
+        let checked = self.read_cold(hash, &mut io::sink());
+        Ok(checked.ok().as_ref() == Some(hash))
     }
 
-    /// Puts an object that matches the file at path.
-    ///
-    /// If there is no such object currently in the store, then it will
-    /// be created.
-    fn put<P: AsRef<Path>>(&mut self, path: P) -> Result<FileHash> {
-        let path = path.as_ref();
+    /// Removes the compressed copy of contents that are stored again.
+    fn discard_cold(&mut self, hash: &FileHash) -> Result<()> {
+        if let Some(path) = self.frozen.remove(hash) {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+        Ok(())
+    }
 
-        let ino = path.metadata().with_path(&path)?.ino();
-        if let Some(object) = self.get_by_ino(&ino) {
-            return Ok(object.hash().clone());
+    /// Removes the recipe of chunked contents, along with the chunks no
+    /// other contents use.
+    fn unpack(&mut self, hash: &FileHash) -> Result<()> {
+        if !self.packed.contains_key(hash) {
+            return Ok(());
+        }
+        for chunk in self.exclusive_chunks(hash)? {
+            let path = self.chunks.join(self.layout.relative(&chunk));
+            fs::remove_file(&path).with_path(&path)?;
         }
+        if let Some(path) = self.packed.remove(hash) {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for ObjectStore {
+    fn has(&self, hash: &FileHash) -> bool {
+        self.files.has(hash) || self.frozen.contains_key(hash) || self.packed.contains_key(hash)
+    }
 
-        let hash = FileHash::of(path)?;
-        if let Some(_) = self.get_by_hash(&hash) {
-            return Ok(hash);
+    fn get(&self, hash: &FileHash, dst: &Path) -> Result<()> {
+        if !self.is_cold(hash) {
+            return self.files.get(hash, dst);
         }
+        let tmp = dst.with_extension("tmp");
+        let mut writer = io::BufWriter::new(fs::File::create(&tmp).with_path(&tmp)?);
+        let copied = self.read_cold(hash, &mut writer)?;
+        writer.flush().with_path(&tmp)?;
+        if copied != *hash {
+            fs::remove_file(&tmp).with_path(&tmp)?;
+            bail!("cold copy of {} does not match its hash", &hash[..12]);
+        }
+        fs::rename(&tmp, dst).with_path(dst)?;
+        Ok(())
+    }
+
+    fn put(&mut self, src: &Path, hash: &FileHash) -> Result<()> {
+        self.files.put(src, hash)?;
+        self.discard_cold(hash)
+    }
+
+    fn remove(&mut self, hash: &FileHash) -> Result<()> {
+        self.files.remove(hash)?;
+        self.unpack(hash)?;
+        self.discard_cold(hash)
+    }
 
-        let src = path;
-        let dst = self.path.join(hash.as_path());
+    fn list(&self) -> Result<Vec<FileHash>> {
+        let mut hashes: HashSet<FileHash> = self.files.list()?.into_iter().collect();
+        hashes.extend(self.frozen.keys().cloned());
+        hashes.extend(self.packed.keys().cloned());
+        Ok(hashes.into_iter().collect())
+    }
 
-        link(&src, &dst)?;
+    /// Checks every copy of the contents, in `by-hash` and in the cold
+    /// tier.
+    fn verify(&self, hash: &FileHash) -> Result<bool> {
+        if self.files.has(hash) && !self.files.verify(hash)? {
+            return Ok(false);
+        }
+        if self.frozen.contains_key(hash) || self.packed.contains_key(hash) {
+            return self.verify_cold(hash);
+        }
+        Ok(self.files.has(hash))
+    }
+}
 
-        let object = FileObject::new(dst)?;
-        self.objects
-            .insert(object.ino()?, object.hash().clone(), object);
+impl LinkStore for ObjectStore {
+    fn file(&self, hash: &FileHash) -> Option<&FileObject> {
+        self.files.file(hash)
+    }
 
+    fn link(&mut self, path: &Path) -> Result<FileHash> {
+        let hash = self.files.link(path)?;
+        self.discard_cold(&hash)?;
         Ok(hash)
     }
+
+    fn take(&mut self, path: &Path, hash: &FileHash) -> Result<()> {
+        self.files.take(path, hash)?;
+        self.discard_cold(hash)
+    }
+
+    fn link_target(&self, hash: &FileHash) -> PathBuf {
+        self.files.link_target(hash)
+    }
+
+    /// Counts the file, the compressed copy, and the recipe and chunks no
+    /// other contents share.
+    fn size(&self, hash: &FileHash) -> Result<u64> {
+        let mut paths: Vec<PathBuf> = self.frozen.get(hash).into_iter().cloned().collect();
+        if let Some(recipe) = self.packed.get(hash) {
+            paths.push(recipe.clone());
+            for chunk in self.exclusive_chunks(hash)? {
+                paths.push(self.chunks.join(self.layout.relative(&chunk)));
+            }
+        }
+        let mut size = self.files.size(hash)?;
+        for path in paths {
+            size += path.metadata().with_path(&path)?.len();
+        }
+        Ok(size)
+    }
+
+    /// Moves the contents from `by-hash` into the cold tier, chunked or
+    /// compressed, or leaves them as they are for not compressing well.
+    ///
+    /// The cold copy is checked against the hash before the contents are
+    /// removed from `by-hash`.
+    fn freeze(&mut self, hash: &FileHash) -> Result<Option<u64>> {
+        let src = match self.files.file(hash) {
+            Some(object) => object.path().to_path_buf(),
+            None => return Ok(None),
+        };
+        let size = src.metadata().with_path(&src)?.len();
+        let stored = if self.chunked {
+            self.pack(hash, &src)?
+        } else {
+            self.compress(hash, &src)?
+        };
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        self.files.remove(hash)?;
+        Ok(Some(size.saturating_sub(stored)))
+    }
+
+    /// Restores cold contents into `by-hash`. Chunks are kept, while
+    /// compressed copies are removed.
+    fn thaw(&mut self, hash: &FileHash) -> Result<()> {
+        if !self.is_cold(hash) {
+            return Ok(());
+        }
+        let copy = self.files.path_of(hash).with_extension("thaw");
+        if let Some(parent) = copy.parent() {
+            fs::create_dir_all(parent).with_path(parent)?;
+        }
+        self.get(hash, &copy)?;
+        self.files.take(&copy, hash)?;
+        self.discard_cold(hash)
+    }
+}
+
+/// Totals from importing a directory tree.
+#[derive(Debug, Default)]
+pub struct Import {
+    /// The number of files and their total size.
+    pub files: usize,
+    pub bytes: u64,
+    /// The number of objects that had to be stored and their total size.
+    pub objects: usize,
+    pub stored: u64,
+}
+
+/// Totals from exporting a view.
+#[derive(Debug, Default)]
+pub struct Export {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// A hoard that holds, or lacks, the contents of an object.
+#[derive(Debug)]
+pub struct Place {
+    /// `here`, the name of a remote, or the id of another hoard.
+    pub name: String,
+    pub present: bool,
+    /// When the hoard was last found to hold or lack the contents, if it
+    /// could not be checked just now.
+    pub since: Option<u64>,
+}
+
+/// The paths an object has in the working tree that a manifest doesn't
+/// give it, and the paths it gives that the object lacks.
+#[derive(Debug)]
+pub struct Drift {
+    pub name: String,
+    pub gained: BTreeSet<PathBuf>,
+    pub lost: BTreeSet<PathBuf>,
+}
+
+/// Names new objects and records the metadata extracted from them.
+struct Registry {
+    policy: Policy,
+    template: Option<Template>,
+    known: HashSet<FileHash>,
+    names: HashSet<String>,
+    metadata: MetadataStore,
+    by_name: PathBuf,
+    added: String,
+    id: String,
+}
+
+impl Registry {
+    fn new(repo: &Repository, index: &Index) -> Result<Self> {
+        let policy = repo.config.naming.policy;
+        let template = match (policy, repo.config.naming.template.as_ref()) {
+            (Policy::Template, Some(template)) => Some(Template::parse(template)?),
+            (Policy::Template, None) => bail!("the 'template' naming policy needs a template"),
+            _ => None,
+        };
+        let now = ::std::time::SystemTime::now()
+            .duration_since(::std::time::UNIX_EPOCH)?
+            .as_secs();
+        let (year, month, day) = civil_date(now as i64);
+
+        Ok(Registry {
+            policy,
+            template,
+            known: index.by_hash().keys().map(|&h| h.clone()).collect(),
+            names: index.by_name().keys().map(|n| n.to_string()).collect(),
+            metadata: repo.metadata(),
+            by_name: repo.root.join(".hoard/objects/by-name"),
+            added: format!("{:04}-{:02}-{:02}", year, month, day),
+            id: copies::store_id(&repo.root)?,
+        })
+    }
+
+    /// Names the object with the given hash, whose contents are in the
+    /// store, added from the source path, unless it already has a name.
+    /// Returns the new name.
+    fn register(
+        &mut self,
+        store: &dyn LinkStore,
+        hash: &FileHash,
+        source: &Path,
+        name: Option<&str>,
+    ) -> Result<Option<String>> {
+        if !self.known.insert(hash.clone()) {
+            return Ok(None);
+        }
+        let contents = match store.file(hash) {
+            Some(object) => object.path(),
+            None => bail!("the contents of {} are not stored", source.display()),
+        };
+
+        let fields = extract::extract(contents).unwrap_or_else(|e| {
+            eprintln!("warning: {}: {}", source.display(), e);
+            Default::default()
+        });
+        let (mime, kind) = sniff::sniff_path(contents)?;
+        let added = &self.added;
+        let id = &self.id;
+        let metadata = self.metadata.update(hash, |metadata| {
+            metadata.locate(id, true);
+            metadata.mime = Some(mime.to_string());
+            metadata.kind = Some(kind);
+            for (key, value) in fields {
+                metadata.fields.entry(key).or_insert(value);
+            }
+            metadata
+                .fields
+                .entry("added".to_string())
+                .or_insert_with(|| added.clone());
+            Ok(())
+        })?;
+
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => {
+                let mut attributes = Attributes::new();
+                attributes.insert("hash", hash.as_str());
+                attributes.insert("mime", mime);
+                attributes.insert("kind", kind.name());
+                metadata.extend(&mut attributes);
+                let name = naming::derive(self.policy, self.template.as_ref(), source, &attributes);
+                let names = &self.names;
+                naming::unique(&name, |name| {
+                    names.contains(name) || naming::check(name).is_err()
+                })
+            }
+        };
+        let symlink = self.by_name.join(&name);
+        let target = store.link_target(hash);
+        ::std::os::unix::fs::symlink(&target, &symlink).with_path(&symlink)?;
+        self.names.insert(name.clone());
+        Ok(Some(name))
+    }
 }
 
 pub struct Repository {
     root: PathBuf,
+    config: Config,
 }
 
 impl Repository {
-    /// Creates a new repository at the given path.
-    pub fn init<P: AsRef<Path>>(path: P) -> Result<()> {
-        Repository::_init(path.as_ref())
+    /// Creates a new repository at the given path, whose object store
+    /// has the given layout.
+    pub fn init<P: AsRef<Path>>(path: P, layout: Layout) -> Result<()> {
+        Repository::_init(path.as_ref(), layout)
     }
 
-    fn _init(path: &Path) -> Result<()> {
+    fn _init(path: &Path, layout: Layout) -> Result<()> {
+        if layout != Layout::default() && path.join(".hoard").exists() {
+            bail!("the layout of an existing hoard cannot be changed");
+        }
         fs::create_dir_all(path.join(".hoard/objects/by-hash"))?;
         fs::create_dir_all(path.join(".hoard/objects/by-name"))?;
+        if layout != Layout::default() {
+            let config = path.join(".hoard/config.toml");
+            let text = format!("[store]\nlayout = \"{}\"\n", layout.name());
+            fs::write(&config, text).with_path(&config)?;
+        }
+        copies::store_id(path)?;
         Ok(())
     }
 
@@ -231,105 +742,1630 @@ impl Repository {
                 break;
             }
             if root.join(".hoard").is_dir() {
-                return Ok(Repository { root });
+                let config = Config::load(&root)?;
+                return Ok(Repository { root, config });
             }
         }
 
         bail!("No hoard repository found")
     }
 
-    // change this to take a vector instead of a single argument?
-    pub fn add<P: AsRef<Path>>(&mut self, paths: Vec<P>) -> Result<()> {
-        // get index and state?
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn metadata(&self) -> MetadataStore {
+        MetadataStore::new(&self.root)
+    }
+
+    /// Finds an object by its name, its hash, or a path linked to it,
+    /// including placeholders.
+    pub fn object(&self, index: &Index, spec: &str) -> Result<Object> {
+        if let Some(object) = index.by_name().get(spec) {
+            return Ok((*object).clone());
+        }
+        if let Ok(hash) = FileHash::from_str(&spec.to_string()) {
+            if let Some(object) = index.by_hash().get(&hash) {
+                return Ok((*object).clone());
+            }
+        }
+        if let Ok(metadata) = Path::new(spec).metadata() {
+            if let Some(object) = index.by_ino().get(&metadata.ino()) {
+                return Ok((*object).clone());
+            }
+        }
+        if let Ok(target) = Path::new(spec).read_link() {
+            if let Ok(placeholder) = FileObject::new(target) {
+                if let Some(object) = index.by_hash().get(placeholder.hash()) {
+                    return Ok((*object).clone());
+                }
+            }
+        }
+        bail!("No such object '{}'", spec)
+    }
+
+    /// Returns every attribute of the object, including its metadata.
+    pub fn info(&self, spec: &str) -> Result<Attributes> {
+        let index = Index::from(&self.root)?;
+        let object = self.object(&index, spec)?;
+        let mut attributes = object.attributes()?;
+        self.metadata().get(object.hash())?.extend(&mut attributes);
+        Ok(attributes)
+    }
+
+    /// Returns the metadata of the named object.
+    pub fn metadata_of(&self, spec: &str) -> Result<Metadata> {
+        let index = Index::from(&self.root)?;
+        let object = self.object(&index, spec)?;
+        self.metadata().get(object.hash())
+    }
+
+    /// Applies the function to the metadata of the named object.
+    pub fn update_metadata<F>(&self, spec: &str, func: F) -> Result<Metadata>
+    where
+        F: FnOnce(&mut Metadata) -> Result<()>,
+    {
+        let index = Index::from(&self.root)?;
+        let object = self.object(&index, spec)?;
+        self.metadata().update(object.hash(), func)
+    }
+
+    /// Sets a metadata field of the named object, or removes it if there
+    /// is no value.
+    pub fn set_field(&self, spec: &str, key: &str, value: Option<&str>) -> Result<()> {
+        if RESERVED.contains(&key) {
+            bail!("'{}' is provided by hoard and cannot be set", key);
+        }
+        self.update_metadata(spec, |metadata| {
+            match value {
+                Some(value) => metadata.fields.insert(key.to_string(), value.to_string()),
+                None => metadata.fields.remove(key),
+            };
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Counts the objects carrying each tag.
+    pub fn tags(&self) -> Result<BTreeMap<String, usize>> {
+        let index = Index::from(&self.root)?;
+        let store = self.metadata();
+        let mut tags = BTreeMap::new();
+        for object in index.objects.iter() {
+            for tag in store.get(object.hash())?.tags {
+                *tags.entry(tag).or_insert(0) += 1;
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Lists the names of the objects matching the query.
+    pub fn query(&self, query: &str) -> Result<Vec<String>> {
+        let query = Query::parse(query)?;
+        let index = Index::from(&self.root)?;
+        let catalog = index.catalog(&self.metadata())?;
+        Ok(catalog
+            .into_iter()
+            .filter(|(_, attributes)| query.matches(attributes))
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// Renames an object, along with its entry in every file of the
+    /// manifest that lists it.
+    ///
+    /// Metadata is keyed by hash, so it is unaffected.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let by_name = self.root.join(".hoard/objects/by-name");
+        let src = by_name.join(from);
+        let dst = by_name.join(to);
+        if src.read_link().is_err() {
+            bail!("No such object '{}'", from);
+        }
+        if dst.symlink_metadata().is_ok() {
+            bail!("An object named '{}' already exists", to);
+        }
+        naming::check(to)?;
 
-        // check if file already is in hoard
-        // if it is, then just add the path
-        // if it is not, then add a new object entry
-        // check if path is below root
-        // MULTI-THREADING?
+        // The link is renamed first, so that a failure leaves nothing to
+        // undo, and the manifests are put back if one cannot be edited.
+        let owners = edit::owners(self.manifest_path(), &self.root, from)?;
+        let mut originals = Vec::new();
+        for owner in owners.iter() {
+            let text = fs::read(&owner.file).with_path(&owner.file)?;
+            originals.push((&owner.file, text));
+        }
+        fs::rename(&src, &dst).with_path(&src)?;
+        for owner in owners.iter() {
+            let new = Some((to, &owner.paths));
+            if let Err(e) = edit::replace(&owner.file, &owner.prefix, from, new) {
+                for (file, text) in originals.iter() {
+                    fs::write(file, text).with_path(file)?;
+                }
+                fs::rename(&dst, &src).with_path(&dst)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
 
+    /// Adds the files at the given paths, and every file below them, to
+    /// the hoard, extracting metadata from their contents.
+    ///
+    /// Objects are named by the naming policy, unless a name is given,
+    /// which is only allowed when adding a single file.
+    pub fn add<P: AsRef<Path>>(&mut self, paths: Vec<P>, name: Option<&str>) -> Result<()> {
         let mut results = Vec::new();
         for path in paths {
             self._expand(&mut results, path.as_ref())?;
         }
-        println!("{:#?}", results);
+        if let Some(name) = name {
+            if results.len() != 1 {
+                bail!("a name can only be given when adding a single file");
+            }
+            naming::check(name)?;
+        }
 
         let index = Index::from(&self.root)?;
+        let mut registry = Registry::new(self, &index)?;
+        if let Some(name) = name {
+            if registry.names.contains(name) {
+                bail!("An object named '{}' already exists", name);
+            }
+        }
+        let mut store = self.store()?;
+
         for path in results {
-            // if path inode is in index, ignore
-            // if path hash is in index (and is same file), delete and link
-            // if path hash is not in index, add to index
+            let hash = store.link(&path)?;
+            let object = store.file(&hash).unwrap();
+            link(object.path(), &path)?;
+            if let Some(name) = registry.register(&*store, &hash, &path, name)? {
+                println!("add: {} as {}", path.display(), name);
+            }
         }
 
         Ok(())
     }
 
-    fn _expand(&self, results: &mut Vec<PathBuf>, path: &Path) -> Result<()> {
-        fn is_index(entry: &DirEntry) -> bool {
-            entry
-                .file_name()
-                .to_str()
-                .map(|s| s == ".hoard")
-                .unwrap_or(false)
+    /// Imports a directory tree from outside the hoard, storing every
+    /// distinct content once and recreating the layout of the tree at
+    /// `dest` with links.
+    ///
+    /// Files are copied unless `remove` is set, in which case they are
+    /// moved, and only removed once the stored copy has been verified.
+    /// Exports made by `export` are checked against their hash list first.
+    pub fn import<S, D>(&mut self, src: S, dest: D, remove: bool) -> Result<Import>
+    where
+        S: AsRef<Path>,
+        D: AsRef<Path>,
+    {
+        let src = src.as_ref().canonicalize().with_path(src.as_ref())?;
+        if src.starts_with(&self.root) {
+            bail!(
+                "'{}' is already inside the hoard, use 'add' instead",
+                src.display()
+            );
+        }
+        let dest = normalize(&self.root.join(dest));
+        if !dest.starts_with(&self.root) || dest.starts_with(self.root.join(".hoard")) {
+            bail!("'{}' is not inside the working tree", dest.display());
         }
 
-        let path = path.canonicalize()?;
-        if !path.starts_with(&self.root) {
-            bail!(
-                "pathspec is not inside of hoard repository: {}",
-                path.display()
-            )
+        // An export is verified against its hash list before anything is
+        // imported, and its objects keep the names in its manifest.
+        let is_export =
+            src.join(export::CHECKSUMS).is_file() && src.join(export::MANIFEST).is_file();
+        let mut names = HashMap::new();
+        let mut checksums = BTreeMap::new();
+        if is_export {
+            checksums = export::read_checksums(src.join(export::CHECKSUMS))?;
+            let manifest = Manifest::from_file(src.join(export::MANIFEST))?;
+            for (name, paths) in manifest.objects {
+                for path in paths {
+                    names.insert(path, name.clone());
+                }
+            }
         }
 
-        for entry in WalkDir::new(path)
-            .into_iter()
-            .filter_entry(|e| !is_index(e))
-        {
+        let mut files = Vec::new();
+        for entry in WalkDir::new(&src).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
             let entry = entry?;
-            if entry.path().is_file() {
-                results.push(entry.path().to_path_buf());
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(&src)?.to_path_buf();
+            if is_export
+                && (relative == Path::new(export::CHECKSUMS)
+                    || relative == Path::new(export::MANIFEST))
+            {
+                continue;
+            }
+            let hash = FileHash::of(entry.path())?;
+            if let Some(expected) = checksums.remove(&relative) {
+                if expected != hash {
+                    bail!(
+                        "'{}' does not match {}",
+                        entry.path().display(),
+                        export::CHECKSUMS
+                    );
+                }
             }
+            files.push((relative, entry.metadata()?.len(), hash));
+        }
+        if let Some(missing) = checksums.keys().next() {
+            bail!(
+                "'{}' is listed in {} but missing",
+                src.join(missing).display(),
+                export::CHECKSUMS
+            );
         }
-        Ok(())
-    }
 
-    /*
-    fn _add_file(&mut self, path: &Path) -> Result<()> {
-        let relative_path = pathdiff::diff_paths(&path, &self.root).unwrap();
+        let index = Index::from(&self.root)?;
+        let mut registry = Registry::new(self, &index)?;
+        let mut store = self.store()?;
+        let mut report = Import::default();
 
-        let created = link(object.path(), path)?;
-        if created {
-            println!("create: {}", path.display());
+        for (relative, size, hash) in files {
+            let path = &src.join(&relative);
+            let target = dest.join(&relative);
+            if target.exists() && FileHash::of(&target)? != hash {
+                eprintln!(
+                    "warning: '{}' already exists with other content, skipping {}",
+                    target.display(),
+                    path.display()
+                );
+                continue;
+            }
+
+            let is_new = !store.has(&hash);
+            if remove {
+                store.take(path, &hash)?;
+            } else {
+                store.put(path, &hash)?;
+            }
+            let object = store.file(&hash).unwrap();
+            link(object.path(), &target)?;
+            let name = names
+                .get(&relative)
+                .filter(|name| naming::check(name).is_ok())
+                .map(|name| naming::unique(name, |name| registry.names.contains(name)));
+            registry.register(&*store, &hash, path, name.as_deref())?;
+            println!("import: {} -> {}", path.display(), target.display());
+
+            report.files += 1;
+            report.bytes += size;
+            if is_new {
+                report.objects += 1;
+                report.stored += size;
+            }
         }
-        Ok(())
+
+        if remove {
+            for entry in WalkDir::new(&src).contents_first(true).into_iter() {
+                let entry = entry?;
+                if entry.file_type().is_dir() && is_empty_dir(entry.path()) {
+                    fs::remove_dir(entry.path()).with_path(entry.path())?;
+                }
+            }
+        }
+        Ok(report)
     }
-    */
 
-    pub fn apply(&self) -> Result<()> {
-        let mut inodes: HashSet<u64> = HashSet::new();
-        let mut paths: HashSet<PathBuf> = HashSet::new();
+    /// Lists the files in the working tree with the same contents.
+    pub fn dupes(&self) -> Result<Vec<Group>> {
+        let index = Index::from(&self.root)?;
+        dupes::find(&self.root, &index)
+    }
 
-        let index = self.root.join(".hoard/");
-        for entry in WalkDir::new(&self.root).contents_first(true) {
-            let entry = entry?;
+    /// Replaces every copy of the same contents in the working tree with
+    /// a link to a single stored object, returning the number of bytes
+    /// reclaimed. Nothing is changed on a dry run.
+    pub fn dedupe(&mut self, dry_run: bool) -> Result<u64> {
+        let index = Index::from(&self.root)?;
+        let groups = dupes::find(&self.root, &index)?;
+        let mut registry = Registry::new(self, &index)?;
+        let mut store = self.store()?;
+        let mut reclaimed = 0;
 
-            if entry.path().starts_with(&index) {
-                continue;
+        for group in groups {
+            // Prefer a copy that is already an object, then a stored
+            // object, and otherwise store the first copy.
+            let ino = match group.copies.iter().find(|copy| copy.linked) {
+                Some(copy) => copy.ino,
+                None => match store.file(&group.hash) {
+                    Some(object) => object.ino()?,
+                    None => group.copies[0].ino,
+                },
+            };
+            let first = &group.copies[0].path;
+            if !dry_run && store.file(&group.hash).is_none() {
+                store.link(first)?;
+                registry.register(&*store, &group.hash, first, None)?;
             }
 
-            if inodes.contains(&entry.path().metadata()?.ino()) {
-                if !paths.contains(entry.path()) {
-                    fs::remove_file(entry.path())?;
-                    println!("delete: {}", entry.path().display());
+            let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+            for copy in group.copies.iter().filter(|copy| copy.ino != ino) {
+                *counts.entry(copy.ino).or_insert(0) += 1;
+                if dry_run {
+                    println!("would link: {}", copy.path.display());
+                } else {
+                    let object = store.file(&group.hash).unwrap();
+                    link(object.path(), &copy.path)?;
+                    println!("link: {}", copy.path.display());
+                }
+            }
+            // A file only frees space once every link to it is replaced.
+            for copy in group.copies.iter() {
+                if counts.remove(&copy.ino) == Some(copy.nlink) {
+                    reclaimed += group.size;
                 }
             }
+        }
+        Ok(reclaimed)
+    }
 
-            if is_empty_dir(entry.path()) {
-                fs::remove_dir(entry.path())?;
-                println!("delete: {}/", entry.path().display());
+    /// Returns the name and fingerprint of every object that has one,
+    /// computing and storing the fingerprints that are missing.
+    fn fingerprints(&self, index: &Index) -> Result<Vec<(String, String)>> {
+        let store = self.metadata();
+        let mut fingerprints = Vec::new();
+        for object in index.objects.iter() {
+            let mut metadata = store.get(object.hash())?;
+            let fingerprint = match metadata.fingerprint.take() {
+                Some(fingerprint) => fingerprint,
+                None if !object.is_present() => continue,
+                None => {
+                    let mut attributes = object.attributes()?;
+                    metadata.extend(&mut attributes);
+                    let fingerprint = similar::fingerprint(object.path(), &attributes)
+                        .unwrap_or_else(|e| {
+                            eprintln!("warning: {}: {}", object.name(), e);
+                            similar::NONE.to_string()
+                        });
+                    store.update(object.hash(), |metadata| {
+                        metadata.fingerprint = Some(fingerprint.clone());
+                        Ok(())
+                    })?;
+                    fingerprint
+                }
+            };
+            if fingerprint != similar::NONE {
+                fingerprints.push((object.name().to_string(), fingerprint));
             }
         }
+        fingerprints.sort();
+        Ok(fingerprints)
+    }
 
-        Ok(())
+    /// Lists the objects within the threshold of the given object, with
+    /// the closest first.
+    pub fn similar_to(&self, spec: &str, threshold: u32) -> Result<Vec<(String, u32)>> {
+        let index = Index::from(&self.root)?;
+        let object = self.object(&index, spec)?;
+        let fingerprints = self.fingerprints(&index)?;
+        let fingerprint = match fingerprints.iter().find(|(n, _)| n == object.name()) {
+            Some((_, fingerprint)) => fingerprint,
+            None => bail!("'{}' has no fingerprint", object.name()),
+        };
+        let mut similar: Vec<_> = fingerprints
+            .iter()
+            .filter(|(name, _)| name != object.name())
+            .filter_map(|(name, other)| {
+                similar::distance(fingerprint, other).map(|d| (name.clone(), d))
+            })
+            .filter(|&(_, d)| d <= threshold)
+            .collect();
+        similar.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(similar)
+    }
+
+    /// Groups the objects that are within the threshold of each other.
+    pub fn similar(&self, threshold: u32) -> Result<Vec<Vec<String>>> {
+        let index = Index::from(&self.root)?;
+        let fingerprints = self.fingerprints(&index)?;
+        let borrowed: Vec<_> = fingerprints
+            .iter()
+            .map(|(name, fingerprint)| (name.as_str(), fingerprint.as_str()))
+            .collect();
+        Ok(similar::clusters(&borrowed, threshold)
+            .into_iter()
+            .map(|cluster| cluster.into_iter().map(String::from).collect())
+            .collect())
+    }
+
+    /// Copies the files of a view out of the hoard to the given path, as
+    /// described in the `export` module. The view is a directory of the
+    /// desired layout, and everything is exported without one.
+    pub fn export(&self, view: Option<&str>, to: &Path) -> Result<Export> {
+        let index = Index::from(&self.root)?;
+        let path = self.manifest_path();
+        let manifest = if path.exists() {
+            Manifest::load(&path, &self.root)?
+        } else {
+            self.snapshot()?
+        };
+        let catalog = index.catalog(&self.metadata())?;
+        let desire = State::from_manifest(&manifest, &catalog)?;
+
+        let view = normalize(Path::new(view.unwrap_or("")));
+        if view.has_root() {
+            bail!("view '{}' must be relative to the hoard", view.display());
+        }
+        let by_name = index.by_name();
+        let mut entries = Vec::new();
+        let mut report = Export::default();
+        for (name, paths) in desire.inner.iter() {
+            let object = match by_name.get(name.as_str()) {
+                Some(object) => object,
+                None => continue,
+            };
+            for path in paths.iter() {
+                if let Ok(path) = path.strip_prefix(&view) {
+                    if !object.is_present() {
+                        bail!("'{}' is absent, use 'get' first", name);
+                    }
+                    report.files += 1;
+                    report.bytes += object.path().metadata().with_path(object.path())?.len();
+                    entries.push(Entry {
+                        name: name.clone(),
+                        path: path.to_path_buf(),
+                        source: object.path().to_path_buf(),
+                        hash: object.hash().clone(),
+                    });
+                }
+            }
+        }
+        if entries.is_empty() {
+            bail!("view '{}' has no objects", view.display());
+        }
+
+        export::write(&entries, to)?;
+        Ok(report)
+    }
+
+    /// Lists the configured remotes.
+    pub fn remotes(&self) -> &BTreeMap<String, RemoteConfig> {
+        &self.config.remotes
+    }
+
+    /// Adds another hoard as a remote with the given name, or creates an
+    /// encrypted store at the path if `encrypted` is set.
+    pub fn add_remote(&mut self, name: &str, path: &Path, encrypted: bool) -> Result<()> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            bail!("remote names can only contain letters, digits, '-' and '_'");
+        }
+        if self.config.remotes.contains_key(name) {
+            bail!("remote '{}' already exists", name);
+        }
+        if encrypted {
+            fs::create_dir_all(path).with_path(path)?;
+        }
+        let path = path.canonicalize().with_path(path)?;
+        if path == self.root {
+            bail!("a hoard cannot be its own remote");
+        }
+        if encrypted {
+            EncryptedStore::init(&path)?;
+        }
+        if !path.join(".hoard").is_dir() {
+            bail!("'{}' is not the root of a hoard", path.display());
+        }
+        let id = copies::store_id(&path)?;
+        for (other, remote) in self.config.remotes.iter() {
+            if remote.path == path || remote.id.as_ref() == Some(&id) {
+                bail!("'{}' is already the remote '{}'", path.display(), other);
+            }
+        }
+        Config::add_remote(&self.root, name, &path, &id)?;
+        self.config = Config::load(&self.root)?;
+        Ok(())
+    }
+
+    /// Returns the root of the named remote, if it is available.
+    fn remote(&self, name: &str) -> Result<PathBuf> {
+        let remote = match self.config.remotes.get(name) {
+            Some(remote) => remote,
+            None => bail!("No such remote '{}'", name),
+        };
+        if !remote.path.join(".hoard").is_dir() {
+            bail!(
+                "remote '{}' is not available at {}",
+                name,
+                remote.path.display()
+            );
+        }
+        Ok(remote.path.clone())
+    }
+
+    /// Copies the objects of the remote that are missing here, without
+    /// merging their names or metadata.
+    pub fn fetch(&self, remote: &str) -> Result<Sync> {
+        remote::sync(&self.hoard_remote(remote)?, &self.root, false)
+    }
+
+    /// Copies the objects of the remote that are missing here, and merges
+    /// their names and metadata.
+    pub fn pull(&self, remote: &str) -> Result<Sync> {
+        remote::sync(&self.hoard_remote(remote)?, &self.root, true)
+    }
+
+    /// Copies the objects that the remote is missing, and merges their
+    /// names and metadata into it. An encrypted store only receives the
+    /// contents.
+    pub fn push(&self, remote: &str) -> Result<Sync> {
+        let root = self.remote(remote)?;
+        if crypt::is_encrypted(&root) {
+            let mut store = EncryptedStore::unlock(&root)?;
+            return remote::backup(&self.root, &mut store, &copies::store_id(&root)?);
+        }
+        remote::sync(&self.root, &root, true)
+    }
+
+    /// Returns the root of the named remote, if it is an available hoard
+    /// rather than an encrypted store.
+    fn hoard_remote(&self, name: &str) -> Result<PathBuf> {
+        let root = self.remote(name)?;
+        if crypt::is_encrypted(&root) {
+            bail!(
+                "remote '{}' is an encrypted store, whose contents are copied with 'get'",
+                name
+            );
+        }
+        Ok(root)
+    }
+
+    /// Finds the objects named by the terms, which are either the names,
+    /// hashes or paths of objects, or a query.
+    fn select(&self, index: &Index, terms: &[&str]) -> Result<Vec<Object>> {
+        let objects: Result<Vec<_>> = terms.iter().map(|term| self.object(index, term)).collect();
+        if let Ok(objects) = objects {
+            return Ok(objects);
+        }
+        let query = Query::parse(&terms.join(" "))?;
+        let by_name = index.by_name();
+        let objects: Vec<Object> = index
+            .catalog(&self.metadata())?
+            .into_iter()
+            .filter(|(_, attributes)| query.matches(attributes))
+            .map(|(name, _)| by_name[name.as_str()].clone())
+            .collect();
+        if objects.is_empty() {
+            bail!("No objects match '{}'", terms.join(" "));
+        }
+        Ok(objects)
+    }
+
+    /// Lists the files in the working tree, other than placeholders,
+    /// by inode.
+    fn files_by_ino(&self) -> Result<HashMap<u64, Vec<PathBuf>>> {
+        let mut files: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        for entry in WalkDir::new(&self.root)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != ".hoard")
+        {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let ino = entry.metadata()?.ino();
+                files
+                    .entry(ino)
+                    .or_default()
+                    .push(entry.path().to_path_buf());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Lists the placeholders in the working tree with the hash of the
+    /// object they stand for.
+    fn placeholders(&self) -> Result<Vec<(PathBuf, FileHash)>> {
+        let mut placeholders = Vec::new();
+        for entry in WalkDir::new(&self.root)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != ".hoard")
+        {
+            let entry = entry?;
+            if !entry.path_is_symlink() {
+                continue;
+            }
+            let target = entry.path().read_link().with_path(entry.path())?;
+            if let Ok(object) = FileObject::new(target) {
+                placeholders.push((entry.path().to_path_buf(), object.hash().clone()));
+            }
+        }
+        Ok(placeholders)
+    }
+
+    /// Copies the contents of the absent objects named by the terms from
+    /// a remote, or the first remote that has them, and replaces their
+    /// placeholders with links. Returns the number of bytes copied.
+    pub fn get_contents(&self, terms: &[&str], from: Option<&str>) -> Result<u64> {
+        let index = Index::from(&self.root)?;
+        let objects = self.select(&index, terms)?;
+        let remotes = match from {
+            Some(name) => vec![store::open(&self.remote(name)?)?],
+            None => self
+                .remote_stores()?
+                .into_iter()
+                .map(|(_, store)| store)
+                .collect(),
+        };
+
+        let mut store = self.store()?;
+        let metadata = self.metadata();
+        let here = copies::store_id(&self.root)?;
+        let mut copied = HashSet::new();
+        let mut missing = 0;
+        let mut bytes = 0;
+        for object in objects.iter().filter(|object| !object.is_present()) {
+            if store.is_cold(object.hash()) {
+                store.thaw(object.hash())?;
+                metadata.update(object.hash(), |metadata| {
+                    metadata.contents.clear();
+                    Ok(())
+                })?;
+                println!("get: {}", object.name());
+                copied.insert(object.hash().clone());
+                continue;
+            }
+            let source = remotes.iter().find(|remote| remote.has(object.hash()));
+            let source = match source {
+                Some(source) => source,
+                None => {
+                    eprintln!("warning: no remote has the contents of '{}'", object.name());
+                    missing += 1;
+                    continue;
+                }
+            };
+            let copy = self
+                .root
+                .join(".hoard/objects")
+                .join(format!("{}.get", object.hash().as_str()));
+            source.get(object.hash(), &copy)?;
+            bytes += copy.metadata().with_path(&copy)?.len();
+            store.take(&copy, object.hash())?;
+            metadata.update(object.hash(), |metadata| {
+                metadata.contents.clear();
+                metadata.locate(&here, true);
+                Ok(())
+            })?;
+            println!("get: {}", object.name());
+            copied.insert(object.hash().clone());
+        }
+
+        for (path, hash) in self.placeholders()? {
+            if copied.contains(&hash) {
+                link(store.file(&hash).unwrap().path(), &path)?;
+            }
+        }
+        if missing > 0 {
+            bail!("{} object(s) could not be found on any remote", missing);
+        }
+        Ok(bytes)
+    }
+
+    /// Removes the local contents of the objects named by the terms,
+    /// leaving placeholders in the working tree. Returns the number of
+    /// bytes freed.
+    ///
+    /// An object is only dropped once enough remotes are confirmed to
+    /// hold identical copies of it, as described in the `copies` module.
+    pub fn drop_contents(&self, terms: &[&str]) -> Result<u64> {
+        let index = Index::from(&self.root)?;
+        let objects = self.select(&index, terms)?;
+        let metadata = self.metadata();
+        let catalog = index.catalog(&metadata)?;
+        let remotes = self.remote_stores()?;
+        let here = copies::store_id(&self.root)?;
+
+        let mut store = self.store()?;
+        let mut files = self.files_by_ino()?;
+        let mut kept = 0;
+        let mut bytes = 0;
+        let stored: Vec<&Object> = objects
+            .iter()
+            .filter(|object| object.is_present() || store.is_cold(object.hash()))
+            .collect();
+        for object in stored {
+            let ids = match self.spare_copies(object, &catalog[object.name()], &remotes)? {
+                Some(ids) => ids,
+                None => {
+                    kept += 1;
+                    continue;
+                }
+            };
+
+            // Cold objects already keep the attributes of their contents.
+            let contents = if object.is_present() {
+                Some(contents_of(object)?)
+            } else {
+                None
+            };
+            metadata.update(object.hash(), |metadata| {
+                if let Some(contents) = contents {
+                    metadata.contents = contents;
+                }
+                metadata.locate(&here, false);
+                for id in ids.iter() {
+                    metadata.locate(id, true);
+                }
+                Ok(())
+            })?;
+            if object.is_present() {
+                for path in files.remove(object.ino()).unwrap_or_default() {
+                    placeholder(object.path(), &path)?;
+                }
+            }
+            bytes += self.unlink_contents(&mut *store, object)?;
+            println!("drop: {}", object.name());
+        }
+        if kept > 0 {
+            bail!("{} object(s) kept, as too few remotes have a copy", kept);
+        }
+        Ok(bytes)
+    }
+
+    /// Removes the objects with the given names, hashes or paths from the
+    /// hoard, along with their links and placeholders in the working
+    /// tree and their entries in the manifest. Returns the number of
+    /// bytes freed.
+    ///
+    /// Their contents stay in the store unless `purge` is set, in which
+    /// case an object is only removed once enough remotes are confirmed
+    /// to hold identical copies of it.
+    pub fn remove(&self, terms: &[&str], purge: bool) -> Result<u64> {
+        let index = Index::from(&self.root)?;
+        let objects = terms
+            .iter()
+            .map(|term| self.object(&index, term))
+            .collect::<Result<Vec<_>>>()?;
+        let metadata = self.metadata();
+        let catalog = index.catalog(&metadata)?;
+        let remotes = self.remote_stores()?;
+        let here = copies::store_id(&self.root)?;
+
+        let mut store = self.store()?;
+        let mut files = self.files_by_ino()?;
+        let mut placeholders: HashMap<FileHash, Vec<PathBuf>> = HashMap::new();
+        for (path, hash) in self.placeholders()? {
+            placeholders.entry(hash).or_default().push(path);
+        }
+        let manifest = self.manifest_path();
+        let by_name = self.root.join(".hoard/objects/by-name");
+        let mut kept = 0;
+        let mut bytes = 0;
+        for object in objects.iter() {
+            let purged = purge && store.has(object.hash());
+            let ids = if purged {
+                match self.spare_copies(object, &catalog[object.name()], &remotes)? {
+                    Some(ids) => ids,
+                    None => {
+                        kept += 1;
+                        continue;
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+
+            for owner in edit::owners(&manifest, &self.root, object.name())? {
+                edit::replace(&owner.file, &owner.prefix, object.name(), None)?;
+            }
+            let mut paths = placeholders.remove(object.hash()).unwrap_or_default();
+            if object.is_present() {
+                paths.extend(files.remove(object.ino()).unwrap_or_default());
+            }
+            for path in paths {
+                fs::remove_file(&path).with_path(&path)?;
+            }
+            let link = by_name.join(object.name());
+            fs::remove_file(&link).with_path(&link)?;
+            if purged {
+                metadata.update(object.hash(), |metadata| {
+                    metadata.locate(&here, false);
+                    for id in ids.iter() {
+                        metadata.locate(id, true);
+                    }
+                    Ok(())
+                })?;
+                bytes += self.unlink_contents(&mut *store, object)?;
+            }
+            println!("rm: {}", object.name());
+        }
+        if kept > 0 {
+            bail!("{} object(s) kept, as too few remotes have a copy", kept);
+        }
+        Ok(bytes)
+    }
+
+    /// Lists the hoards that hold or lack the contents of an object,
+    /// along with the number of copies it should have.
+    ///
+    /// This hoard and the available remotes are checked, and the result
+    /// is recorded. Other hoards are listed as they were last seen.
+    pub fn whereis(&self, spec: &str) -> Result<(Vec<Place>, usize)> {
+        let index = Index::from(&self.root)?;
+        let object = self.object(&index, spec)?;
+        let store = self.metadata();
+        let catalog = index.catalog(&store)?;
+        let required = copies::required(&self.config.copies, &catalog[object.name()])?;
+        let mut metadata = store.get(object.hash())?;
+
+        let here = copies::store_id(&self.root)?;
+        let present = self.store()?.has(object.hash());
+        let mut checked = vec![("here".to_string(), here, present)];
+        let mut unavailable = Vec::new();
+        for (name, config) in self.config.remotes.iter() {
+            match self.remote(name) {
+                Ok(root) => {
+                    // A hoard configured under several names holds one copy.
+                    let id = copies::store_id(&root)?;
+                    if checked.iter().any(|checked| checked.1 == id) {
+                        continue;
+                    }
+                    let present = store::open(&root)?.has(object.hash());
+                    checked.push((name.clone(), id, present));
+                }
+                Err(_) => {
+                    if let Some(id) = config.id.as_ref() {
+                        unavailable.push((name.clone(), id.clone()));
+                    }
+                }
+            }
+        }
+
+        let mut places = Vec::new();
+        let mut seen = HashSet::new();
+        let mut changed = false;
+        for (name, id, present) in checked {
+            changed |= metadata.locate(&id, present);
+            places.push(Place {
+                name,
+                present,
+                since: None,
+            });
+            seen.insert(id);
+        }
+        for (name, id) in unavailable {
+            if let Some(location) = metadata.locations.get(&id) {
+                places.push(Place {
+                    name,
+                    present: location.present,
+                    since: Some(location.since),
+                });
+            }
+            seen.insert(id);
+        }
+        for (id, location) in metadata.locations.iter() {
+            if !seen.contains(id) {
+                places.push(Place {
+                    name: id.clone(),
+                    present: location.present,
+                    since: Some(location.since),
+                });
+            }
+        }
+        if changed {
+            store.put(object.hash(), &metadata)?;
+        }
+        Ok((places, required))
+    }
+
+    /// Opens the stores of the remotes that are available, along with
+    /// their ids. A store configured under several names is opened once.
+    fn remote_stores(&self) -> Result<Vec<(String, Box<dyn Store>)>> {
+        let mut stores: Vec<(String, Box<dyn Store>)> = Vec::new();
+        for name in self.config.remotes.keys() {
+            if let Ok(root) = self.remote(name) {
+                let id = copies::store_id(&root)?;
+                if !stores.iter().any(|(other, _)| *other == id) {
+                    stores.push((id, store::open(&root)?));
+                }
+            }
+        }
+        Ok(stores)
+    }
+
+    /// Returns the ids of the remotes holding identical copies of an
+    /// object, if there are enough of them for its contents to be
+    /// removed here.
+    fn spare_copies(
+        &self,
+        object: &Object,
+        attributes: &Attributes,
+        remotes: &[(String, Box<dyn Store>)],
+    ) -> Result<Option<Vec<String>>> {
+        let mut ids = Vec::new();
+        for (id, remote) in remotes.iter() {
+            if ids.contains(id) {
+                continue;
+            }
+            if remote.has(object.hash()) && remote.verify(object.hash())? {
+                ids.push(id.clone());
+            }
+        }
+        let required = copies::required(&self.config.copies, attributes)?;
+        if ids.len() < required {
+            eprintln!(
+                "warning: '{}' needs {} copies, but only {} remote(s) have one",
+                object.name(),
+                required,
+                ids.len()
+            );
+            return Ok(None);
+        }
+        Ok(Some(ids))
+    }
+
+    /// Removes the stored contents of an object, returning their size.
+    fn unlink_contents(&self, store: &mut dyn LinkStore, object: &Object) -> Result<u64> {
+        if object.is_present() {
+            let stat = object.path().metadata().with_path(object.path())?;
+            if stat.nlink() > 1 {
+                eprintln!(
+                    "warning: '{}' is still linked outside the working tree",
+                    object.name()
+                );
+            }
+        }
+        let size = store.size(object.hash())?;
+        store.remove(object.hash())?;
+        Ok(size)
+    }
+
+    /// Checks the stored contents of every object against their hash,
+    /// decompressing cold contents to do so. Returns the number of
+    /// contents checked and the names of the damaged objects, or their
+    /// hash if they have no name.
+    pub fn fsck(&self) -> Result<(usize, Vec<String>)> {
+        let index = Index::from(&self.root)?;
+        let by_hash = index.by_hash();
+        let store = self.store()?;
+        let mut hashes = store.list()?;
+        hashes.sort();
+
+        let mut damaged = Vec::new();
+        for hash in hashes.iter() {
+            if !store.verify(hash)? {
+                damaged.push(match by_hash.get(hash) {
+                    Some(object) => object.name().to_string(),
+                    None => hash.to_string(),
+                });
+            }
+        }
+        Ok((hashes.len(), damaged))
+    }
+
+    /// Decompresses the cold objects the manifest gives a path to, and
+    /// links the placeholders standing for them.
+    fn thaw_desired(&self, manifest: &Manifest, store: &mut dyn LinkStore) -> Result<()> {
+        let index = Index::from(&self.root)?;
+        let metadata = self.metadata();
+        let catalog = index.catalog(&metadata)?;
+        let desire = State::from_manifest(manifest, &catalog)?;
+        let by_name = index.by_name();
+
+        let mut thawed = HashSet::new();
+        for (name, paths) in desire.inner.iter() {
+            if paths.is_empty() {
+                continue;
+            }
+            let object = match by_name.get(name.as_str()) {
+                Some(object) if store.is_cold(object.hash()) => object,
+                _ => continue,
+            };
+            store.thaw(object.hash())?;
+            metadata.update(object.hash(), |metadata| {
+                metadata.contents.clear();
+                Ok(())
+            })?;
+            println!("thaw: {}", name);
+            thawed.insert(object.hash().clone());
+        }
+        if !thawed.is_empty() {
+            for (path, hash) in self.placeholders()? {
+                if thawed.contains(&hash) {
+                    link(store.file(&hash).unwrap().path(), &path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compresses the objects whose contents are no longer linked outside
+    /// the store, keeping the attributes of their contents in their
+    /// metadata as for absent objects.
+    fn freeze_unlinked(&self, store: &mut dyn LinkStore) -> Result<()> {
+        let index = Index::from(&self.root)?;
+        let metadata = self.metadata();
+        for object in index.objects.iter().filter(|object| object.is_present()) {
+            let stat = object.path().metadata().with_path(object.path())?;
+            if stat.nlink() > 1 {
+                continue;
+            }
+            let contents = contents_of(object)?;
+            if let Some(saved) = store.freeze(object.hash())? {
+                metadata.update(object.hash(), |metadata| {
+                    metadata.contents = contents;
+                    Ok(())
+                })?;
+                println!("freeze: {} ({} saved)", object.name(), human_size(saved));
+            }
+        }
+        Ok(())
+    }
+
+    fn _expand(&self, results: &mut Vec<PathBuf>, path: &Path) -> Result<()> {
+        // The index, manifest fragments and ignore files belong to the
+        // hoard itself rather than to its contents.
+        fn is_index(entry: &DirEntry) -> bool {
+            entry
+                .file_name()
+                .to_str()
+                .map(|s| s == ".hoard" || s == ".hoardignore" || s.starts_with(".hoard."))
+                .unwrap_or(false)
+        }
+
+        let path = path.canonicalize()?;
+        if !path.starts_with(&self.root) {
+            bail!(
+                "pathspec is not inside of hoard repository: {}",
+                path.display()
+            )
+        }
+
+        for entry in WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|e| !is_index(e))
+        {
+            let entry = entry?;
+            if entry.path().is_file() {
+                results.push(entry.path().to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    fn _add_file(&mut self, path: &Path) -> Result<()> {
+        let relative_path = pathdiff::diff_paths(&path, &self.root).unwrap();
+
+        let created = link(object.path(), path)?;
+        if created {
+            println!("create: {}", path.display());
+        }
+        Ok(())
+    }
+    */
+
+    /// Opens the object store of the hoard.
+    fn store(&self) -> Result<Box<dyn LinkStore>> {
+        store::local(&self.root)
+    }
+
+    /// Returns the path of the manifest describing the desired state.
+    ///
+    /// A manifest in the configured format is preferred, followed by a
+    /// manifest in any other format. If there is no manifest yet, the
+    /// path it should be created at is returned.
+    pub fn manifest_path(&self) -> PathBuf {
+        let configured = self.config.manifest.format.unwrap_or(Format::Json);
+        let path_of = |format: Format| {
+            self.root
+                .join(".hoard")
+                .join(format!("manifest.{}", format.extension()))
+        };
+
+        Some(configured)
+            .iter()
+            .chain(Format::all())
+            .map(|format| path_of(*format))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| path_of(configured))
+    }
+
+    /// Builds a manifest from the links currently in the repository.
+    pub fn snapshot(&self) -> Result<Manifest> {
+        let index = Index::from(&self.root)?;
+        let actual = State::from_path(&self.root, &index)?;
+
+        let mut manifest = Manifest {
+            version: Some(VERSION),
+            ..Manifest::default()
+        };
+        for (name, paths) in actual.inner.into_iter() {
+            let paths = paths
+                .iter()
+                .filter_map(|path| path.strip_prefix(&self.root).ok())
+                .map(Path::to_path_buf)
+                .collect();
+            manifest.objects.insert(name, paths);
+        }
+        Ok(manifest)
+    }
+
+    /// Updates the manifest at the given path with the links currently in
+    /// the repository, and returns it with the objects whose paths changed.
+    ///
+    /// Only objects whose links differ from the paths the manifest gives
+    /// them, through its rules and fragments as well, are listed anew.
+    /// Everything else in the manifest is kept as it is.
+    pub fn merge_snapshot(&self, path: &Path) -> Result<(Manifest, Vec<Drift>)> {
+        let snapshot = self.snapshot()?;
+        let mut manifest = Manifest::from_file(path)?;
+        let index = Index::from(&self.root)?;
+        let catalog = index.catalog(&self.metadata())?;
+        let desired = State::from_manifest(&Manifest::load(path, &self.root)?, &catalog)?;
+
+        let empty = BTreeSet::new();
+        let names: BTreeSet<&String> = snapshot
+            .objects
+            .keys()
+            .chain(desired.inner.keys())
+            .collect();
+        let mut drifts = Vec::new();
+        for name in names {
+            let actual = snapshot.objects.get(name).unwrap_or(&empty);
+            let wanted = desired.inner.get(name).unwrap_or(&empty);
+            if actual == wanted {
+                continue;
+            }
+            drifts.push(Drift {
+                name: name.clone(),
+                gained: actual.difference(wanted).cloned().collect(),
+                lost: wanted.difference(actual).cloned().collect(),
+            });
+            manifest.objects.insert(name.clone(), actual.clone());
+        }
+        manifest.version = Some(VERSION);
+        Ok((manifest, drifts))
+    }
+
+    /// Compares two states of the repository, each given as a manifest
+    /// file, the root of the working tree, or a history entry as `@N`.
+    pub fn diff(&self, a: &str, b: &str) -> Result<Diff> {
+        let index = Index::from(&self.root)?;
+        let catalog = index.catalog(&self.metadata())?;
+        let a = self.state_of(a, &index, &catalog)?;
+        let b = self.state_of(b, &index, &catalog)?;
+        Ok(Diff::new(&a, &b, &index))
+    }
+
+    /// Reads one side of a diff, with paths relative to the root.
+    fn state_of(
+        &self,
+        spec: &str,
+        index: &Index,
+        catalog: &BTreeMap<String, Attributes>,
+    ) -> Result<State> {
+        let path = Path::new(spec);
+        if spec.starts_with('@') {
+            return State::from_manifest(&self.manifest_of(spec)?, catalog);
+        }
+        if !self.is_root(path)? {
+            return State::from_manifest(&Manifest::load(path, &self.root)?, catalog);
+        }
+        let mut state = State::from_path(&self.root, index)?;
+        for paths in state.inner.values_mut() {
+            *paths = paths
+                .iter()
+                .filter_map(|path| path.strip_prefix(&self.root).ok())
+                .map(Path::to_path_buf)
+                .collect();
+        }
+        Ok(state)
+    }
+
+    /// Reads a manifest given as a file, as the root of the working tree
+    /// for a snapshot of it, or as a history entry `@N`. Manifest files
+    /// are read without what they include.
+    pub fn manifest_of(&self, spec: &str) -> Result<Manifest> {
+        if let Some(id) = spec.strip_prefix('@') {
+            let id = id
+                .parse()
+                .map_err(|_| format_err!("'{}' is not a history entry", spec))?;
+            return Ok(history::get(&self.root, id)?.manifest);
+        }
+
+        let path = Path::new(spec);
+        if self.is_root(path)? {
+            return self.snapshot();
+        }
+        Manifest::from_file(path)
+    }
+
+    /// Whether the path is the root of the working tree, rather than a
+    /// file. Fails for any other directory.
+    fn is_root(&self, path: &Path) -> Result<bool> {
+        if !path.is_dir() {
+            return Ok(false);
+        }
+        let root = self.root.canonicalize().with_path(&self.root)?;
+        if path.canonicalize().with_path(path)? != root {
+            bail!("{} is not the root of the hoard", path.display());
+        }
+        Ok(true)
+    }
+
+    /// Checks the manifest against the objects in the repository.
+    pub fn validate(&self) -> Result<Vec<Diagnostic>> {
+        let path = self.manifest_path();
+        if !path.exists() {
+            bail!("No manifest found at {}", path.display());
+        }
+
+        let index = Index::from(&self.root)?;
+        let names = index
+            .by_name()
+            .keys()
+            .map(|name| name.to_string())
+            .collect();
+        validate::validate(&path, &self.root, &names)
+    }
+
+    /// Syncs the links in the repository to the manifest.
+    ///
+    /// Nothing is changed if the manifest fails validation.
+    pub fn apply(&self) -> Result<()> {
+        let diagnostics = self.validate()?;
+        if !diagnostics.is_empty() {
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}", diagnostic);
+            }
+            bail!(
+                "manifest has {} problem(s), nothing applied",
+                diagnostics.len()
+            );
+        }
+
+        let path = self.manifest_path();
+        let manifest = Manifest::load(&path, &self.root)?;
+        let mut store = self.store()?;
+        self.thaw_desired(&manifest, &mut *store)?;
+        let index = Index::from(&self.root)?;
+        let catalog = index.catalog(&self.metadata())?;
+        let desire = State::from_manifest(&manifest, &catalog)?.rebase(&self.root);
+        let actual = State::from_path(&self.root, &index)?;
+
+        for change in state::resolve(&desire, &actual, &index) {
+            println!("{}", change);
+            change.execute()?;
+        }
+
+        let index = self.root.join(".hoard/");
+        for entry in WalkDir::new(&self.root).contents_first(true) {
+            let entry = entry?;
+
+            if entry.path().starts_with(&index) {
+                continue;
+            }
+
+            if is_empty_dir(entry.path()) {
+                fs::remove_dir(entry.path())?;
+                println!("delete: {}/", entry.path().display());
+            }
+        }
+
+        if self.config.store.cold || self.config.store.chunked {
+            self.freeze_unlinked(&mut *store)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Creates an empty hoard in a temporary directory for the test.
+    fn hoard(test: &str) -> Repository {
+        let root = env::temp_dir().join(format!("hoard-{}-{}", test, process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        Repository::init(&root, Layout::default()).unwrap();
+        Repository::load(&root).unwrap()
+    }
+
+    /// Copies a fixture to a temporary directory, for tests that change it.
+    fn copy(fixture: &str, test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hoard-{}-src-{}", test, process::id()));
+        for entry in WalkDir::new(fixture).into_iter().map(|e| e.unwrap()) {
+            let path = dir.join(entry.path().strip_prefix(fixture).unwrap());
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&path).unwrap();
+            } else {
+                fs::copy(entry.path(), &path).unwrap();
+            }
+        }
+        dir
+    }
+
+    #[test]
+    fn repository_import_dedupes() {
+        let mut arg1 = hoard("import-dedupes");
+        let arg2 = "test/res/repository_import/tree";
+
+        let result = arg1.import(arg2, "imported", false).unwrap();
+
+        let root = arg1.root().join("imported");
+        let ino = |path: &str| root.join(path).metadata().unwrap().ino();
+        assert_eq!((result.files, result.bytes), (3, 17));
+        assert_eq!((result.objects, result.stored), (2, 11));
+        assert_eq!(ino("a.txt"), ino("sub/copy.txt"));
+        assert!(Path::new(arg2).join("a.txt").exists());
+        fs::remove_dir_all(arg1.root()).unwrap();
+    }
+
+    #[test]
+    fn repository_import_move() {
+        let mut arg1 = hoard("import-move");
+        let arg2 = copy("test/res/repository_import/tree", "import-move");
+
+        let result = arg1.import(&arg2, "imported", true).unwrap();
+
+        assert_eq!(result.files, 3);
+        assert!(!arg2.exists());
+        let (checked, errors) = arg1.fsck().unwrap();
+        assert_eq!((checked, errors.len()), (2, 0));
+        let text = fs::read_to_string(arg1.root().join("imported/b.txt")).unwrap();
+        assert_eq!(text, "beta\n");
+        fs::remove_dir_all(arg1.root()).unwrap();
+    }
+
+    #[test]
+    fn repository_import_mismatch() {
+        let mut arg1 = hoard("import-mismatch");
+        let arg2 = copy("test/res/repository_import/mismatch", "import-mismatch");
+
+        let result = arg1.import(&arg2, "imported", true);
+
+        assert!(result.unwrap_err().to_string().contains("does not match"));
+        assert!(arg2.join("a.txt").exists() && arg2.join("b.txt").exists());
+        assert!(!arg1.root().join("imported").exists());
+        fs::remove_dir_all(arg1.root()).unwrap();
+        fs::remove_dir_all(&arg2).unwrap();
+    }
+
+    #[test]
+    fn repository_import_conflict() {
+        let mut arg1 = hoard("import-conflict");
+        let arg2 = "test/res/repository_import/tree";
+        let target = arg1.root().join("imported/b.txt");
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&target, "mine\n").unwrap();
+
+        let result = arg1.import(arg2, "imported", false).unwrap();
+
+        assert_eq!(result.files, 2);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "mine\n");
+        let index = Index::from(arg1.root()).unwrap();
+        assert!(arg1.object(&index, "a").is_ok());
+        assert!(arg1.object(&index, "b").is_err());
+        fs::remove_dir_all(arg1.root()).unwrap();
+    }
+
+    #[test]
+    fn repository_snapshot_stable() {
+        let mut arg1 = hoard("snapshot-stable");
+        let root = arg1.root().to_path_buf();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for file in ["b.txt", "a.txt", "sub/c.txt"].iter() {
+            fs::write(root.join(file), file).unwrap();
+        }
+        arg1.add(vec![root.clone()], None).unwrap();
+        fs::hard_link(root.join("b.txt"), root.join("sub/b.txt")).unwrap();
+
+        let result = arg1.snapshot().unwrap().to_string(Format::Json).unwrap();
+
+        let again = arg1.snapshot().unwrap().to_string(Format::Json).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(result, again);
+        let expected = r#"{
+  "version": 1,
+  "a": [
+    "a.txt"
+  ],
+  "b": [
+    "b.txt",
+    "sub/b.txt"
+  ],
+  "c": [
+    "sub/c.txt"
+  ]
+}"#;
+        assert_eq!(result.trim_end(), expected);
+    }
+
+    #[test]
+    fn repository_merge_snapshot() {
+        let mut arg1 = hoard("merge-snapshot");
+        let root = arg1.root().to_path_buf();
+        for file in ["a.txt", "b.txt"].iter() {
+            fs::write(root.join(file), file).unwrap();
+        }
+        arg1.add(vec![root.clone()], None).unwrap();
+        let arg2 = root.join(".hoard/manifest.json");
+        let text = r#"{
+  "a": ["a.txt"],
+  "b": ["b.txt"],
+  "ghost": ["ghost.txt"],
+  "rules": [{ "dir": "unread/", "query": "tag:unread" }]
+}"#;
+        fs::write(&arg2, text).unwrap();
+        fs::create_dir_all(root.join("moved")).unwrap();
+        fs::rename(root.join("a.txt"), root.join("moved/a.txt")).unwrap();
+
+        let (result, drifts) = arg1.merge_snapshot(&arg2).unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(drifts.len(), 1);
+        assert_eq!(drifts[0].name, "a");
+        let gained: Vec<_> = drifts[0].gained.iter().collect();
+        let lost: Vec<_> = drifts[0].lost.iter().collect();
+        assert_eq!(gained, [Path::new("moved/a.txt")]);
+        assert_eq!(lost, [Path::new("a.txt")]);
+        let paths = |name: &str| result.objects[name].iter().cloned().collect::<Vec<_>>();
+        assert_eq!(paths("a"), [PathBuf::from("moved/a.txt")]);
+        assert_eq!(paths("b"), [PathBuf::from("b.txt")]);
+        assert_eq!(paths("ghost"), [PathBuf::from("ghost.txt")]);
+        assert_eq!(result.rules.len(), 1);
+        assert_eq!(result.rules[0].dir, "unread/");
+    }
+
+    #[test]
+    fn repository_rename_rollback() {
+        let mut arg1 = hoard("rename-rollback");
+        let root = arg1.root().to_path_buf();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::hard_link(root.join("a.txt"), root.join("sub/a.txt")).unwrap();
+        arg1.add(vec![root.join("a.txt")], None).unwrap();
+        let main = r#"{ "a": ["a.txt"] }"#;
+        let fragment = "? a\n: [a.txt]\n";
+        fs::write(root.join(".hoard/manifest.json"), main).unwrap();
+        fs::write(root.join("sub/.hoard.yaml"), fragment).unwrap();
+
+        let result = arg1.rename("a", "b");
+
+        let by_name = root.join(".hoard/objects/by-name");
+        let links = (by_name.join("a").exists(), by_name.join("b").exists());
+        let texts = (
+            fs::read_to_string(root.join(".hoard/manifest.json")).unwrap(),
+            fs::read_to_string(root.join("sub/.hoard.yaml")).unwrap(),
+        );
+        fs::remove_dir_all(&root).unwrap();
+        assert!(result.is_err());
+        assert_eq!(links, (true, false));
+        assert_eq!(texts, (main.to_string(), fragment.to_string()));
+    }
+
+    #[test]
+    fn repository_rename_taken() {
+        let mut arg1 = hoard("rename-taken");
+        let root = arg1.root().to_path_buf();
+        for file in ["a.txt", "b.txt"].iter() {
+            fs::write(root.join(file), file).unwrap();
+        }
+        arg1.add(vec![root.clone()], None).unwrap();
+        let main = r#"{ "a": ["a.txt"], "b": ["b.txt"] }"#;
+        fs::write(root.join(".hoard/manifest.json"), main).unwrap();
+
+        let result = arg1.rename("a", "b");
+
+        let text = fs::read_to_string(root.join(".hoard/manifest.json")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert!(result.is_err());
+        assert_eq!(text, main);
+    }
+
+    /// Creates a hoard holding the object `a`, linked at `a.txt` and
+    /// tagged, with a copy on the remote `r`.
+    fn hoard_with_remote(test: &str) -> (Repository, PathBuf) {
+        let mut repo = hoard(test);
+        let root = repo.root().to_path_buf();
+        fs::write(root.join("a.txt"), "alpha").unwrap();
+        repo.add(vec![root.join("a.txt")], None).unwrap();
+        let index = Index::from(&root).unwrap();
+        let hash = repo.object(&index, "a").unwrap().hash().clone();
+        repo.metadata()
+            .update(&hash, |metadata| {
+                metadata.tags.insert("unread".to_string());
+                Ok(())
+            })
+            .unwrap();
+
+        let remote = hoard(&format!("{}-remote", test)).root().to_path_buf();
+        repo.add_remote("r", &remote, false).unwrap();
+        repo.push("r").unwrap();
+        (repo, remote)
+    }
+
+    #[test]
+    fn repository_drop_contents() {
+        let (arg1, remote) = hoard_with_remote("drop-contents");
+        let root = arg1.root().to_path_buf();
+
+        let result = arg1.drop_contents(&["a"]).unwrap();
+
+        let index = Index::from(&root).unwrap();
+        let object = arg1.object(&index, "a").unwrap();
+        let metadata = arg1.metadata().get(object.hash()).unwrap();
+        let link = root.join("a.txt").symlink_metadata().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&remote).unwrap();
+        assert_eq!(result, 5);
+        assert!(!object.is_present());
+        assert!(link.file_type().is_symlink());
+        assert!(metadata.tags.contains("unread"));
+        assert_eq!(metadata.contents["size"], "5");
+    }
+
+    #[test]
+    fn repository_get_contents() {
+        let (arg1, remote) = hoard_with_remote("get-contents");
+        let root = arg1.root().to_path_buf();
+        arg1.drop_contents(&["a"]).unwrap();
+
+        let result = arg1.get_contents(&["a"], None).unwrap();
+
+        let index = Index::from(&root).unwrap();
+        let object = arg1.object(&index, "a").unwrap();
+        let link = root.join("a.txt").symlink_metadata().unwrap();
+        let text = fs::read_to_string(root.join("a.txt")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&remote).unwrap();
+        assert_eq!(result, 5);
+        assert!(object.is_present());
+        assert!(link.file_type().is_file());
+        assert_eq!(&link.ino(), object.ino());
+        assert_eq!(text, "alpha");
+    }
+
+    #[test]
+    fn repository_get_contents_missing() {
+        let (mut arg1, remote) = hoard_with_remote("get-contents-missing");
+        let root = arg1.root().to_path_buf();
+        let empty = hoard("get-contents-missing-empty").root().to_path_buf();
+        arg1.add_remote("empty", &empty, false).unwrap();
+        arg1.drop_contents(&["a"]).unwrap();
+        let before = root.join("a.txt").read_link().unwrap();
+
+        let result = arg1.get_contents(&["a"], Some("empty"));
+
+        let after = root.join("a.txt").read_link().unwrap();
+        let index = Index::from(&root).unwrap();
+        let object = arg1.object(&index, "a").unwrap();
+        for dir in [&root, &remote, &empty].iter() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        assert!(result.is_err());
+        assert_eq!(before, after);
+        assert!(!object.is_present());
+    }
+
+    #[test]
+    fn repository_sniffed_kept() {
+        let (arg1, remote) = hoard_with_remote("sniffed-kept");
+        let root = arg1.root().to_path_buf();
+        let index = Index::from(&root).unwrap();
+        let hash = arg1.object(&index, "a").unwrap().hash().clone();
+        let sniffed = |metadata: Metadata| (metadata.mime, metadata.kind);
+        let before = sniffed(arg1.metadata().get(&hash).unwrap());
+
+        arg1.drop_contents(&["a"]).unwrap();
+        let dropped = sniffed(arg1.metadata().get(&hash).unwrap());
+        arg1.get_contents(&["a"], None).unwrap();
+        let result = sniffed(arg1.metadata().get(&hash).unwrap());
+
+        let pushed = sniffed(MetadataStore::new(&remote).get(&hash).unwrap());
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&remote).unwrap();
+        assert!(before.0.is_some() && before.1.is_some());
+        assert_eq!(dropped, before);
+        assert_eq!(result, before);
+        assert_eq!(pushed, before);
     }
 }
@@ -7,28 +7,71 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
+extern crate argon2;
+extern crate chacha20poly1305;
+extern crate fastcdc;
 extern crate hex;
+extern crate hmac;
+extern crate inotify;
+extern crate jpeg_decoder;
 extern crate multi_map;
 extern crate open;
 extern crate pathdiff;
+extern crate png;
 extern crate regex;
+extern crate rpassword;
 extern crate serde;
 extern crate serde_json;
+extern crate serde_yaml;
 extern crate sha2;
+extern crate signal_hook;
+extern crate tar;
+extern crate toml;
 extern crate walkdir;
+extern crate zip;
+extern crate zstd;
 
 use std::env;
 use std::fs;
+use std::path::Path;
 use std::process;
+use std::time::Duration;
 
 use clap::ArgMatches;
 
+use diff::Output;
 use hoard::Repository;
+use manifest::{Format, Manifest};
+use store::Layout;
 
 mod app;
+mod chunks;
+mod cold;
+mod config;
+mod copies;
+mod crypt;
+mod diff;
+mod dupes;
+mod edit;
 mod error;
+mod export;
+mod extract;
+mod history;
 mod hoard;
+mod manifest;
+mod merge;
+mod metadata;
+mod naming;
+mod query;
+mod remote;
+mod similar;
+mod sniff;
 mod state;
+mod store;
+mod template;
+mod tree;
+mod validate;
+mod watch;
 
 pub type Result<T> = ::std::result::Result<T, failure::Error>;
 
@@ -43,15 +86,51 @@ fn try_main() -> Result<()> {
     match app::app().get_matches().subcommand() {
         ("init", Some(matches)) => init(matches),
         ("add", Some(matches)) => add(matches),
+        ("import", Some(matches)) => import(matches),
+        ("dupes", Some(matches)) => dupes(matches),
+        ("dedupe", Some(matches)) => dedupe(matches),
+        ("similar", Some(matches)) => similar(matches),
+        ("export", Some(matches)) => export(matches),
+        ("remote", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => remote_add(matches),
+            ("ls", Some(matches)) => remote_ls(matches),
+            (command, _) => bail!("'remote {}' not implemented", command),
+        },
+        ("fetch", Some(matches)) => sync(matches, "fetched", Repository::fetch),
+        ("pull", Some(matches)) => sync(matches, "pulled", Repository::pull),
+        ("push", Some(matches)) => sync(matches, "pushed", Repository::push),
+        ("get", Some(matches)) => get_contents(matches),
+        ("drop", Some(matches)) => drop_contents(matches),
+        ("whereis", Some(matches)) => whereis(matches),
+        ("fsck", Some(matches)) => fsck(matches),
+        ("watch", Some(matches)) => watch(matches),
+        ("history", Some(matches)) => history(matches),
         ("edit", Some(matches)) => edit(matches),
         ("apply", Some(matches)) => apply(matches),
+        ("snapshot", Some(matches)) => snapshot(matches),
+        ("diff", Some(matches)) => diff(matches),
+        ("merge", Some(matches)) => merge(matches),
+        ("mv", Some(matches)) => mv(matches),
+        ("rm", Some(matches)) => rm(matches),
+        ("info", Some(matches)) => info(matches),
+        ("query", Some(matches)) => query(matches),
+        ("tag", Some(matches)) => tag(matches),
+        ("meta", Some(matches)) => meta(matches),
+        ("manifest", Some(matches)) => match matches.subcommand() {
+            ("validate", Some(matches)) => validate(matches),
+            (command, _) => bail!("'manifest {}' not implemented", command),
+        },
         (command, _) => bail!("'{}' not implemented", command),
     }
 }
 
 fn init(matches: &ArgMatches) -> Result<()> {
     let path = matches.value_of("NAME").unwrap_or(".");
-    Repository::init(&path)?;
+    let layout = match matches.value_of("LAYOUT") {
+        Some(name) => Layout::from_name(name)?,
+        None => Layout::default(),
+    };
+    Repository::init(&path, layout)?;
     println!(
         "Initialized new hoard repository in {}",
         fs::canonicalize(&path)?.display()
@@ -63,12 +142,359 @@ fn add(matches: &ArgMatches) -> Result<()> {
     let current_dir = env::current_dir()?;
     let mut repo = Repository::load(current_dir)?;
     if let Some(paths) = matches.values_of("PATH") {
-        repo.add(paths.collect())?;
+        repo.add(paths.collect(), matches.value_of("NAME"))?;
     }
     Ok(())
 }
 
-fn edit(_matches: &ArgMatches) -> Result<()> {
+fn import(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let mut repo = Repository::load(&current_dir)?;
+    let src = Path::new(matches.value_of("SRC").unwrap());
+    let dest = match matches.value_of("DEST") {
+        Some(dest) => current_dir.join(dest),
+        None => match src.canonicalize()?.file_name() {
+            Some(name) => current_dir.join(name),
+            None => bail!("cannot name a directory after '{}'", src.display()),
+        },
+    };
+    let report = repo.import(src, dest, matches.is_present("MOVE"))?;
+    println!(
+        "imported {} files ({}) as {} objects ({}), saving {}",
+        report.files,
+        hoard::human_size(report.bytes),
+        report.objects,
+        hoard::human_size(report.stored),
+        hoard::human_size(report.bytes - report.stored)
+    );
+    Ok(())
+}
+
+fn dupes(_: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let groups = repo.dupes()?;
+    let mut wasted = 0;
+    for group in groups.iter() {
+        let size = hoard::human_size(group.size);
+        println!("{} ({} each):", group.hash.as_str(), size);
+        for copy in group.copies.iter() {
+            let path = pathdiff::diff_paths(&copy.path, &current_dir)
+                .unwrap_or_else(|| copy.path.clone());
+            let state = if copy.linked { "linked" } else { "copy" };
+            println!("  {:<6} {}", state, path.display());
+        }
+        wasted += group.size * (group.files() as u64 - 1);
+    }
+    println!(
+        "{} groups of duplicates, up to {} can be reclaimed",
+        groups.len(),
+        hoard::human_size(wasted)
+    );
+    Ok(())
+}
+
+fn dedupe(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let mut repo = Repository::load(&current_dir)?;
+    let dry_run = matches.is_present("DRY_RUN");
+    let reclaimed = repo.dedupe(dry_run)?;
+    if dry_run {
+        println!("would reclaim {}", hoard::human_size(reclaimed));
+    } else {
+        println!("reclaimed {}", hoard::human_size(reclaimed));
+    }
+    Ok(())
+}
+
+fn similar(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let threshold = match matches.value_of("THRESHOLD") {
+        Some(threshold) => threshold.parse()?,
+        None => similar::THRESHOLD,
+    };
+    match matches.value_of("OBJECT") {
+        Some(object) => {
+            for (name, distance) in repo.similar_to(object, threshold)? {
+                println!("{:>3} {}", distance, name);
+            }
+        }
+        None => {
+            for cluster in repo.similar(threshold)? {
+                println!("{}", cluster.join("\n  "));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn export(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let to = current_dir.join(matches.value_of("TO").unwrap());
+    let report = repo.export(matches.value_of("VIEW"), &to)?;
+    println!(
+        "exported {} files ({}) to {}",
+        report.files,
+        hoard::human_size(report.bytes),
+        to.display()
+    );
+    Ok(())
+}
+
+fn remote_add(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let mut repo = Repository::load(&current_dir)?;
+    let name = matches.value_of("NAME").unwrap();
+    let path = matches.value_of("PATH").unwrap();
+    repo.add_remote(name, Path::new(path), matches.is_present("ENCRYPTED"))
+}
+
+fn remote_ls(_: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    for (name, remote) in repo.remotes() {
+        if crypt::is_encrypted(&remote.path) {
+            println!("{}\t{} (encrypted)", name, remote.path.display());
+        } else {
+            println!("{}\t{}", name, remote.path.display());
+        }
+    }
+    Ok(())
+}
+
+fn sync<F>(matches: &ArgMatches, verb: &str, func: F) -> Result<()>
+where
+    F: Fn(&Repository, &str) -> Result<remote::Sync>,
+{
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let report = func(&repo, matches.value_of("REMOTE").unwrap())?;
+    for name in report.names.iter() {
+        println!("add: {}", name);
+    }
+    println!(
+        "{} {} objects ({})",
+        verb,
+        report.objects,
+        hoard::human_size(report.bytes)
+    );
+    if !report.conflicts.is_empty() {
+        for conflict in report.conflicts.iter() {
+            eprintln!("conflict: {}", conflict);
+        }
+        bail!("{} conflict(s) left unmerged", report.conflicts.len());
+    }
+    Ok(())
+}
+
+fn get_contents(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let terms: Vec<_> = matches.values_of("OBJECT").unwrap().collect();
+    let bytes = repo.get_contents(&terms, matches.value_of("FROM"))?;
+    println!("copied {}", hoard::human_size(bytes));
+    Ok(())
+}
+
+fn drop_contents(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let terms: Vec<_> = matches.values_of("OBJECT").unwrap().collect();
+    let bytes = repo.drop_contents(&terms)?;
+    println!("freed {}", hoard::human_size(bytes));
+    Ok(())
+}
+
+fn whereis(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let (places, required) = repo.whereis(matches.value_of("OBJECT").unwrap())?;
+    for place in places.iter() {
+        let state = if place.present { "present" } else { "absent" };
+        match place.since {
+            Some(since) => {
+                let (year, month, day) = hoard::civil_date(since as i64);
+                println!(
+                    "{}: {} as of {:04}-{:02}-{:02}",
+                    place.name, state, year, month, day
+                );
+            }
+            None => println!("{}: {}", place.name, state),
+        }
+    }
+    let present = places.iter().filter(|place| place.present).count();
+    println!("{} of {} required copies", present, required);
+    Ok(())
+}
+
+fn fsck(_: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let (checked, damaged) = repo.fsck()?;
+    for name in damaged.iter() {
+        println!("damaged: {}", name);
+    }
+    if !damaged.is_empty() {
+        bail!("{} object(s) are damaged", damaged.len());
+    }
+    println!("{} object(s) checked", checked);
+    Ok(())
+}
+
+fn watch(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let mut repo = Repository::load(current_dir)?;
+    let quiet = match matches.value_of("QUIET") {
+        Some(quiet) => quiet.parse()?,
+        None => watch::QUIET,
+    };
+    watch::watch(&mut repo, Duration::from_secs(quiet))?;
+    println!("stopped watching");
+    Ok(())
+}
+
+fn history(_: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    for entry in history::list(repo.root())? {
+        let (year, month, day) = hoard::civil_date(entry.time as i64);
+        println!(
+            "@{} {:04}-{:02}-{:02} {}",
+            entry.id, year, month, day, entry.message
+        );
+    }
+    Ok(())
+}
+
+fn edit(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let path = repo.manifest_path();
+    if !path.exists() {
+        repo.snapshot()?.to_file(&path)?;
+    }
+
+    let format = match matches.value_of("FORMAT") {
+        Some(name) => Format::from_name(name)?,
+        None => Format::from_path(&path)?,
+    };
+    if format == Format::from_path(&path)? {
+        open_editor(&path)?;
+        return repo.apply();
+    }
+
+    // Edit a converted copy, which is only written back once it parses
+    // so that a mistake doesn't lose the edits.
+    let copy = path.with_extension(format!("edit.{}", format.extension()));
+    Manifest::from_file(&path)?.to_file(&copy)?;
+    open_editor(&copy)?;
+    Manifest::from_file(&copy)?.to_file(&path)?;
+    fs::remove_file(&copy)?;
+    repo.apply()
+}
+
+/// Opens the file in the user's editor and waits for it to be closed.
+fn open_editor(path: &Path) -> Result<()> {
+    let editor = env::var_os("VISUAL").or_else(|| env::var_os("EDITOR"));
+    let status = match editor {
+        Some(editor) => process::Command::new(editor).arg(path).status()?,
+        None => open::that(path)?,
+    };
+    if !status.success() {
+        bail!("editor exited with {}", status);
+    }
+    Ok(())
+}
+
+fn snapshot(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let path = match matches.value_of("FILE") {
+        Some(file) => current_dir.join(file),
+        None => repo.manifest_path(),
+    };
+
+    let manifest = if matches.is_present("MERGE") {
+        let (manifest, drifts) = repo.merge_snapshot(&path)?;
+        for drift in drifts.iter() {
+            for gained in drift.gained.iter() {
+                println!("gained: {} at {}", drift.name, gained.display());
+            }
+            for lost in drift.lost.iter() {
+                println!("lost: {} at {}", drift.name, lost.display());
+            }
+        }
+        println!("{} object(s) changed", drifts.len());
+        manifest
+    } else {
+        repo.snapshot()?
+    };
+    manifest.to_file(&path)?;
+    if path == repo.manifest_path() {
+        history::record(repo.root(), &manifest, "snapshot of the working tree")?;
+    }
+    Ok(())
+}
+
+fn diff(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let output = match matches.value_of("OUTPUT") {
+        Some(name) => Output::from_name(name)?,
+        None => Output::Human,
+    };
+    let a = matches.value_of("A").unwrap();
+    let b = matches.value_of("B").unwrap();
+    print!("{}", repo.diff(a, b)?.render(output, a, b)?);
+    Ok(())
+}
+
+fn merge(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(&current_dir)?;
+    let ours = matches.value_of("OURS").unwrap();
+    let theirs = matches.value_of("THEIRS").unwrap();
+    let path = match matches.value_of("TO") {
+        Some(to) => current_dir.join(to),
+        None if ours.starts_with('@') || Path::new(ours).is_dir() => {
+            bail!("{} is not a file, use --to to write the merge", ours)
+        }
+        None => current_dir.join(ours),
+    };
+
+    let (manifest, conflicts) = merge::merge(
+        &repo.manifest_of(matches.value_of("BASE").unwrap())?,
+        &repo.manifest_of(ours)?,
+        &repo.manifest_of(theirs)?,
+    );
+    let report = matches.is_present("REPORT");
+    let format = Format::from_path(&path)?;
+    if report {
+        println!("{}", serde_json::to_string_pretty(&conflicts)?);
+    }
+    if let Some(text) = merge::to_string(&manifest, &conflicts, format, report, ours, theirs)? {
+        fs::write(&path, text)?;
+    }
+
+    if conflicts.is_empty() && path == repo.manifest_path() {
+        let message = format!("merge of {} and {}", ours, theirs);
+        history::record(repo.root(), &manifest, &message)?;
+    }
+    if !conflicts.is_empty() {
+        for conflict in conflicts.iter() {
+            eprintln!("conflict: {}", conflict.path.display());
+        }
+        if report {
+            bail!("{} conflict(s), nothing written", conflicts.len());
+        }
+        bail!(
+            "{} conflict(s), marked in {}",
+            conflicts.len(),
+            path.display()
+        );
+    }
     Ok(())
 }
 
@@ -77,3 +503,121 @@ fn apply(_: &ArgMatches) -> Result<()> {
     let repo = Repository::load(current_dir)?;
     repo.apply()
 }
+
+fn mv(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let name = matches.value_of("NAME").unwrap();
+    let new_name = matches.value_of("NEW_NAME").unwrap();
+    repo.rename(name, new_name)
+}
+
+fn rm(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let names: Vec<_> = matches.values_of("NAME").unwrap().collect();
+    let bytes = repo.remove(&names, matches.is_present("PURGE"))?;
+    if bytes > 0 {
+        println!("freed {}", hoard::human_size(bytes));
+    }
+    Ok(())
+}
+
+fn query(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let terms: Vec<_> = matches
+        .values_of("QUERY")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    for name in repo.query(&terms.join(" "))? {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+fn tag(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let tags: Vec<_> = matches.values_of("TAG").unwrap().collect();
+            repo.update_metadata(matches.value_of("OBJECT").unwrap(), |metadata| {
+                metadata.tags.extend(tags.iter().map(|tag| tag.to_string()));
+                Ok(())
+            })?;
+        }
+        ("rm", Some(matches)) => {
+            let tags: Vec<_> = matches.values_of("TAG").unwrap().collect();
+            repo.update_metadata(matches.value_of("OBJECT").unwrap(), |metadata| {
+                metadata.tags.retain(|tag| !tags.contains(&tag.as_str()));
+                Ok(())
+            })?;
+        }
+        ("ls", Some(matches)) => match matches.value_of("OBJECT") {
+            Some(object) => {
+                for tag in repo.metadata_of(object)?.tags {
+                    println!("{}", tag);
+                }
+            }
+            None => {
+                for (tag, count) in repo.tags()? {
+                    println!("{}\t{}", tag, count);
+                }
+            }
+        },
+        (command, _) => bail!("'tag {}' not implemented", command),
+    }
+    Ok(())
+}
+
+fn meta(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    match matches.subcommand() {
+        ("set", Some(matches)) => repo.set_field(
+            matches.value_of("OBJECT").unwrap(),
+            matches.value_of("KEY").unwrap(),
+            matches.value_of("VALUE"),
+        )?,
+        ("get", Some(matches)) => {
+            let metadata = repo.metadata_of(matches.value_of("OBJECT").unwrap())?;
+            match matches.value_of("KEY") {
+                Some(key) => match metadata.fields.get(key) {
+                    Some(value) => println!("{}", value),
+                    None => bail!("No field '{}'", key),
+                },
+                None => {
+                    for (key, value) in metadata.fields.iter() {
+                        println!("{}: {}", key, value);
+                    }
+                }
+            }
+        }
+        (command, _) => bail!("'meta {}' not implemented", command),
+    }
+    Ok(())
+}
+
+fn info(matches: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let attributes = repo.info(matches.value_of("OBJECT").unwrap())?;
+    for (key, values) in attributes.iter() {
+        println!("{}: {}", key, values.join(", "));
+    }
+    Ok(())
+}
+
+fn validate(_: &ArgMatches) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let repo = Repository::load(current_dir)?;
+    let diagnostics = repo.validate()?;
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    if !diagnostics.is_empty() {
+        bail!("manifest has {} problem(s)", diagnostics.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,510 @@
+//! The manifest file describing the desired layout of a hoard.
+//!
+//! A manifest maps the name of every object to the set of paths it
+//! should be linked to. Alongside these static entries, a manifest
+//! can contain `rules` which are expanded when the manifest is applied:
+//!
+//! ```json
+//! {
+//!   "item-name-1": [
+//!     "path1/item-name-1"
+//!   ],
+//!   "rules": [
+//!     { "dir": "unread/", "query": "tag:unread" },
+//!     { "dir": "by-year/{year}/", "query": "" },
+//!     { "path": "library/{author}/{series|Standalone}/{title}.epub", "query": "ebook" }
+//!   ]
+//! }
+//! ```
+//!
+//! A manifest can also `include` other manifests, given relative to its
+//! own directory, whose paths are relative to the same root. Fragments
+//! named `.hoard.json` (or any other supported extension) anywhere in
+//! the working tree describe the layout of their own directory, with
+//! paths relative to it.
+//!
+//! The schema is versioned by an optional top-level `version`, which
+//! manifests written by hoard always include.
+//!
+//! As a consequence, `version`, `rules` and `include` cannot be used as
+//! the names of objects.
+//!
+//! The same structure can also be written as TOML or YAML, or as the
+//! directory tree described in the `tree` module, which is selected by
+//! the extension of the manifest file.
+//!
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use walkdir::{DirEntry, WalkDir};
+
+use serde::de::{Deserialize, Deserializer, Error, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json;
+use serde_yaml;
+use toml;
+
+use error::{LocatedError, ResultExt};
+use query::{Attributes, Query};
+use template::Template;
+use tree;
+use Result;
+
+/// The file formats a manifest can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Tree,
+}
+
+impl Format {
+    pub fn all() -> &'static [Format] {
+        &[Format::Json, Format::Toml, Format::Yaml, Format::Tree]
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "tree" => Ok(Format::Tree),
+            _ => bail!("unknown manifest format '{}'", name),
+        }
+    }
+
+    /// Determines the format from the extension of the path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        Format::from_name(&extension).with_path(path).map_err(Into::into)
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Tree => "tree",
+        }
+    }
+}
+
+/// The newest manifest schema version understood by this build.
+pub const VERSION: u32 = 1;
+
+/// A rule that links every object matching `query` into `dir`, or to
+/// the path rendered from the `path` template.
+///
+/// Both are templates as described in the `template` module, which are
+/// rendered with the attributes of the object being linked. Objects
+/// missing a required attribute are skipped.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub dir: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    #[serde(default)]
+    pub query: String,
+}
+
+impl Rule {
+    /// Parses the `dir` or `path` template of the rule.
+    pub fn template(&self) -> Result<Template> {
+        if !self.dir.is_empty() && !self.path.is_empty() {
+            bail!("a rule cannot have both 'dir' and 'path'");
+        }
+        Template::parse(self.target())
+    }
+
+    /// The directory or path template the rule links objects to.
+    pub fn target(&self) -> &str {
+        if self.path.is_empty() {
+            &self.dir
+        } else {
+            &self.path
+        }
+    }
+
+    /// Returns the path the object should be linked to, if any.
+    pub fn path_for(
+        &self,
+        query: &Query,
+        template: &Template,
+        name: &str,
+        attributes: &Attributes,
+    ) -> Option<PathBuf> {
+        if !query.matches(attributes) {
+            return None;
+        }
+        let path = PathBuf::from(template.render(attributes)?);
+        if self.path.is_empty() {
+            Some(path.join(name))
+        } else {
+            Some(path)
+        }
+    }
+}
+
+/// A single file read while loading a manifest.
+pub struct Source {
+    pub path: PathBuf,
+    /// The directory the paths in the file are relative to.
+    pub prefix: PathBuf,
+    pub manifest: Result<Manifest>,
+}
+
+impl Source {
+    fn collect(path: &Path, prefix: &Path, stack: &mut Vec<PathBuf>, sources: &mut Vec<Source>) {
+        let manifest = match path.canonicalize() {
+            Ok(ref canonical) if stack.contains(canonical) => {
+                Err(format_err!("{}: manifest includes itself", path.display()))
+            }
+            Ok(canonical) => {
+                let manifest = Manifest::from_file(path);
+                if let Ok(ref manifest) = manifest {
+                    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                    stack.push(canonical);
+                    for include in manifest.include.iter() {
+                        Source::collect(&dir.join(include), prefix, stack, sources);
+                    }
+                    stack.pop();
+                }
+                manifest
+            }
+            Err(e) => Err(format_err!("{}: {}", path.display(), e)),
+        };
+
+        sources.push(Source {
+            path: path.to_path_buf(),
+            prefix: prefix.to_path_buf(),
+            manifest,
+        });
+    }
+}
+
+/// The parsed contents of a manifest file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// The schema version, which is absent in manifests written before
+    /// versioning was introduced.
+    pub version: Option<u32>,
+    pub objects: BTreeMap<String, BTreeSet<PathBuf>>,
+    pub rules: Vec<Rule>,
+    pub include: Vec<PathBuf>,
+    /// The files each path was read from, when loaded from several files.
+    pub origins: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+}
+
+impl Manifest {
+    /// Reads the manifest at the given path, along with the manifests it
+    /// includes and every fragment in the working tree of the hoard.
+    pub fn load<P, R>(path: P, root: R) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        R: AsRef<Path>,
+    {
+        let mut manifest = Manifest::default();
+        for source in Manifest::sources(path, root)? {
+            manifest.merge(source.manifest?, &source.prefix, &source.path);
+        }
+        Ok(manifest)
+    }
+
+    /// Lists every file that makes up the manifest at the given path, in
+    /// the order they are merged. Each file that could not be read is
+    /// included with its error, and its own includes are skipped.
+    pub fn sources<P, R>(path: P, root: R) -> Result<Vec<Source>>
+    where
+        P: AsRef<Path>,
+        R: AsRef<Path>,
+    {
+        fn is_index(entry: &DirEntry) -> bool {
+            entry.file_name() == ".hoard"
+        }
+
+        fn is_fragment(entry: &DirEntry) -> bool {
+            let name = entry.file_name().to_string_lossy();
+            entry.file_type().is_file()
+                && Format::all()
+                    .iter()
+                    .any(|format| name == format!(".hoard.{}", format.extension()))
+        }
+
+        let root = root.as_ref();
+        let mut sources = Vec::new();
+        let mut stack = Vec::new();
+        Source::collect(path.as_ref(), Path::new(""), &mut stack, &mut sources);
+
+        for entry in WalkDir::new(root)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(|e| !is_index(e))
+        {
+            let entry = entry?;
+            if !is_fragment(&entry) {
+                continue;
+            }
+            let dir = entry
+                .path()
+                .parent()
+                .and_then(|dir| dir.strip_prefix(root).ok())
+                .unwrap_or_else(|| Path::new(""));
+            Source::collect(entry.path(), dir, &mut stack, &mut sources);
+        }
+
+        Ok(sources)
+    }
+
+    /// Merges another manifest read from the given file, with its paths
+    /// placed under the prefix.
+    fn merge(&mut self, other: Manifest, prefix: &Path, file: &Path) {
+        for (name, paths) in other.objects.into_iter() {
+            let paths: BTreeSet<_> = paths.iter().map(|p| prefix.join(p)).collect();
+            for p in paths.iter() {
+                self.origins
+                    .entry(p.clone())
+                    .or_default()
+                    .insert(file.to_path_buf());
+            }
+            self.objects.entry(name).or_default().extend(paths);
+        }
+        for rule in other.rules.into_iter() {
+            let join = |target: &str| prefix.join(target).to_string_lossy().to_string();
+            let (dir, path) = if rule.path.is_empty() {
+                (join(&rule.dir), rule.path)
+            } else {
+                (rule.dir, join(&rule.path))
+            };
+            self.rules.push(Rule {
+                dir,
+                path,
+                query: rule.query,
+            });
+        }
+    }
+
+    /// Reads a manifest from the given path, in the format given by its
+    /// extension.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let format = Format::from_path(path)?;
+        let text = fs::read_to_string(path).with_path(path)?;
+        Manifest::parse(&text, format).map_err(|(line, column, message)| {
+            LocatedError {
+                path: path.display().to_string(),
+                line,
+                column,
+                message,
+            }
+            .into()
+        })
+    }
+
+    /// Writes the manifest to the given path, in the format given by its
+    /// extension.
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let text = self.to_string(Format::from_path(path)?)?;
+        fs::write(path, text).with_path(path)?;
+        Ok(())
+    }
+
+    /// Parses a manifest, returning the line, column and message of the
+    /// error on failure.
+    pub fn parse(text: &str, format: Format) -> ::std::result::Result<Self, (usize, usize, String)> {
+        fn message<E: fmt::Display>(e: E) -> String {
+            let message = e.to_string();
+            match message.rfind(" at line ") {
+                Some(i) => message[..i].to_string(),
+                None => message,
+            }
+        }
+
+        match format {
+            Format::Json => {
+                serde_json::from_str(text).map_err(|e| (e.line(), e.column(), message(&e)))
+            }
+            Format::Toml => toml::from_str(text).map_err(|e| {
+                let (line, column) = e.line_col().unwrap_or((0, 0));
+                (line + 1, column + 1, message(&e))
+            }),
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| {
+                let (line, column) = e
+                    .location()
+                    .map(|l| (l.line(), l.column()))
+                    .unwrap_or((0, 0));
+                (line, column, message(&e))
+            }),
+            Format::Tree => tree::parse(text),
+        }
+    }
+
+    pub fn to_string(&self, format: Format) -> Result<String> {
+        let mut text = match format {
+            Format::Json => serde_json::to_string_pretty(self)?,
+            Format::Toml => toml::to_string_pretty(self)?,
+            Format::Yaml => serde_yaml::to_string(self)?,
+            Format::Tree => tree::to_string(self),
+        };
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        Ok(text)
+    }
+}
+
+impl Serialize for Manifest {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let length = self.objects.len()
+            + if self.version.is_none() { 0 } else { 1 }
+            + if self.include.is_empty() { 0 } else { 1 }
+            + if self.rules.is_empty() { 0 } else { 1 };
+        let mut map = serializer.serialize_map(Some(length))?;
+        if let Some(version) = self.version {
+            map.serialize_entry("version", &version)?;
+        }
+        if !self.include.is_empty() {
+            map.serialize_entry("include", &self.include)?;
+        }
+        for (name, paths) in self.objects.iter() {
+            map.serialize_entry(name, paths)?;
+        }
+        // TOML requires tables to come after plain values.
+        if !self.rules.is_empty() {
+            map.serialize_entry("rules", &self.rules)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Manifest {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ManifestVisitor;
+
+        impl<'de> Visitor<'de> for ManifestVisitor {
+            type Value = Manifest;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of object names to paths")
+            }
+
+            fn visit_map<M>(self, mut map: M) -> ::std::result::Result<Manifest, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let mut manifest = Manifest::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "version" => {
+                            let version = map.next_value::<u32>()?;
+                            if version > VERSION {
+                                return Err(M::Error::custom(format!(
+                                    "unsupported manifest version {}, expected at most {}",
+                                    version, VERSION
+                                )));
+                            }
+                            manifest.version = Some(version);
+                        }
+                        "rules" => manifest.rules.extend(map.next_value::<Vec<Rule>>()?),
+                        "include" => manifest
+                            .include
+                            .extend(map.next_value::<Vec<PathBuf>>()?),
+                        _ => manifest
+                            .objects
+                            .entry(key)
+                            .or_insert_with(BTreeSet::new)
+                            .extend(map.next_value::<BTreeSet<PathBuf>>()?),
+                    }
+                }
+                Ok(manifest)
+            }
+        }
+
+        deserializer.deserialize_map(ManifestVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(result: Result<Manifest>) -> (usize, usize) {
+        let error = result.unwrap_err();
+        let error = error.downcast_ref::<LocatedError>().unwrap();
+        (error.line, error.column)
+    }
+
+    #[test]
+    fn manifest_from_file_success() {
+        let arg1 = "test/res/manifest_from_file/success/test1";
+
+        let json = Manifest::from_file(format!("{}.json", arg1)).unwrap();
+        let toml = Manifest::from_file(format!("{}.toml", arg1)).unwrap();
+        let yaml = Manifest::from_file(format!("{}.yaml", arg1)).unwrap();
+
+        assert_eq!(json.objects.len(), 2);
+        assert_eq!(json.rules.len(), 1);
+        assert_eq!(json, toml);
+        assert_eq!(json, yaml);
+    }
+
+    #[test]
+    fn manifest_from_file_invalid() {
+        let arg1 = "test/res/manifest_from_file/invalid/test1";
+
+        assert_eq!(location(Manifest::from_file(format!("{}.json", arg1))), (4, 1));
+        assert_eq!(location(Manifest::from_file(format!("{}.toml", arg1))).0, 2);
+        assert_eq!(location(Manifest::from_file(format!("{}.yaml", arg1))).0, 4);
+    }
+
+    #[test]
+    fn manifest_load_success() {
+        let arg1 = "test/res/manifest_load/success/.hoard/manifest.json";
+        let arg2 = "test/res/manifest_load/success";
+
+        let result = Manifest::load(arg1, arg2).unwrap();
+
+        assert_eq!(result.objects.len(), 3);
+        assert_eq!(result.objects["item1"].len(), 2);
+        assert!(result.objects["item3"].contains(Path::new("books/item3")));
+        assert_eq!(result.rules[0].dir, "books/unread/");
+        assert_eq!(result.origins[Path::new("path1/item2")].len(), 1);
+    }
+
+    #[test]
+    fn manifest_load_cycle() {
+        let arg1 = "test/res/manifest_load/cycle/.hoard/manifest.json";
+        let arg2 = "test/res/manifest_load/cycle";
+
+        let result = Manifest::load(arg1, arg2);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn manifest_to_string_roundtrip() {
+        let arg1 = Manifest::from_file("test/res/manifest_from_file/success/test1.json").unwrap();
+
+        for format in Format::all() {
+            let text = arg1.to_string(*format).unwrap();
+            let result = Manifest::parse(&text, *format).unwrap();
+            assert_eq!(result, arg1);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Combines two manifests edited from the same base, for `merge`.
+//!
+//! Objects are merged one at a time. When only one side changed the
+//! paths of an object, its paths are taken from that side, and when both
+//! did, every path is merged on its own: a path added by either side is
+//! kept, and a path removed by either side is dropped. The rules and
+//! includes of the manifest are taken from the side that changed them,
+//! or from ours when both did.
+//!
+//! The merge can still give the same path to several objects, such as
+//! when each side moved a different file to it. Those paths are left out
+//! of the merged manifest and returned as conflicts, which `merge` either
+//! appends to the manifest between markers, leaving it to be fixed by
+//! hand before it can be read again:
+//!
+//! ```text
+//! <<<<<<< ours.json
+//! comics/cover.jpg: cover
+//! =======
+//! comics/cover.jpg: front
+//! >>>>>>> theirs.json
+//! ```
+//!
+//! or reports as JSON, in which case nothing is written until the
+//! conflicts are resolved.
+//!
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use manifest::{Format, Manifest};
+use Result;
+
+/// A path given to several objects by the merge, with the objects each
+/// side gives it to.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub ours: Vec<String>,
+    pub theirs: Vec<String>,
+}
+
+/// Merges the objects of a manifest, along with its rules and includes.
+pub fn merge(base: &Manifest, ours: &Manifest, theirs: &Manifest) -> (Manifest, Vec<Conflict>) {
+    let mut merged = ours.clone();
+    merged.origins.clear();
+    if ours.rules == base.rules {
+        merged.rules = theirs.rules.clone();
+    }
+    if ours.include == base.include {
+        merged.include = theirs.include.clone();
+    }
+    merged.version = ours.version.max(theirs.version);
+
+    let names: BTreeSet<&String> = base
+        .objects
+        .keys()
+        .chain(ours.objects.keys())
+        .chain(theirs.objects.keys())
+        .collect();
+    merged.objects.clear();
+    for name in names {
+        let paths = merge_paths(
+            base.objects.get(name),
+            ours.objects.get(name),
+            theirs.objects.get(name),
+        );
+        if let Some(paths) = paths {
+            merged.objects.insert(name.clone(), paths);
+        }
+    }
+
+    let mut claims: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for (name, paths) in merged.objects.iter() {
+        for path in paths.iter() {
+            claims.entry(path.clone()).or_default().push(name.clone());
+        }
+    }
+    let mut conflicts = Vec::new();
+    for (path, names) in claims.into_iter().filter(|(_, names)| names.len() > 1) {
+        let claimed = |side: &Manifest| -> Vec<String> {
+            names
+                .iter()
+                .filter(|name| side.objects.get(*name).is_some_and(|p| p.contains(&path)))
+                .cloned()
+                .collect()
+        };
+        let conflict = Conflict {
+            ours: claimed(ours),
+            theirs: claimed(theirs),
+            path: path.clone(),
+        };
+        for name in names.iter() {
+            merged.objects.get_mut(name).unwrap().remove(&path);
+        }
+        conflicts.push(conflict);
+    }
+    (merged, conflicts)
+}
+
+/// Merges the paths of an object, which is left out when a side removed
+/// it and no paths remain.
+fn merge_paths(
+    base: Option<&BTreeSet<PathBuf>>,
+    ours: Option<&BTreeSet<PathBuf>>,
+    theirs: Option<&BTreeSet<PathBuf>>,
+) -> Option<BTreeSet<PathBuf>> {
+    if ours == theirs || theirs == base {
+        return ours.cloned();
+    }
+    if ours == base {
+        return theirs.cloned();
+    }
+
+    let empty = BTreeSet::new();
+    let (base, ours_set, theirs_set) = (
+        base.unwrap_or(&empty),
+        ours.unwrap_or(&empty),
+        theirs.unwrap_or(&empty),
+    );
+    let paths: BTreeSet<PathBuf> = ours_set
+        .union(theirs_set)
+        .filter(|path| {
+            (ours_set.contains(*path) && theirs_set.contains(*path)) || !base.contains(*path)
+        })
+        .cloned()
+        .collect();
+    if paths.is_empty() && (ours.is_none() || theirs.is_none()) {
+        return None;
+    }
+    Some(paths)
+}
+
+/// Writes the conflicts between markers labelled with both sides.
+pub fn markers(conflicts: &[Conflict], ours: &str, theirs: &str) -> String {
+    let mut text = String::new();
+    for conflict in conflicts.iter() {
+        let path = conflict.path.display();
+        writeln!(text, "<<<<<<< {}", ours).unwrap();
+        for name in conflict.ours.iter() {
+            writeln!(text, "{}: {}", path, name).unwrap();
+        }
+        writeln!(text, "=======").unwrap();
+        for name in conflict.theirs.iter() {
+            writeln!(text, "{}: {}", path, name).unwrap();
+        }
+        writeln!(text, ">>>>>>> {}", theirs).unwrap();
+    }
+    text
+}
+
+/// Returns the text of the merged manifest, followed by its conflicts
+/// between markers. When conflicts are only reported there is nothing
+/// to write, as the manifest lacks the paths in conflict, and `apply`
+/// would unlink them.
+pub fn to_string(
+    manifest: &Manifest,
+    conflicts: &[Conflict],
+    format: Format,
+    report: bool,
+    ours: &str,
+    theirs: &str,
+) -> Result<Option<String>> {
+    if report && !conflicts.is_empty() {
+        return Ok(None);
+    }
+    let mut text = manifest.to_string(format)?;
+    text.push_str(&markers(conflicts, ours, theirs));
+    Ok(Some(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Manifest {
+        Manifest::parse(text, Format::Json).unwrap()
+    }
+
+    #[test]
+    fn merge_objects() {
+        let arg1 = parse(
+            r#"{"a": ["x/a", "y/a"], "b": ["x/b"], "c": ["x/c"], "d": ["x/d"], "e": ["x/e"]}"#,
+        );
+        let arg2 = parse(r#"{"a": ["x/a", "z/a"], "b": ["y/b"], "c": ["x/c"], "e": ["x/e"]}"#);
+        let arg3 = parse(
+            r#"{"a": ["y/a", "w/a"], "b": ["x/b"], "c": ["y/c"], "d": ["x/d"], "f": ["x/f"]}"#,
+        );
+        let (result, conflicts) = merge(&arg1, &arg2, &arg3);
+
+        let expected = parse(r#"{"a": ["w/a", "z/a"], "b": ["y/b"], "c": ["y/c"], "f": ["x/f"]}"#);
+        assert_eq!(result.objects, expected.objects);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        let arg1 = parse(r#"{"a": ["x/a"], "b": ["x/b"]}"#);
+        let arg2 = parse(r#"{"a": ["cover.jpg"], "b": ["x/b"]}"#);
+        let arg3 = parse(r#"{"a": ["x/a"], "b": ["cover.jpg"]}"#);
+        let (result, conflicts) = merge(&arg1, &arg2, &arg3);
+
+        let expected = vec![Conflict {
+            path: PathBuf::from("cover.jpg"),
+            ours: vec!["a".to_string()],
+            theirs: vec!["b".to_string()],
+        }];
+        assert_eq!(conflicts, expected);
+        assert!(result.objects.values().all(BTreeSet::is_empty));
+
+        let result = markers(&conflicts, "ours.json", "theirs.json");
+        let expected =
+            "<<<<<<< ours.json\ncover.jpg: a\n=======\ncover.jpg: b\n>>>>>>> theirs.json\n";
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn merge_to_string_report() {
+        let arg1 = parse(r#"{"f": ["d/f.txt"], "g": ["g.txt"]}"#);
+        let arg2 = parse(r#"{"f": ["cover.txt"], "g": ["g.txt"]}"#);
+        let arg3 = parse(r#"{"f": ["d/f.txt"], "g": ["cover.txt"]}"#);
+        let (manifest, conflicts) = merge(&arg1, &arg2, &arg3);
+
+        let result = to_string(&manifest, &conflicts, Format::Json, true, "o", "t").unwrap();
+        assert_eq!(result, None);
+
+        let result = to_string(&manifest, &conflicts, Format::Json, false, "o", "t").unwrap();
+        assert!(result.unwrap().contains("<<<<<<< o\ncover.txt: f\n"));
+
+        let (manifest, conflicts) = merge(&arg1, &arg2, &arg1);
+        let result = to_string(&manifest, &conflicts, Format::Json, true, "o", "t").unwrap();
+        assert_eq!(
+            parse(&result.unwrap()),
+            parse(r#"{"f": ["cover.txt"], "g": ["g.txt"]}"#)
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Tags and key/value fields attached to objects.
+//!
+//! Metadata is stored in `.hoard/metadata` as one JSON file per object,
+//! laid out by hash in the same way as `.hoard/objects/by-hash`. Since
+//! it is keyed by content rather than by name, it follows an object
+//! through renames and is kept when the object is removed, so it comes
+//! back if the same content is added again.
+//!
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use copies;
+use error::ResultExt;
+use hoard::FileHash;
+use query::Attributes;
+use sniff::Kind;
+use Result;
+
+/// Field names that are provided by hoard itself and cannot be set.
+pub const RESERVED: &[&str] = &["name", "hash", "size", "tag", "mime", "kind"];
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+    /// The perceptual fingerprint of the contents, computed on demand by
+    /// `similar`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// The MIME type of the contents, sniffed when they were added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    /// The kind of the contents, sniffed along with their MIME type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<Kind>,
+    /// The attributes of the contents, such as their size and kind, kept
+    /// while the object is absent.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub contents: BTreeMap<String, String>,
+    /// Whether each hoard held the contents when last seen, by the id of
+    /// the hoard.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub locations: BTreeMap<String, Location>,
+}
+
+/// Whether a hoard holds the contents of an object.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub present: bool,
+    /// When this was last found to change, in seconds since the epoch.
+    pub since: u64,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+            && self.fields.is_empty()
+            && self.fingerprint.is_none()
+            && self.mime.is_none()
+            && self.kind.is_none()
+            && self.contents.is_empty()
+            && self.locations.is_empty()
+    }
+
+    /// Records whether the hoard with the given id holds the contents,
+    /// returning whether that changed.
+    pub fn locate(&mut self, id: &str, present: bool) -> bool {
+        if self.locations.get(id).map(|l| l.present) == Some(present) {
+            return false;
+        }
+        let since = copies::now();
+        self.locations
+            .insert(id.to_string(), Location { present, since });
+        true
+    }
+
+    /// Adds the tags as `tag` attributes, and every field and attribute of
+    /// the contents under its key. Fields replace the attributes derived
+    /// from the file, such as the `year` it was last modified.
+    pub fn extend(&self, attributes: &mut Attributes) {
+        for tag in self.tags.iter() {
+            attributes.insert("tag", tag.as_str());
+        }
+        for (key, value) in self.contents.iter().chain(self.fields.iter()) {
+            attributes.set(key.as_str(), value.as_str());
+        }
+    }
+}
+
+/// The on-disk collection of metadata for every object.
+pub struct MetadataStore {
+    path: PathBuf,
+}
+
+impl MetadataStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        MetadataStore {
+            path: root.as_ref().join(".hoard/metadata"),
+        }
+    }
+
+    fn path_of(&self, hash: &FileHash) -> PathBuf {
+        self.path.join(format!("{}.json", hash.as_path()))
+    }
+
+    /// Returns the metadata of the object, which is empty if nothing has
+    /// been recorded for it.
+    pub fn get(&self, hash: &FileHash) -> Result<Metadata> {
+        let path = self.path_of(hash);
+        if !path.exists() {
+            return Ok(Metadata::default());
+        }
+        let file = fs::File::open(&path).with_path(&path)?;
+        let metadata = serde_json::from_reader(file).with_path(&path)?;
+        Ok(metadata)
+    }
+
+    /// Replaces the metadata of the object.
+    pub fn put(&self, hash: &FileHash, metadata: &Metadata) -> Result<()> {
+        let path = self.path_of(hash);
+        if metadata.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path).with_path(&path)?;
+            }
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_path(parent)?;
+        }
+        let text = serde_json::to_string_pretty(metadata)?;
+        fs::write(&path, text).with_path(&path)?;
+        Ok(())
+    }
+
+    /// Applies the function to the metadata of the object and saves the
+    /// result.
+    pub fn update<F>(&self, hash: &FileHash, func: F) -> Result<Metadata>
+    where
+        F: FnOnce(&mut Metadata) -> Result<()>,
+    {
+        let mut metadata = self.get(hash)?;
+        func(&mut metadata)?;
+        self.put(hash, &metadata)?;
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_extend_overrides() {
+        let mut arg1 = Metadata::default();
+        arg1.fields.insert("year".to_string(), "1937".to_string());
+        arg1.contents.insert("year".to_string(), "2026".to_string());
+        arg1.tags.insert("old".to_string());
+        let mut result = Attributes::new();
+        result.insert("year", "2026");
+        result.insert("tag", "new");
+
+        arg1.extend(&mut result);
+
+        assert_eq!(result.get("year"), &["1937".to_string()]);
+        assert_eq!(result.get("tag"), &["new".to_string(), "old".to_string()]);
+    }
+
+    #[test]
+    fn metadata_store_get_success() {
+        let arg1 = MetadataStore::new("test/res/metadata_store_get/success");
+        let arg2 = FileHash::from_str(
+            &"e80aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7".to_string(),
+        )
+        .unwrap();
+
+        let result = arg1.get(&arg2).unwrap();
+
+        let mut attributes = Attributes::new();
+        result.extend(&mut attributes);
+        assert_eq!(attributes.get("tag"), ["unread".to_string()]);
+        assert_eq!(attributes.first("author"), Some("Tolkien"));
+    }
+
+    #[test]
+    fn metadata_store_get_missing() {
+        let arg1 = MetadataStore::new("test/res/metadata_store_get/missing");
+        let arg2 = FileHash::from_str(
+            &"e80aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7".to_string(),
+        )
+        .unwrap();
+
+        let result = arg1.get(&arg2).unwrap();
+
+        assert!(result.is_empty());
+    }
+}
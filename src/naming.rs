@@ -0,0 +1,181 @@
+//! How objects are named when they are added.
+//!
+//! Names identify objects in manifests and on the command line, and are
+//! the file names of the links in `.hoard/objects/by-name`. The policy
+//! is set in `.hoard/config.toml`:
+//!
+//! ```toml
+//! [naming]
+//! policy = "template"
+//! template = "{author} - {title}"
+//! ```
+//!
+//! - `stem` names an object after the file it was added from, without
+//!   its extension
+//! - `template` renders a template, as described in the `template`
+//!   module, from the attributes of the object, falling back to the stem
+//!   when a field is missing
+//! - `hash` uses the first characters of the hash of its contents
+//!
+//! When the name is already taken, or is one that manifests reserve such
+//! as `rules`, `~2`, `~3` and so on is appended.
+//!
+use std::path::Path;
+
+use query::Attributes;
+use template::{self, Template};
+use Result;
+
+/// The number of characters of the hash used by the `hash` policy.
+const HASH_PREFIX: usize = 12;
+
+/// Names that have a special meaning in manifests.
+const RESERVED: &[&str] = &["version", "rules", "include"];
+
+/// The longest derived name, leaving room for a suffix within the 255
+/// bytes allowed in a file name.
+const MAX_DERIVED: usize = 240;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    #[default]
+    Stem,
+    Template,
+    Hash,
+}
+
+/// Derives the name of an object added from the given path.
+pub fn derive(
+    policy: Policy,
+    template: Option<&Template>,
+    path: &Path,
+    attributes: &Attributes,
+) -> String {
+    let stem = || {
+        path.file_stem()
+            .map(|stem| template::sanitize(&stem.to_string_lossy()))
+            .filter(|stem| !stem.is_empty())
+            .unwrap_or_else(|| "object".to_string())
+    };
+    // Names that only fail `check` for being reserved are left to `unique`.
+    let mut name = match policy {
+        Policy::Stem => stem(),
+        Policy::Template => template
+            .and_then(|template| template.render(attributes))
+            .map(|name| template::sanitize(&name))
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(stem),
+        Policy::Hash => attributes
+            .first("hash")
+            .map(|hash| hash.chars().take(HASH_PREFIX).collect())
+            .unwrap_or_else(stem),
+    };
+    let mut end = name.len().min(MAX_DERIVED);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name.truncate(end);
+    name
+}
+
+/// Returns the name, or the first of `name~2`, `name~3` and so on that
+/// is not taken.
+pub fn unique<F>(name: &str, is_taken: F) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    let mut unique = name.to_string();
+    let mut n = 1;
+    while is_taken(&unique) {
+        n += 1;
+        unique = format!("{}~{}", name, n);
+    }
+    unique
+}
+
+/// Checks that a name can be used as the file name of a link and in a
+/// manifest.
+pub fn check(name: &str) -> Result<()> {
+    if name.is_empty() {
+        bail!("object names cannot be empty");
+    }
+    if name == "." || name == ".." || name.starts_with('.') {
+        bail!("object name '{}' cannot start with '.'", name);
+    }
+    if RESERVED.contains(&name) {
+        bail!("object name '{}' is reserved by the manifest", name);
+    }
+    if template::sanitize(name) != name {
+        bail!(
+            "object name '{}' contains characters that are not allowed in file names",
+            name
+        );
+    }
+    if name.len() > 255 {
+        bail!("object name '{}' is too long", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn naming_derive() {
+        let arg1 = Path::new("books/The Hobbit.epub");
+        let mut arg2 = Attributes::new();
+        arg2.insert(
+            "hash",
+            "e80aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7",
+        );
+        arg2.insert("author", "Tolkien");
+        let arg3 = Template::parse("{author} - {title}").unwrap();
+
+        assert_eq!(derive(Policy::Stem, None, arg1, &arg2), "The Hobbit");
+        assert_eq!(derive(Policy::Hash, None, arg1, &arg2), "e80aa9815d6b");
+        assert_eq!(
+            derive(Policy::Template, Some(&arg3), arg1, &arg2),
+            "The Hobbit"
+        );
+        arg2.insert("title", "The Hobbit");
+        assert_eq!(
+            derive(Policy::Template, Some(&arg3), arg1, &arg2),
+            "Tolkien - The Hobbit"
+        );
+    }
+
+    #[test]
+    fn naming_unique() {
+        let taken = ["item", "item~2"];
+
+        let result = unique("item", |name| taken.contains(&name));
+
+        assert_eq!(result, "item~3");
+    }
+
+    #[test]
+    fn naming_check() {
+        assert!(check("The Hobbit").is_ok());
+        assert!(check("").is_err());
+        assert!(check(".hidden").is_err());
+        assert!(check("rules").is_err());
+        assert!(check("a/b").is_err());
+        assert!(check("a:b").is_err());
+    }
+
+    #[test]
+    fn naming_derive_reserved() {
+        let arg1 = Path::new("rules.txt");
+        let arg2 = "x".repeat(300) + ".txt";
+        let taken = ["rules~2"];
+
+        let result = derive(Policy::Stem, None, arg1, &Attributes::new());
+        let result = unique(&result, |name| taken.contains(&name) || check(name).is_err());
+        assert_eq!(result, "rules~3");
+
+        let result = derive(Policy::Stem, None, Path::new(&arg2), &Attributes::new());
+        assert!(check(&result).is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+//! A small query language for selecting objects by their attributes.
+//!
+//! A query is a whitespace separated list of terms, all of which must
+//! match for an object to be selected:
+//!
+//! - `word` matches objects whose name contains `word`
+//! - `key:value` matches objects with a `key` attribute equal to `value`
+//! - `key>value` and `key<value` compare numerically when both sides are
+//!   numbers, and lexically otherwise
+//! - a leading `-` negates a term
+//!
+//! Within `word` and `key:value` terms, `*` matches any sequence of
+//! characters. Values containing whitespace can be wrapped in double
+//! quotes, as in `title:"the hobbit"`. All comparisons are
+//! case-insensitive.
+//!
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use Result;
+
+/// The attributes of an object that can be queried.
+///
+/// An attribute can have several values, such as the tags of an object.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    inner: BTreeMap<String, Vec<String>>,
+}
+
+impl Attributes {
+    pub fn new() -> Self {
+        Attributes::default()
+    }
+
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let values = self.inner.entry(key.into()).or_default();
+        let value = value.into();
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+
+    /// Replaces every value of the key with the given one.
+    pub fn set<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.inner.insert(key.into(), vec![value.into()]);
+    }
+
+    pub fn get(&self, key: &str) -> &[String] {
+        self.inner.get(key).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn first(&self, key: &str) -> Option<&str> {
+        self.get(key).first().map(|v| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.inner.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Lt,
+    Gt,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Term {
+    negate: bool,
+    key: Option<String>,
+    op: Op,
+    value: String,
+}
+
+impl Term {
+    fn parse(token: &str) -> Result<Self> {
+        let (negate, token) = if token.starts_with('-') && token.len() > 1 {
+            (true, &token[1..])
+        } else {
+            (false, token)
+        };
+
+        let split = token.find(&[':', '<', '>'][..]);
+        let (key, op, value) = match split {
+            Some(i) => {
+                let op = match &token[i..=i] {
+                    ":" => Op::Eq,
+                    "<" => Op::Lt,
+                    _ => Op::Gt,
+                };
+                (Some(token[..i].to_lowercase()), op, &token[i + 1..])
+            }
+            None => (None, Op::Eq, token),
+        };
+
+        if key.as_ref().map(|k| k.is_empty()).unwrap_or(false) {
+            bail!("missing attribute name in query term '{}'", token);
+        }
+        if value.is_empty() {
+            bail!("missing value in query term '{}'", token);
+        }
+
+        Ok(Term {
+            negate,
+            key,
+            op,
+            value: value.trim_matches('"').to_lowercase(),
+        })
+    }
+
+    fn matches(&self, attributes: &Attributes) -> bool {
+        let found = match self.key {
+            None => {
+                let pattern = format!("*{}*", self.value);
+                attributes
+                    .get("name")
+                    .iter()
+                    .any(|name| glob(&pattern, &name.to_lowercase()))
+            }
+            Some(ref key) => attributes.get(key).iter().any(|actual| {
+                let actual = actual.to_lowercase();
+                match self.op {
+                    Op::Eq => glob(&self.value, &actual),
+                    Op::Lt => compare(&actual, &self.value) == Ordering::Less,
+                    Op::Gt => compare(&actual, &self.value) == Ordering::Greater,
+                }
+            }),
+        };
+        found != self.negate
+    }
+}
+
+/// A parsed query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+impl Query {
+    /// Parses a query. The empty query matches every object.
+    pub fn parse(input: &str) -> Result<Self> {
+        let terms = tokenize(input)?
+            .iter()
+            .map(|token| Term::parse(token))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Query { terms })
+    }
+
+    pub fn matches(&self, attributes: &Attributes) -> bool {
+        self.terms.iter().all(|term| term.matches(attributes))
+    }
+}
+
+/// Splits the input on whitespace, keeping quoted sections together.
+fn tokenize(input: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(current.clone());
+                    current.clear();
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if quoted {
+        bail!("unterminated quote in query '{}'", input);
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+/// Matches text against a pattern where `*` matches any sequence of
+/// characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+    if text.len() < first.len() + last.len() || !text.starts_with(first) || !text.ends_with(last)
+    {
+        return false;
+    }
+
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes() -> Attributes {
+        let mut attributes = Attributes::new();
+        attributes.insert("name", "The-Hobbit");
+        attributes.insert("tag", "unread");
+        attributes.insert("tag", "fantasy");
+        attributes.insert("year", "1937");
+        attributes
+    }
+
+    #[test]
+    fn query_parse_invalid() {
+        assert!(Query::parse("tag:").is_err());
+        assert!(Query::parse(":unread").is_err());
+        assert!(Query::parse("title:\"the hobbit").is_err());
+    }
+
+    #[test]
+    fn query_matches_empty() {
+        let result = Query::parse("").unwrap();
+
+        assert!(result.matches(&Attributes::new()));
+    }
+
+    #[test]
+    fn query_matches_terms() {
+        let arg1 = attributes();
+
+        assert!(Query::parse("hobbit").unwrap().matches(&arg1));
+        assert!(Query::parse("tag:unread").unwrap().matches(&arg1));
+        assert!(Query::parse("tag:fan*").unwrap().matches(&arg1));
+        assert!(Query::parse("year>1900 year<2000").unwrap().matches(&arg1));
+        assert!(Query::parse("-tag:read").unwrap().matches(&arg1));
+        assert!(!Query::parse("-tag:*").unwrap().matches(&arg1));
+        assert!(!Query::parse("tag:unread year>2000").unwrap().matches(&arg1));
+    }
+}
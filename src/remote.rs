@@ -0,0 +1,407 @@
+//! Syncs objects between hoards on locally mounted paths, such as a
+//! subset on a laptop and the full copy on a home server.
+//!
+//! Remotes are named in `.hoard/config.toml`:
+//!
+//! ```toml
+//! [remotes.server]
+//! path = "/mnt/server/media"
+//! ```
+//!
+//! Syncing copies every object the receiving hoard is missing, keyed by
+//! hash, and checks each copy against its hash before storing it. Unless
+//! only fetching, the names and metadata of the objects are then merged:
+//!
+//! - a missing name is added
+//! - a name for different contents on each side is a conflict, and is
+//!   left alone
+//! - contents with a different name on each side are a conflict, and
+//!   keep the name of the receiving hoard
+//! - tags are combined and missing fields are added, while a field with
+//!   a different value on each side is a conflict that keeps the value
+//!   of the receiving hoard
+//!
+//! Objects that are absent on the sending side are added as absent
+//! objects, whose contents can be copied later with `get`.
+//!
+//! Both hoards also record which of them hold the contents of every
+//! merged object, as described in the `copies` module.
+//!
+//! An encrypted store, as described in the `crypt` module, can also be
+//! a remote. Pushing to it only copies contents, which are copied back
+//! with `get`.
+//!
+//! Merging only changes the objects of a hoard, not its working tree,
+//! which is updated by `apply`.
+//!
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use copies;
+use error::ResultExt;
+use hoard::FileHash;
+use metadata::{Metadata, MetadataStore};
+use state::Index;
+use store::{self, LinkStore, Store};
+use Result;
+
+/// A difference between two hoards that is not merged automatically.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Conflict {
+    /// The same name is used for different contents.
+    Name {
+        name: String,
+        ours: FileHash,
+        theirs: FileHash,
+    },
+    /// The same contents have different names.
+    Renamed {
+        hash: FileHash,
+        ours: String,
+        theirs: String,
+    },
+    /// A metadata field has a different value on each side.
+    Field {
+        name: String,
+        key: String,
+        ours: String,
+        theirs: String,
+    },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Conflict::Name {
+                ref name,
+                ref ours,
+                ref theirs,
+            } => write!(
+                f,
+                "'{}' is {} here but {} there",
+                name,
+                &ours[..12],
+                &theirs[..12]
+            ),
+            Conflict::Renamed {
+                ref hash,
+                ref ours,
+                ref theirs,
+            } => write!(
+                f,
+                "{} is named '{}' here but '{}' there",
+                &hash[..12],
+                ours,
+                theirs
+            ),
+            Conflict::Field {
+                ref name,
+                ref key,
+                ref ours,
+                ref theirs,
+            } => write!(
+                f,
+                "'{}' has {} '{}' here but '{}' there",
+                name, key, ours, theirs
+            ),
+        }
+    }
+}
+
+/// The outcome of syncing two hoards.
+#[derive(Debug, Default)]
+pub struct Sync {
+    /// The number of objects copied and their total size.
+    pub objects: usize,
+    pub bytes: u64,
+    /// The names added to the receiving hoard.
+    pub names: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Copies the objects of the hoard at `from` that the hoard at `to` is
+/// missing, then merges their names and metadata if `merge` is set.
+///
+/// Objects whose names conflict are not copied, so that they are not
+/// stored without a name.
+pub fn sync(from: &Path, to: &Path, merge: bool) -> Result<Sync> {
+    let names = |index: &Index| -> BTreeMap<String, FileHash> {
+        index
+            .objects
+            .iter()
+            .map(|object| (object.name().to_string(), object.hash().clone()))
+            .collect()
+    };
+    let source = Index::from(from)?;
+    let theirs = names(&source);
+    let mut ours = names(&Index::from(to)?);
+    let (added, conflicts) = plan(&ours, &theirs);
+
+    let source_store = store::local(from)?;
+    let mut store = store::local(to)?;
+    let mut report = Sync::default();
+    for (_, hash) in added.iter() {
+        if source_store.has(hash) && !store.has(hash) {
+            report.bytes += copy(&*source_store, &mut *store, hash, to)?;
+            report.objects += 1;
+        }
+    }
+    if !merge {
+        return Ok(report);
+    }
+
+    report.conflicts = conflicts;
+    let by_name = to.join(".hoard/objects/by-name");
+    for (name, hash) in added {
+        let link = by_name.join(&name);
+        symlink(store.link_target(&hash), &link).with_path(&link)?;
+        report.names.push(name.clone());
+        ours.insert(name, hash);
+    }
+
+    let source_metadata = MetadataStore::new(from);
+    let target_metadata = MetadataStore::new(to);
+    let by_hash: HashMap<&FileHash, &String> = ours.iter().map(|(n, h)| (h, n)).collect();
+    for hash in theirs.values() {
+        let name = match by_hash.get(hash) {
+            Some(name) => name,
+            None => continue,
+        };
+        let metadata = source_metadata.get(hash)?;
+        if metadata.is_empty() {
+            continue;
+        }
+        let conflicts = &mut report.conflicts;
+        let present = store.file(hash).is_some();
+        target_metadata.update(hash, |ours| {
+            let fields = merge_metadata(ours, &metadata);
+            // Only absent and cold objects keep the attributes of their
+            // contents.
+            if present {
+                ours.contents.clear();
+            }
+            for (key, ours, theirs) in fields {
+                conflicts.push(Conflict::Field {
+                    name: name.to_string(),
+                    key,
+                    ours,
+                    theirs,
+                });
+            }
+            Ok(())
+        })?;
+    }
+
+    let source_id = copies::store_id(from)?;
+    let target_id = copies::store_id(to)?;
+    for object in source.objects.iter() {
+        let hash = object.hash();
+        if !by_hash.contains_key(hash) {
+            continue;
+        }
+        let locations = [
+            (&source_id, source_store.has(hash)),
+            (&target_id, store.has(hash)),
+        ];
+        for metadata in [&source_metadata, &target_metadata].iter() {
+            let mut ours = metadata.get(hash)?;
+            let mut changed = false;
+            for &(id, present) in locations.iter() {
+                changed |= ours.locate(id, present);
+            }
+            if changed {
+                metadata.put(hash, &ours)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Copies the contents of the objects of the hoard at `from` that the
+/// store is missing, and records that the store with the given id holds
+/// them.
+pub fn backup(from: &Path, store: &mut dyn Store, id: &str) -> Result<Sync> {
+    let metadata = MetadataStore::new(from);
+    let source_store = store::local(from)?;
+    let held: HashSet<FileHash> = store.list()?.into_iter().collect();
+    let mut report = Sync::default();
+    for object in Index::from(from)?.objects.iter() {
+        let hash = object.hash();
+        if !source_store.has(hash) {
+            continue;
+        }
+        if !held.contains(hash) {
+            report.bytes += copy(&*source_store, store, hash, from)?;
+            report.objects += 1;
+        }
+        let mut ours = metadata.get(hash)?;
+        if ours.locate(id, true) {
+            metadata.put(hash, &ours)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Copies the contents with the given hash from the object store of a
+/// hoard to another store, and returns their size.
+///
+/// Cold contents are decompressed into the hoard at `tmp` first.
+fn copy(from: &dyn LinkStore, to: &mut dyn Store, hash: &FileHash, tmp: &Path) -> Result<u64> {
+    if let Some(object) = from.file(hash) {
+        let path = object.path();
+        to.put(path, hash)?;
+        return Ok(path.metadata().with_path(path)?.len());
+    }
+    let copy = tmp
+        .join(".hoard/objects")
+        .join(format!("{}.get", hash.as_str()));
+    from.get(hash, &copy)?;
+    let size = copy.metadata().with_path(&copy)?.len();
+    let stored = to.put(&copy, hash);
+    if copy.exists() {
+        fs::remove_file(&copy).with_path(&copy)?;
+    }
+    stored.map(|_| size)
+}
+
+/// Returns the names to add to our side, and the conflicting ones.
+fn plan(
+    ours: &BTreeMap<String, FileHash>,
+    theirs: &BTreeMap<String, FileHash>,
+) -> (Vec<(String, FileHash)>, Vec<Conflict>) {
+    let by_hash: HashMap<&FileHash, &String> = ours.iter().map(|(n, h)| (h, n)).collect();
+    let mut added = Vec::new();
+    let mut conflicts = Vec::new();
+    for (name, hash) in theirs.iter() {
+        match (ours.get(name), by_hash.get(hash)) {
+            (Some(ours), _) if ours == hash => {}
+            (Some(ours), _) => conflicts.push(Conflict::Name {
+                name: name.clone(),
+                ours: ours.clone(),
+                theirs: hash.clone(),
+            }),
+            (None, Some(other)) => conflicts.push(Conflict::Renamed {
+                hash: hash.clone(),
+                ours: other.to_string(),
+                theirs: name.clone(),
+            }),
+            (None, None) => added.push((name.clone(), hash.clone())),
+        }
+    }
+    (added, conflicts)
+}
+
+/// Merges their metadata into ours, returning the key and both values of
+/// every field that differs.
+///
+/// The date an object was added differs between hoards by design, so
+/// the earliest is kept instead.
+fn merge_metadata(ours: &mut Metadata, theirs: &Metadata) -> Vec<(String, String, String)> {
+    ours.tags.extend(theirs.tags.iter().cloned());
+    if ours.fingerprint.is_none() {
+        ours.fingerprint = theirs.fingerprint.clone();
+    }
+    if ours.mime.is_none() {
+        ours.mime = theirs.mime.clone();
+        ours.kind = theirs.kind;
+    }
+    if ours.contents.is_empty() {
+        ours.contents = theirs.contents.clone();
+    }
+    copies::merge(&mut ours.locations, &theirs.locations);
+
+    let mut conflicts = Vec::new();
+    for (key, value) in theirs.fields.iter() {
+        let ours = ours
+            .fields
+            .entry(key.clone())
+            .or_insert_with(|| value.clone());
+        if ours == value {
+            continue;
+        }
+        if key == "added" {
+            if value < ours {
+                *ours = value.clone();
+            }
+        } else {
+            conflicts.push((key.clone(), ours.clone(), value.clone()));
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(c: char) -> FileHash {
+        FileHash::from_str(&c.to_string().repeat(64)).unwrap()
+    }
+
+    #[test]
+    fn remote_plan() {
+        let mut arg1 = BTreeMap::new();
+        arg1.insert("item1".to_string(), hash('1'));
+        arg1.insert("item2".to_string(), hash('2'));
+        arg1.insert("item3".to_string(), hash('3'));
+        let mut arg2 = BTreeMap::new();
+        arg2.insert("item1".to_string(), hash('1'));
+        arg2.insert("item2".to_string(), hash('a'));
+        arg2.insert("moved".to_string(), hash('3'));
+        arg2.insert("item4".to_string(), hash('4'));
+
+        let (added, conflicts) = plan(&arg1, &arg2);
+
+        assert_eq!(added, vec![("item4".to_string(), hash('4'))]);
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict::Name {
+                    name: "item2".to_string(),
+                    ours: hash('2'),
+                    theirs: hash('a'),
+                },
+                Conflict::Renamed {
+                    hash: hash('3'),
+                    ours: "item3".to_string(),
+                    theirs: "moved".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_merge_metadata() {
+        let mut arg1 = Metadata::default();
+        arg1.tags.insert("unread".to_string());
+        arg1.fields
+            .insert("added".to_string(), "2020-02-01".to_string());
+        arg1.fields
+            .insert("author".to_string(), "Tolkien".to_string());
+        let mut arg2 = Metadata::default();
+        arg2.tags.insert("fantasy".to_string());
+        arg2.fields
+            .insert("added".to_string(), "2019-05-01".to_string());
+        arg2.fields
+            .insert("author".to_string(), "J. R. R. Tolkien".to_string());
+        arg2.fields.insert("year".to_string(), "1937".to_string());
+
+        let result = merge_metadata(&mut arg1, &arg2);
+
+        assert_eq!(
+            result,
+            vec![(
+                "author".to_string(),
+                "Tolkien".to_string(),
+                "J. R. R. Tolkien".to_string()
+            )]
+        );
+        assert_eq!(arg1.tags.len(), 2);
+        assert_eq!(arg1.fields["added"], "2019-05-01");
+        assert_eq!(arg1.fields["author"], "Tolkien");
+        assert_eq!(arg1.fields["year"], "1937");
+    }
+}
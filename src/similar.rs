@@ -0,0 +1,271 @@
+//! Finds objects that are probably the same photo, cover or recording,
+//! even though their contents differ.
+//!
+//! Each object gets a fingerprint, which is cached in its metadata:
+//!
+//! - images get a 64 bit difference hash (dHash) of a 9x8 grayscale
+//!   thumbnail, which survives resizing and re-encoding
+//! - audio gets its normalized artist and title along with its duration
+//!   when it is known, which is cheap and matches re-encoded copies of
+//!   the same track
+//!
+//! The distance between two images is the number of differing bits of
+//! their hashes, and the distance between two recordings with the same
+//! artist and title is the difference between their durations in
+//! seconds, or zero if either duration is unknown. Fingerprints of
+//! different kinds are never similar.
+//!
+//! Images are decoded with the pure Rust `png` and `jpeg-decoder` crates.
+//!
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+
+use jpeg_decoder;
+use png;
+
+use query::Attributes;
+use Result;
+
+/// The default largest distance at which objects are considered similar.
+pub const THRESHOLD: u32 = 10;
+
+/// Stored for objects that have no fingerprint, so that they are not
+/// decoded again.
+pub const NONE: &str = "none";
+
+/// Computes the fingerprint of the object with the given contents and
+/// attributes, or `NONE` if its kind of content is not supported.
+pub fn fingerprint(path: &Path, attributes: &Attributes) -> Result<String> {
+    let fingerprint = match attributes.first("mime") {
+        Some("image/jpeg") | Some("image/png") => {
+            let (width, height, pixels) = decode(path, attributes.first("mime").unwrap())?;
+            format!("dhash:{:016x}", dhash(width, height, &pixels))
+        }
+        Some(mime) if mime.starts_with("audio/") => {
+            let key = |field: &str| {
+                attributes
+                    .first(field)
+                    .unwrap_or("")
+                    .chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .flat_map(char::to_lowercase)
+                    .collect::<String>()
+            };
+            let (artist, title) = (key("artist"), key("title"));
+            if title.is_empty() {
+                NONE.to_string()
+            } else {
+                let duration = attributes.first("duration").unwrap_or("");
+                format!("audio:{}:{}:{}", duration, artist, title)
+            }
+        }
+        _ => NONE.to_string(),
+    };
+    Ok(fingerprint)
+}
+
+/// Returns the distance between two fingerprints, or `None` if they
+/// can't be compared.
+pub fn distance(a: &str, b: &str) -> Option<u32> {
+    let mut a = a.splitn(2, ':');
+    let mut b = b.splitn(2, ':');
+    match (a.next()?, a.next()?, b.next()?, b.next()?) {
+        ("dhash", a, "dhash", b) => {
+            let a = u64::from_str_radix(a, 16).ok()?;
+            let b = u64::from_str_radix(b, 16).ok()?;
+            Some((a ^ b).count_ones())
+        }
+        ("audio", a, "audio", b) => {
+            let (a_duration, a_key) = split_duration(a)?;
+            let (b_duration, b_key) = split_duration(b)?;
+            if a_key != b_key {
+                return None;
+            }
+            match (a_duration, b_duration) {
+                (Some(a), Some(b)) => Some((a - b).unsigned_abs() as u32),
+                _ => Some(0),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn split_duration(audio: &str) -> Option<(Option<i64>, &str)> {
+    let mut parts = audio.splitn(2, ':');
+    let duration = parts.next()?.parse().ok();
+    Some((duration, parts.next()?))
+}
+
+/// Decodes an image into 8 bit grayscale pixels.
+fn decode(path: &Path, mime: &str) -> Result<(usize, usize, Vec<u8>)> {
+    let file = BufReader::new(fs::File::open(path)?);
+    if mime == "image/jpeg" {
+        let mut decoder = jpeg_decoder::Decoder::new(file);
+        // Decoding at a reduced size is much faster, and enough for a
+        // 9x8 thumbnail.
+        let (width, height) = decoder.scale(64, 64)?;
+        let pixels = decoder.decode()?;
+        let format = decoder.info().map(|info| info.pixel_format);
+        let gray = match format {
+            Some(jpeg_decoder::PixelFormat::L8) => pixels,
+            Some(jpeg_decoder::PixelFormat::L16) => pixels.chunks(2).map(|p| p[0]).collect(),
+            Some(jpeg_decoder::PixelFormat::RGB24) => pixels.chunks(3).map(luma).collect(),
+            Some(jpeg_decoder::PixelFormat::CMYK32) => pixels
+                .chunks(4)
+                .map(|p| luma(&[255 - p[0], 255 - p[1], 255 - p[2]]))
+                .collect(),
+            None => bail!("{}: missing image information", path.display()),
+        };
+        return Ok((usize::from(width), usize::from(height), gray));
+    }
+
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    pixels.truncate(info.buffer_size());
+    let gray = match info.color_type {
+        png::ColorType::Grayscale => pixels,
+        png::ColorType::GrayscaleAlpha => pixels.chunks(2).map(|p| p[0]).collect(),
+        png::ColorType::Rgb => pixels.chunks(3).map(luma).collect(),
+        png::ColorType::Rgba => pixels.chunks(4).map(luma).collect(),
+        png::ColorType::Indexed => bail!("{}: unexpanded palette", path.display()),
+    };
+    Ok((info.width as usize, info.height as usize, gray))
+}
+
+fn luma(rgb: &[u8]) -> u8 {
+    let (r, g, b) = (u32::from(rgb[0]), u32::from(rgb[1]), u32::from(rgb[2]));
+    ((r * 299 + g * 587 + b * 114) / 1000) as u8
+}
+
+/// Computes the difference hash of a grayscale image: each bit tells
+/// whether a cell of a 9x8 thumbnail is brighter than the next one in
+/// its row.
+fn dhash(width: usize, height: usize, pixels: &[u8]) -> u64 {
+    const COLUMNS: usize = 9;
+    const ROWS: usize = 8;
+    if width == 0 || height == 0 {
+        return 0;
+    }
+
+    // Averages the pixels covered by each cell of the thumbnail.
+    let mut cells = [[0u32; COLUMNS]; ROWS];
+    for (row, cells) in cells.iter_mut().enumerate() {
+        let (top, bottom) = (
+            row * height / ROWS,
+            ((row + 1) * height / ROWS).max(row * height / ROWS + 1),
+        );
+        for (column, cell) in cells.iter_mut().enumerate() {
+            let left = column * width / COLUMNS;
+            let right = ((column + 1) * width / COLUMNS).max(left + 1);
+            let mut sum = 0;
+            let mut count = 0;
+            for y in top..bottom.min(height) {
+                for x in left..right.min(width) {
+                    sum += u32::from(pixels[y * width + x]);
+                    count += 1;
+                }
+            }
+            *cell = sum.checked_div(count).unwrap_or(0);
+        }
+    }
+
+    let mut hash = 0;
+    for row in cells.iter() {
+        for pair in row.windows(2) {
+            hash = hash << 1 | u64::from(pair[0] > pair[1]);
+        }
+    }
+    hash
+}
+
+/// Groups the fingerprinted objects into clusters, where every object is
+/// within the threshold of at least one other object in its cluster.
+pub fn clusters<'a>(fingerprints: &[(&'a str, &str)], threshold: u32) -> Vec<Vec<&'a str>> {
+    // Union-find over the indices of the objects.
+    fn root(parents: &mut [usize], i: usize) -> usize {
+        let mut i = i;
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    let mut parents: Vec<usize> = (0..fingerprints.len()).collect();
+    for i in 0..fingerprints.len() {
+        for j in i + 1..fingerprints.len() {
+            match distance(fingerprints[i].1, fingerprints[j].1) {
+                Some(d) if d <= threshold => {
+                    let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                    parents[a.max(b)] = a.min(b);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<&str>> = vec![Vec::new(); fingerprints.len()];
+    for (i, &(name, _)) in fingerprints.iter().enumerate() {
+        let r = root(&mut parents, i);
+        clusters[r].push(name);
+    }
+    clusters.retain(|cluster| cluster.len() > 1);
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similar_dhash() {
+        let arg1: Vec<u8> = (0..18 * 16).map(|i| ((i % 18) * 14) as u8).collect();
+        let arg2: Vec<u8> = (0..9 * 8).map(|i| ((i % 9) * 28) as u8).collect();
+
+        let result1 = dhash(18, 16, &arg1);
+        let result2 = dhash(9, 8, &arg2);
+
+        assert_eq!(result1, 0);
+        assert_eq!(result1, result2);
+    }
+
+    #[test]
+    fn similar_distance() {
+        assert_eq!(
+            distance("dhash:00000000000000ff", "dhash:000000000000000f"),
+            Some(4)
+        );
+        assert_eq!(
+            distance("audio:200:band:song", "audio:203:band:song"),
+            Some(3)
+        );
+        assert_eq!(distance("audio::band:song", "audio:203:band:song"), Some(0));
+        assert_eq!(
+            distance("audio:200:band:song", "audio:200:band:other"),
+            None
+        );
+        assert_eq!(
+            distance("audio:200:band:song", "dhash:0000000000000000"),
+            None
+        );
+        assert_eq!(distance(NONE, NONE), None);
+    }
+
+    #[test]
+    fn similar_clusters() {
+        let arg1 = [
+            ("item1", "dhash:0000000000000000"),
+            ("item2", "dhash:0000000000000003"),
+            ("item3", "dhash:ffffffffffffffff"),
+            ("item4", "dhash:000000000000000f"),
+        ];
+
+        let result = clusters(&arg1, 2);
+
+        assert_eq!(result, vec![vec!["item1", "item2", "item4"]]);
+    }
+}
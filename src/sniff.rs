@@ -0,0 +1,243 @@
+//! Classifies files by the magic bytes at the start of their contents.
+//!
+//! Paths in a hoard are only links, so their extensions say nothing
+//! reliable about what an object is. Instead, each object is given a
+//! MIME type and a coarse `Kind` based on its leading bytes. This is
+//! done once, when the object is added, and kept in its metadata.
+//!
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use error::ResultExt;
+use Result;
+
+/// The number of leading bytes read to classify a file.
+const HEAD_SIZE: u64 = 4096;
+
+/// A coarse classification of the contents of an object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Video,
+    Audio,
+    Ebook,
+    Image,
+    Archive,
+    Document,
+    Other,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Video => "video",
+            Kind::Audio => "audio",
+            Kind::Ebook => "ebook",
+            Kind::Image => "image",
+            Kind::Archive => "archive",
+            Kind::Document => "document",
+            Kind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Signatures as an offset, the bytes expected there, the MIME type and
+/// the kind. They are checked in order, so more specific ones go first.
+const SIGNATURES: &[(usize, &[u8], &str, Kind)] = &[
+    (0, b"%PDF-", "application/pdf", Kind::Document),
+    (0, b"\xFF\xD8\xFF", "image/jpeg", Kind::Image),
+    (0, b"\x89PNG\r\n\x1a\n", "image/png", Kind::Image),
+    (0, b"GIF87a", "image/gif", Kind::Image),
+    (0, b"GIF89a", "image/gif", Kind::Image),
+    (0, b"II*\x00", "image/tiff", Kind::Image),
+    (0, b"MM\x00*", "image/tiff", Kind::Image),
+    (8, b"WEBP", "image/webp", Kind::Image),
+    (4, b"ftypavif", "image/avif", Kind::Image),
+    (4, b"ftypheic", "image/heic", Kind::Image),
+    (4, b"ftypM4A ", "audio/mp4", Kind::Audio),
+    (4, b"ftypM4B ", "audio/mp4", Kind::Audio),
+    (4, b"ftypqt  ", "video/quicktime", Kind::Video),
+    (4, b"ftyp", "video/mp4", Kind::Video),
+    (0, b"\x1A\x45\xDF\xA3", "video/x-matroska", Kind::Video),
+    (8, b"AVI ", "video/x-msvideo", Kind::Video),
+    (0, b"\x00\x00\x01\xBA", "video/mpeg", Kind::Video),
+    (0, b"ID3", "audio/mpeg", Kind::Audio),
+    (0, b"fLaC", "audio/flac", Kind::Audio),
+    (8, b"WAVE", "audio/wav", Kind::Audio),
+    (0, b"MThd", "audio/midi", Kind::Audio),
+    (
+        0,
+        b"BOOKMOBI",
+        "application/x-mobipocket-ebook",
+        Kind::Ebook,
+    ),
+    (
+        60,
+        b"BOOKMOBI",
+        "application/x-mobipocket-ebook",
+        Kind::Ebook,
+    ),
+    (0, b"AT&TFORM", "image/vnd.djvu", Kind::Ebook),
+    (0, b"\x1F\x8B", "application/gzip", Kind::Archive),
+    (0, b"BZh", "application/x-bzip2", Kind::Archive),
+    (0, b"\xFD7zXZ\x00", "application/x-xz", Kind::Archive),
+    (0, b"\x28\xB5\x2F\xFD", "application/zstd", Kind::Archive),
+    (
+        0,
+        b"7z\xBC\xAF\x27\x1C",
+        "application/x-7z-compressed",
+        Kind::Archive,
+    ),
+    (0, b"Rar!\x1A\x07", "application/vnd.rar", Kind::Archive),
+    (257, b"ustar", "application/x-tar", Kind::Archive),
+    (
+        0,
+        b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1",
+        "application/x-ole-storage",
+        Kind::Document,
+    ),
+    (0, b"{\\rtf", "application/rtf", Kind::Document),
+];
+
+/// Returns the MIME type and kind of the file at the given path.
+pub fn sniff_path<P: AsRef<Path>>(path: P) -> Result<(&'static str, Kind)> {
+    let path = path.as_ref();
+    let mut head = Vec::new();
+    fs::File::open(path)
+        .with_path(path)?
+        .take(HEAD_SIZE)
+        .read_to_end(&mut head)
+        .with_path(path)?;
+    Ok(sniff(&head))
+}
+
+/// Returns the MIME type and kind of a file given its leading bytes.
+pub fn sniff(head: &[u8]) -> (&'static str, Kind) {
+    let at = |offset: usize, bytes: &[u8]| {
+        head.get(offset..offset + bytes.len())
+            .map(|window| window == bytes)
+            .unwrap_or(false)
+    };
+
+    if at(0, b"PK\x03\x04") {
+        return zip(head);
+    }
+    if at(0, b"OggS") {
+        return if contains(head, b"\x01video") || contains(head, b"\x80theora") {
+            ("video/ogg", Kind::Video)
+        } else {
+            ("audio/ogg", Kind::Audio)
+        };
+    }
+    // MPEG audio frames start with an 11 bit sync word.
+    if head.len() >= 2 && head[0] == 0xFF && head[1] & 0xE0 == 0xE0 {
+        return ("audio/mpeg", Kind::Audio);
+    }
+    // Bitmaps have a short signature, so also check the reserved bytes.
+    if at(0, b"BM") && at(6, b"\x00\x00\x00\x00") {
+        return ("image/bmp", Kind::Image);
+    }
+    for &(offset, bytes, mime, kind) in SIGNATURES {
+        if at(offset, bytes) {
+            return (mime, kind);
+        }
+    }
+
+    if head.is_empty() {
+        ("application/x-empty", Kind::Other)
+    } else if is_text(head) {
+        let start = String::from_utf8_lossy(&head[..head.len().min(256)]).to_lowercase();
+        let start = start.trim_start();
+        if start.starts_with("<!doctype html") || start.starts_with("<html") {
+            ("text/html", Kind::Document)
+        } else {
+            ("text/plain", Kind::Document)
+        }
+    } else {
+        ("application/octet-stream", Kind::Other)
+    }
+}
+
+/// Tells apart the formats that are stored as ZIP archives by the name
+/// and contents of their first entry.
+fn zip(head: &[u8]) -> (&'static str, Kind) {
+    let start = &head[30.min(head.len())..head.len().min(128)];
+    if start.starts_with(b"mimetype") {
+        let body = &start[8..];
+        if body.starts_with(b"application/epub+zip") {
+            return ("application/epub+zip", Kind::Ebook);
+        }
+        if body.starts_with(b"application/vnd.oasis.opendocument.") {
+            return ("application/vnd.oasis.opendocument", Kind::Document);
+        }
+    }
+    if contains(head, b"word/") {
+        let mime = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+        return (mime, Kind::Document);
+    }
+    if contains(head, b"xl/") {
+        let mime = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+        return (mime, Kind::Document);
+    }
+    if contains(head, b"ppt/") {
+        let mime = "application/vnd.openxmlformats-officedocument.presentationml.presentation";
+        return (mime, Kind::Document);
+    }
+    ("application/zip", Kind::Archive)
+}
+
+fn contains(head: &[u8], needle: &[u8]) -> bool {
+    head.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Guesses whether the bytes are text, allowing for a character cut in
+/// half at the end.
+fn is_text(head: &[u8]) -> bool {
+    let valid = match ::std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && e.valid_up_to() + 4 > head.len(),
+    };
+    valid
+        && !head
+            .iter()
+            .any(|&b| b < 0x20 && b != b'\n' && b != b'\r' && b != b'\t' && b != 0x0C)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_signatures() {
+        assert_eq!(sniff(b"%PDF-1.4\n"), ("application/pdf", Kind::Document));
+        assert_eq!(
+            sniff(b"\x00\x00\x00\x18ftypmp42"),
+            ("video/mp4", Kind::Video)
+        );
+        assert_eq!(sniff(b"ID3\x04\x00"), ("audio/mpeg", Kind::Audio));
+        assert_eq!(sniff(b"hello, world\n"), ("text/plain", Kind::Document));
+        assert_eq!(
+            sniff(b"\x00\x01\x02"),
+            ("application/octet-stream", Kind::Other)
+        );
+    }
+
+    #[test]
+    fn sniff_zip() {
+        let mut arg1 = b"PK\x03\x04".to_vec();
+        arg1.extend_from_slice(&[0; 26]);
+        arg1.extend_from_slice(b"mimetypeapplication/epub+zip");
+
+        let result = sniff(&arg1);
+
+        assert_eq!(result, ("application/epub+zip", Kind::Ebook));
+    }
+}
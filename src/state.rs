@@ -2,25 +2,32 @@
 //!
 //! Handles conversion from both JSON manifests and the filesystem.
 //!
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs;
-use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use serde_json;
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 use error::ResultExt;
-use hoard::{self, FileHash};
+use hoard::{self, FileHash, FileObject};
+use manifest::{Manifest, Rule};
+use metadata::MetadataStore;
+use query::{Attributes, Query};
+use sniff::{self, Kind};
+use template;
 use Result;
 
-fn resolve(desire: &State, actual: &State, index: &Index) -> Vec<Change> {
+/// Produces the changes needed to turn the actual state into the
+/// desired state.
+///
+/// Only objects named in the desired state are considered, so objects
+/// missing from the manifest keep whatever paths they currently have.
+pub fn resolve(desire: &State, actual: &State, index: &Index) -> Vec<Change> {
     use self::ChangeType::*;
 
-    let desire_keys: BTreeSet<_> = desire.inner.keys().collect();
-    let actual_keys: BTreeSet<_> = actual.inner.keys().collect();
-    let nameset: BTreeSet<_> = desire_keys.intersection(&actual_keys).collect();
+    let nameset: BTreeSet<_> = desire.inner.keys().collect();
 
     let mut objects_by_name: HashMap<&str, &Object> = HashMap::new();
     for (name, object) in index.by_name().iter() {
@@ -77,6 +84,11 @@ pub struct Object {
     hash: FileHash,
     name: String,
     ino: u64,
+    mime: String,
+    kind: Kind,
+    /// Whether the contents are stored locally, as opposed to only on a
+    /// remote.
+    present: bool,
 }
 
 impl Object {
@@ -95,21 +107,55 @@ impl Object {
     pub fn hash(&self) -> &FileHash {
         &self.hash
     }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Returns the attributes of the object that can be used in queries.
+    ///
+    /// Only the name and hash are known of absent objects, the rest is
+    /// kept in their metadata.
+    pub fn attributes(&self) -> Result<Attributes> {
+        if !self.present {
+            let mut attributes = Attributes::new();
+            attributes.insert("name", self.name.as_str());
+            attributes.insert("hash", self.hash.as_str());
+            return Ok(attributes);
+        }
+
+        let metadata = self.path.metadata().with_path(&self.path)?;
+        let (year, _, _) = hoard::civil_date(metadata.mtime());
+
+        let mut attributes = Attributes::new();
+        attributes.insert("name", self.name.as_str());
+        attributes.insert("hash", self.hash.as_str());
+        attributes.insert("size", metadata.len().to_string());
+        attributes.insert("year", year.to_string());
+        attributes.insert("mime", self.mime.as_str());
+        attributes.insert("kind", self.kind.name());
+        Ok(attributes)
+    }
 }
 
 /// An in-memory represenation of the contents of the '.hoard/objects'
 /// folder. Its purpose is generally for looking up objects based on
 /// hash or inode.
 pub struct Index {
-    pub(crate) created: Vec<Object>,
-    pub(crate) deleted: Vec<Object>,
     pub(crate) objects: Vec<Object>,
 }
 
 impl Index {
     /// Builds an index using the given path as the hoard root.
+    ///
+    /// Names whose link leads to a missing object are absent objects,
+    /// whose contents have been dropped in favour of a remote.
+    ///
+    /// The MIME type and kind of each object are read from its metadata,
+    /// and only sniffed for objects added before they were recorded there.
     pub fn from<P: AsRef<Path>>(root: P) -> Result<Self> {
         let path_by_name = root.as_ref().join(".hoard/objects/by-name");
+        let store = MetadataStore::new(&root);
 
         let mut objects = vec![];
         for entry in WalkDir::new(&path_by_name)
@@ -123,36 +169,52 @@ impl Index {
                 .unwrap()
                 .to_string_lossy()
                 .to_string();
-            let path = entry.path().canonicalize().with_path(entry.path())?;
-
-            if !path.exists() {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Symlink does not lead to object",
-                )
-                .into());
-            }
-
-            let hash = FileHash::from_path(&path).with_path(&path)?;
-            let ino = path.metadata()?.ino();
+            let target = entry.path().read_link().with_path(entry.path())?;
+            let path = hoard::normalize(&path_by_name.join(target));
+            let present = path.exists();
+            let path = if present {
+                path.canonicalize().with_path(&path)?
+            } else {
+                path
+            };
+
+            let hash = match FileObject::new(&path) {
+                Ok(object) => object.hash().clone(),
+                Err(_) if present => FileHash::from_path(&path)?,
+                Err(_) => bail!(
+                    "{}: link does not lead to an object",
+                    entry.path().display()
+                ),
+            };
+            let metadata = store.get(&hash)?;
+            let (mime, kind) = match (metadata.mime, metadata.kind) {
+                (Some(mime), Some(kind)) => (mime, kind),
+                _ if present => {
+                    let (mime, kind) = sniff::sniff_path(&path)?;
+                    (mime.to_string(), kind)
+                }
+                _ => (String::new(), Kind::Other),
+            };
+            let ino = if present { path.metadata()?.ino() } else { 0 };
             objects.push(Object {
                 path,
                 hash,
                 name,
                 ino,
+                mime,
+                kind,
+                present,
             });
         }
 
-        Ok(Index {
-            created: vec![],
-            deleted: vec![],
-            objects,
-        })
+        Ok(Index { objects })
     }
 
+    /// Maps the inodes of the objects that are present to them.
     pub fn by_ino(&self) -> HashMap<&u64, &Object> {
         self.objects
             .iter()
+            .filter(|object| object.present)
             .map(|object| (object.ino(), object))
             .collect()
     }
@@ -170,6 +232,19 @@ impl Index {
             .map(|object| (object.hash(), object))
             .collect()
     }
+
+    /// Returns the attributes of every object, including its metadata,
+    /// keyed by name.
+    pub fn catalog(&self, store: &MetadataStore) -> Result<BTreeMap<String, Attributes>> {
+        self.objects
+            .iter()
+            .map(|object| {
+                let mut attributes = object.attributes()?;
+                store.get(object.hash())?.extend(&mut attributes);
+                Ok((object.name().to_string(), attributes))
+            })
+            .collect()
+    }
 }
 
 /// The types of change that can be executed.
@@ -193,6 +268,14 @@ pub struct Change {
 }
 
 impl Change {
+    pub fn path(&self) -> &Path {
+        &self._path
+    }
+
+    pub fn change_type(&self) -> &ChangeType {
+        &self._type
+    }
+
     pub fn execute(self) -> Result<()> {
         use self::ChangeType::*;
         match self._type {
@@ -200,17 +283,31 @@ impl Change {
             Delete(_) => {
                 fs::remove_file(&self._path)?;
             }
-            Create(src) => {
-                fs::hard_link(src.path(), &self._path)?;
-            }
-            Modify(_old, new) => {
-                hoard::link(new.path(), &self._path)?;
+            Create(src) | Modify(src, _) => {
+                if src.is_present() {
+                    hoard::link(src.path(), &self._path)?;
+                } else {
+                    hoard::placeholder(src.path(), &self._path)?;
+                }
             }
         };
         Ok(())
     }
 }
 
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ChangeType::*;
+        let action = match self._type {
+            Ignore => "ignore",
+            Create(_) => "create",
+            Delete(_) => "delete",
+            Modify(_, _) => "modify",
+        };
+        write!(f, "{}: {}", action, self._path.display())
+    }
+}
+
 /// An in-memory representation of the hoard.
 ///
 /// This can be produced from a file manifest, allowing the user
@@ -228,41 +325,27 @@ pub struct State {
 }
 
 impl State {
-    /// Builds a State according to a manifest.
-    /// The manifest is in the format of the following:
-    ///
-    /// ```json
-    /// {
-    ///   "item-name-1": [
-    ///     "path1/item-name-1",
-    ///     "path2/item-name-1"
-    ///   ],
-    ///   "item-name-2": [
-    ///     "path1/item-name-2",
-    ///     "path3/item-name-2"
-    ///   ]
-    /// }
-    /// ```
-    fn from_file<P>(path: P, names: &HashSet<String>) -> Result<Self>
-    where
-        P: AsRef<Path>,
-    {
+    /// Builds a State according to a parsed manifest, expanding its
+    /// rules against the attributes of the objects in the catalog.
+    pub fn from_manifest(
+        manifest: &Manifest,
+        catalog: &BTreeMap<String, Attributes>,
+    ) -> Result<Self> {
         let mut state = State {
             inner: BTreeMap::new(),
             extra: BTreeSet::new(),
         };
 
-        let file = fs::File::open(&path)?;
-        let manifest: HashMap<_, _> = serde_json::from_reader(file)?;
-
-        for (name, paths) in manifest.into_iter() {
-            if names.contains(&name) {
-                state.inner.insert(name, paths);
-            } else {
-                println!("No such object '{}'", name);
+        for (name, paths) in manifest.objects.iter() {
+            if catalog.contains_key(name) {
+                state.inner.insert(name.clone(), paths.clone());
             }
         }
 
+        for rule in manifest.rules.iter() {
+            state.expand(rule, catalog)?;
+        }
+
         {
             let mut dupes = BTreeMap::new();
             for (name, paths) in state.inner.iter() {
@@ -272,13 +355,72 @@ impl State {
             }
             dupes = dupes.into_iter().filter(|(_k, v)| v.len() > 1).collect();
             if !dupes.is_empty() {
-                bail!("duplicate paths for entries: {:#?}", dupes);
+                let mut message = "duplicate paths for entries:".to_string();
+                for (path, names) in dupes.iter() {
+                    let names: Vec<_> = names.iter().map(|name| name.as_str()).collect();
+                    message.push_str(&format!("\n  {}: {}", path.display(), names.join(", ")));
+                    if let Some(files) = manifest.origins.get(*path) {
+                        let files: Vec<_> = files.iter().map(|f| f.display().to_string()).collect();
+                        message.push_str(&format!(" (in {})", files.join(", ")));
+                    }
+                }
+                bail!("{}", message);
             }
         }
 
         Ok(state)
     }
 
+    /// Adds a path for every object in the catalog matched by the rule.
+    ///
+    /// When the path is already taken by another object, a ` (n)` suffix
+    /// is added to tell them apart.
+    fn expand(&mut self, rule: &Rule, catalog: &BTreeMap<String, Attributes>) -> Result<()> {
+        let context = |e: ::failure::Error| format_err!("rule for '{}': {}", rule.target(), e);
+        let query = Query::parse(&rule.query).map_err(context)?;
+        let template = rule.template().map_err(context)?;
+
+        let mut taken: HashMap<PathBuf, String> = HashMap::new();
+        for (name, paths) in self.inner.iter() {
+            for path in paths {
+                taken.insert(path.clone(), name.clone());
+            }
+        }
+
+        for (name, attributes) in catalog.iter() {
+            let path = match rule.path_for(&query, &template, name, attributes) {
+                Some(path) => path,
+                None => continue,
+            };
+            let mut unique = path.clone();
+            let mut n = 1;
+            while taken
+                .get(&unique)
+                .map(|owner| owner != name)
+                .unwrap_or(false)
+            {
+                n += 1;
+                unique = PathBuf::from(template::suffix(&path.to_string_lossy(), n));
+            }
+            taken.insert(unique.clone(), name.clone());
+            self.inner.entry(name.clone()).or_default().insert(unique);
+        }
+        Ok(())
+    }
+
+    /// Returns the State with every path resolved against the root.
+    pub fn rebase<P: AsRef<Path>>(self, root: P) -> Self {
+        let root = root.as_ref();
+        State {
+            inner: self
+                .inner
+                .into_iter()
+                .map(|(name, paths)| (name, paths.iter().map(|p| root.join(p)).collect()))
+                .collect(),
+            extra: self.extra.iter().map(|p| root.join(p)).collect(),
+        }
+    }
+
     /// Builds a State from the filesystem.
     /// Such a filesystem might look like the following.
     ///
@@ -295,19 +437,41 @@ impl State {
     ///
     /// 3 directories, 4 files
     /// ```
-    fn from_path<P>(path: P, index: &Index) -> Result<Self>
+    pub fn from_path<P>(path: P, index: &Index) -> Result<Self>
     where
         P: AsRef<Path>,
     {
+        fn is_index(entry: &DirEntry) -> bool {
+            entry.file_name() == ".hoard"
+        }
+
         let mut inner = BTreeMap::new();
         let mut extra = BTreeSet::new();
+        let by_hash = index.by_hash();
 
         for entry in WalkDir::new(&path.as_ref())
             .into_iter()
+            .filter_entry(|e| !is_index(e))
             .filter_map(|e| e.ok())
-            .filter(|e| e.path().is_file())
         {
             let path = entry.path();
+            // Placeholders of objects are links into the object store.
+            if entry.path_is_symlink() {
+                let hash = path
+                    .read_link()
+                    .ok()
+                    .and_then(|target| FileObject::new(target).ok());
+                if let Some(object) = hash.and_then(|o| by_hash.get(o.hash()).cloned()) {
+                    inner
+                        .entry(object.name().to_string())
+                        .or_insert(BTreeSet::new())
+                        .insert(path.to_path_buf());
+                    continue;
+                }
+            }
+            if !path.is_file() {
+                continue;
+            }
             let ino = path.metadata()?.ino();
             if let Some(object) = index.by_ino().get(&ino) {
                 inner
@@ -344,6 +508,36 @@ impl State {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
+    use std::env;
+    use std::process;
+
+    fn from_file<P: AsRef<Path>>(path: P, names: &HashSet<String>) -> Result<State> {
+        let catalog = names
+            .iter()
+            .map(|name| {
+                let mut attributes = Attributes::new();
+                attributes.insert("name", name.as_str());
+                (name.clone(), attributes)
+            })
+            .collect();
+
+        State::from_manifest(&Manifest::from_file(path)?, &catalog)
+    }
+
+    fn object(dir: &Path, name: &str, digit: &str) -> Object {
+        let path = dir.join(name);
+        fs::write(&path, name).unwrap();
+        Object {
+            ino: path.metadata().unwrap().ino(),
+            hash: FileHash::from_str(&digit.repeat(64)).unwrap(),
+            name: name.to_string(),
+            mime: "text/plain".to_string(),
+            kind: Kind::Document,
+            present: true,
+            path,
+        }
+    }
 
     #[test]
     fn change_execute_ignore() {
@@ -361,17 +555,59 @@ mod tests {
 
     #[test]
     fn change_execute_create() {
-        panic!("Unimplemented");
+        let dir = env::temp_dir().join(format!("hoard-change-create-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let arg1 = object(&dir, "object", "1");
+        let target = dir.join("path1/target");
+
+        let change = Change {
+            _path: target.clone(),
+            _type: ChangeType::Create(arg1.clone()),
+        };
+        change.execute().unwrap();
+
+        let result = target.metadata().unwrap().ino();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&result, arg1.ino());
     }
 
     #[test]
     fn change_execute_delete() {
-        panic!("Unimplemented");
+        let dir = env::temp_dir().join(format!("hoard-change-delete-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let arg1 = object(&dir, "object", "1");
+        let target = dir.join("target");
+        hoard::link(arg1.path(), &target).unwrap();
+
+        let change = Change {
+            _path: target.clone(),
+            _type: ChangeType::Delete(arg1.clone()),
+        };
+        change.execute().unwrap();
+
+        let result = (target.exists(), arg1.path().exists());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, (false, true));
     }
 
     #[test]
     fn change_execute_modify() {
-        panic!("Unimplemented");
+        let dir = env::temp_dir().join(format!("hoard-change-execute-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let arg1 = object(&dir, "desired", "1");
+        let arg2 = object(&dir, "actual", "2");
+        let target = dir.join("target");
+        hoard::link(arg2.path(), &target).unwrap();
+
+        let change = Change {
+            _path: target.clone(),
+            _type: ChangeType::Modify(arg1.clone(), arg2),
+        };
+        change.execute().unwrap();
+
+        let result = fs::read_to_string(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, "desired");
     }
 
     #[test]
@@ -380,8 +616,6 @@ mod tests {
 
         let result = Index::from(arg1).unwrap();
 
-        assert!(result.created.is_empty());
-        assert!(result.deleted.is_empty());
         assert_eq!(result.objects.len(), 1);
     }
 
@@ -394,6 +628,32 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn index_from_sniffed() {
+        let arg1 = "test/res/index_from/sniffed";
+
+        let result = Index::from(arg1).unwrap();
+
+        let attributes = result.objects[0].attributes().unwrap();
+        assert_eq!(attributes.first("mime"), Some("image/png"));
+        assert_eq!(attributes.first("kind"), Some("image"));
+    }
+
+    #[test]
+    fn index_from_absent() {
+        let arg1 = "test/res/index_from/absent";
+
+        let result = Index::from(arg1).unwrap();
+
+        assert_eq!(result.objects.len(), 1);
+        assert!(!result.objects[0].is_present());
+        assert_eq!(
+            result.objects[0].hash().as_str(),
+            "e80aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7"
+        );
+        assert!(result.by_ino().is_empty());
+    }
+
     #[test]
     fn state_from_file_duplicates() {
         let arg1 = "test/res/state_from_file/duplicates/test1.json";
@@ -407,7 +667,7 @@ mod tests {
         .cloned()
         .collect();
 
-        let result = State::from_file(arg1, &arg2);
+        let result = from_file(arg1, &arg2);
 
         assert!(result.is_err());
     }
@@ -417,7 +677,7 @@ mod tests {
         let arg1 = "test/res/state_from_file/invalid/test1.json";
         let arg2 = HashSet::new();
 
-        let result = State::from_file(arg1, &arg2);
+        let result = from_file(arg1, &arg2);
 
         assert!(result.is_err());
     }
@@ -427,7 +687,7 @@ mod tests {
         let arg1 = "test/res/state_from_file/no_objects/test1.json";
         let arg2 = HashSet::new();
 
-        let result = State::from_file(arg1, &arg2).unwrap();
+        let result = from_file(arg1, &arg2).unwrap();
 
         assert!(result.inner.is_empty());
     }
@@ -445,19 +705,73 @@ mod tests {
         .cloned()
         .collect();
 
-        let result = State::from_file(arg1, &arg2).unwrap();
+        let result = from_file(arg1, &arg2).unwrap();
 
         assert_eq!(result.inner.len(), 4);
     }
 
+    #[test]
+    fn state_from_file_rules() {
+        let arg1 = "test/res/state_from_file/rules/test1.json";
+        let arg2: HashSet<_, _> = ["item1".to_string(), "item2".to_string(), "item3".to_string()]
+            .iter()
+            .cloned()
+            .collect();
+
+        let result = from_file(arg1, &arg2).unwrap();
+
+        assert_eq!(result.inner.len(), 3);
+        assert_eq!(result.inner["item1"].len(), 3);
+        assert!(result.inner["item2"].contains(Path::new("matched/item2/item2")));
+        assert_eq!(result.inner["item3"].len(), 1);
+    }
+
+    #[test]
+    fn state_from_manifest_views() {
+        let mut arg1 = Manifest::default();
+        arg1.rules.push(Rule {
+            path: "{author}/{series|Standalone}/{title}.epub".to_string(),
+            ..Rule::default()
+        });
+        let mut arg2 = BTreeMap::new();
+        for &(name, title) in [("item1", "Title"), ("item2", "Title"), ("item3", "Other")].iter() {
+            let mut attributes = Attributes::new();
+            attributes.insert("author", "Author");
+            attributes.insert("title", title);
+            arg2.insert(name.to_string(), attributes);
+        }
+
+        let result = State::from_manifest(&arg1, &arg2).unwrap();
+
+        let path = |name: &str| result.inner[name].iter().next().unwrap().clone();
+        assert_eq!(path("item1"), Path::new("Author/Standalone/Title.epub"));
+        assert_eq!(path("item2"), Path::new("Author/Standalone/Title (2).epub"));
+        assert_eq!(path("item3"), Path::new("Author/Standalone/Other.epub"));
+    }
+
+    #[test]
+    fn state_from_manifest_duplicates() {
+        let arg1 = Manifest::load(
+            "test/res/manifest_load/duplicates/.hoard/manifest.json",
+            "test/res/manifest_load/duplicates",
+        )
+        .unwrap();
+        let arg2 = ["item1", "item2"]
+            .iter()
+            .map(|name| (name.to_string(), Attributes::new()))
+            .collect();
+
+        let result = State::from_manifest(&arg1, &arg2);
+
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("books/item1: item1, item2"));
+        assert!(message.contains("books/.hoard.json"));
+    }
+
     #[test]
     fn state_from_path_extra() {
         let arg1 = "test/res/state_from_path/extra";
-        let arg2 = Index {
-            created: vec![],
-            deleted: vec![],
-            objects: vec![],
-        };
+        let arg2 = Index { objects: vec![] };
 
         let result = State::from_path(arg1, &arg2).unwrap();
 
@@ -468,11 +782,7 @@ mod tests {
     #[test]
     fn state_from_path_empty() {
         let arg1 = "test/res/state_from_path/empty";
-        let arg2 = Index {
-            created: vec![],
-            deleted: vec![],
-            objects: vec![],
-        };
+        let arg2 = Index { objects: vec![] };
 
         let result = State::from_path(arg1, &arg2).unwrap();
 
@@ -482,11 +792,32 @@ mod tests {
 
     #[test]
     fn state_from_path_success() {
-        let arg1 = "test/res/state_from_path/success";
-        let arg2 = Index::from(arg1).expect("Invalid hoard repository");
+        let fixture = Path::new("test/res/state_from_path/success");
+        let arg1 = env::temp_dir().join(format!("hoard-state-from-path-{}", process::id()));
+        for entry in WalkDir::new(fixture).into_iter().map(|e| e.unwrap()) {
+            let path = arg1.join(entry.path().strip_prefix(fixture).unwrap());
+            if entry.path_is_symlink() {
+                let target = entry.path().read_link().unwrap();
+                ::std::os::unix::fs::symlink(target, &path).unwrap();
+            } else if entry.file_type().is_dir() {
+                fs::create_dir_all(&path).unwrap();
+            } else {
+                fs::copy(entry.path(), &path).unwrap();
+            }
+        }
+        // Git does not keep hard links, so the links to the object are
+        // made here.
+        let object = arg1.join(
+            ".hoard/objects/by-hash/e8/0aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7",
+        );
+        for link in ["path1/item-name-1", "path2/item-name-1"].iter() {
+            hoard::link(&object, arg1.join(link)).unwrap();
+        }
+        let arg2 = Index::from(&arg1).expect("Invalid hoard repository");
 
-        let result = State::from_path(arg1, &arg2).unwrap();
+        let result = State::from_path(&arg1, &arg2).unwrap();
 
+        fs::remove_dir_all(&arg1).unwrap();
         assert_eq!(result.inner.len(), 1);
         assert_eq!(result.extra.len(), 2);
     }
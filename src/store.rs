@@ -0,0 +1,374 @@
+//! Places that hold the contents of objects, keyed by their hash.
+//!
+//! A `FileStore` keeps contents as plain files in a directory, in one of
+//! these layouts:
+//!
+//! - `fanout`, the default, in directories named after the first two
+//!   characters of the hash, as in `ab/cdef…`
+//! - `flat`, all in one directory, as in `abcdef…`
+//!
+//! The object store of a hoard keeps its contents in one, in
+//! `.hoard/objects/by-hash` and in the layout chosen by `init`, along
+//! with the cold tiers described in the `cold` and `chunks` modules. The
+//! working tree links to its files, which `LinkStore` gives access to.
+//!
+//! An encrypted store, as described in the `crypt` module, is another,
+//! and can be added as a remote to keep copies on a shared or external
+//! disk.
+//!
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use multi_map::MultiMap as TwoKeyMap;
+use walkdir::WalkDir;
+
+use crypt::{self, EncryptedStore};
+use error::ResultExt;
+use hoard::{self, FileHash, FileObject, ObjectStore};
+use Result;
+
+/// How the contents are laid out in the object store of a hoard.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Fanout,
+    Flat,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "fanout" => Ok(Layout::Fanout),
+            "flat" => Ok(Layout::Flat),
+            _ => bail!("unknown store layout '{}'", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Fanout => "fanout",
+            Layout::Flat => "flat",
+        }
+    }
+
+    /// Returns where the contents with the given hash are kept, relative
+    /// to the store.
+    pub fn relative(self, hash: &FileHash) -> PathBuf {
+        match self {
+            Layout::Fanout => PathBuf::from(hash.as_path()),
+            Layout::Flat => PathBuf::from(hash.as_str()),
+        }
+    }
+}
+
+/// Returns the hash of the contents kept at the given path, in either
+/// layout.
+pub fn parse(path: &Path) -> Result<FileHash> {
+    let mut parts = path
+        .components()
+        .rev()
+        .map(|part| part.as_os_str().to_string_lossy().into_owned());
+    let name = parts.next().unwrap_or_default();
+    if name.len() == 64 {
+        return FileHash::from_str(&name);
+    }
+    match parts.next() {
+        Some(prefix) => FileHash::from_str(&format!("{}{}", prefix, name)),
+        None => bail!("{}: not the path of an object", path.display()),
+    }
+}
+
+pub trait Store {
+    /// Whether the store holds the contents with the given hash.
+    fn has(&self, hash: &FileHash) -> bool;
+
+    /// Writes a copy of the contents to the given path, checking it
+    /// against the hash.
+    fn get(&self, hash: &FileHash, dst: &Path) -> Result<()>;
+
+    /// Stores a copy of the file at the given path, whose hash is
+    /// already known.
+    fn put(&mut self, src: &Path, hash: &FileHash) -> Result<()>;
+
+    /// Removes the contents, if the store holds them.
+    fn remove(&mut self, hash: &FileHash) -> Result<()>;
+
+    /// Lists the hashes of every content in the store.
+    fn list(&self) -> Result<Vec<FileHash>>;
+
+    /// Whether the stored contents still match their hash.
+    fn verify(&self, hash: &FileHash) -> Result<bool>;
+}
+
+/// A store that keeps contents as files the working tree can link to,
+/// found by inode as well as by hash.
+pub trait LinkStore: Store {
+    /// Returns the file holding the contents with the given hash, unless
+    /// they are absent or only kept in a form that cannot be linked to.
+    fn file(&self, hash: &FileHash) -> Option<&FileObject>;
+
+    /// Stores the file at the given path by linking it, unless a file
+    /// with the same inode or contents is already stored, and returns
+    /// the hash of its contents.
+    fn link(&mut self, path: &Path) -> Result<FileHash>;
+
+    /// Moves a file into the store, whose hash is already known, or
+    /// copies it when it cannot be moved. A copy is checked against the
+    /// hash before the original is removed.
+    fn take(&mut self, path: &Path, hash: &FileHash) -> Result<()>;
+
+    /// Returns the target of a link in `by-name` to the contents with
+    /// the given hash.
+    fn link_target(&self, hash: &FileHash) -> PathBuf;
+
+    /// Returns the size of everything stored for the contents with the
+    /// given hash.
+    fn size(&self, hash: &FileHash) -> Result<u64>;
+
+    /// Moves the contents with the given hash out of their file into a
+    /// smaller form that cannot be linked to. Returns the number of bytes
+    /// saved, or nothing if they are left as they are.
+    fn freeze(&mut self, _hash: &FileHash) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Restores contents that are only kept in a form that cannot be
+    /// linked to into a file.
+    fn thaw(&mut self, _hash: &FileHash) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the contents with the given hash are stored, but not in a
+    /// file.
+    fn is_cold(&self, hash: &FileHash) -> bool {
+        self.file(hash).is_none() && self.has(hash)
+    }
+}
+
+/// Contents kept as plain files in a directory, in one of the layouts.
+pub struct FileStore {
+    path: PathBuf,
+    layout: Layout,
+    objects: TwoKeyMap<u64, FileHash, FileObject>,
+}
+
+impl FileStore {
+    /// Opens the store in the directory at the given path, reading the
+    /// inode of every file in it.
+    pub fn new(path: PathBuf, layout: Layout) -> Result<Self> {
+        let mut store = FileStore {
+            path,
+            layout,
+            objects: TwoKeyMap::new(),
+        };
+        for entry in WalkDir::new(&store.path) {
+            let entry = entry?;
+            if entry.path().is_file() && entry.path().extension().is_none() {
+                store.insert(entry.path())?;
+            }
+        }
+        Ok(store)
+    }
+
+    fn insert(&mut self, path: &Path) -> Result<()> {
+        let object = FileObject::new(path)?;
+        self.objects
+            .insert(object.ino()?, object.hash().clone(), object);
+        Ok(())
+    }
+
+    /// Returns where the contents with the given hash are, or would be
+    /// stored.
+    pub fn path_of(&self, hash: &FileHash) -> PathBuf {
+        match self.objects.get_alt(hash) {
+            Some(object) => object.path().to_path_buf(),
+            None => self.path.join(self.layout.relative(hash)),
+        }
+    }
+
+    /// Stores a copy of a file, or moves it if `remove` is set.
+    fn store(&mut self, path: &Path, hash: &FileHash, remove: bool) -> Result<()> {
+        if !self.has(hash) {
+            let dst = self.path_of(hash);
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent).with_path(parent)?;
+            }
+            if !remove || fs::rename(path, &dst).is_err() {
+                let tmp = dst.with_extension("tmp");
+                fs::copy(path, &tmp).with_path(path)?;
+                if FileHash::of(&tmp)? != *hash {
+                    fs::remove_file(&tmp).with_path(&tmp)?;
+                    bail!("{}: copy does not match the original", path.display());
+                }
+                fs::rename(&tmp, &dst).with_path(&dst)?;
+            }
+            self.insert(&dst)?;
+        }
+        if remove && path.exists() {
+            fs::remove_file(path).with_path(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn has(&self, hash: &FileHash) -> bool {
+        self.objects.get_alt(hash).is_some()
+    }
+
+    fn get(&self, hash: &FileHash, dst: &Path) -> Result<()> {
+        let src = self.path_of(hash);
+        let tmp = dst.with_extension("tmp");
+        fs::copy(&src, &tmp).with_path(&src)?;
+        if FileHash::of(&tmp)? != *hash {
+            fs::remove_file(&tmp).with_path(&tmp)?;
+            bail!("{}: copy does not match its hash", src.display());
+        }
+        fs::rename(&tmp, dst).with_path(dst)?;
+        Ok(())
+    }
+
+    fn put(&mut self, src: &Path, hash: &FileHash) -> Result<()> {
+        self.store(src, hash, false)
+    }
+
+    fn remove(&mut self, hash: &FileHash) -> Result<()> {
+        if let Some(object) = self.objects.remove_alt(hash) {
+            fs::remove_file(object.path()).with_path(object.path())?;
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<FileHash>> {
+        let hashes: HashSet<&FileHash> = self.objects.iter().map(|(_, (hash, _))| hash).collect();
+        Ok(hashes.into_iter().cloned().collect())
+    }
+
+    fn verify(&self, hash: &FileHash) -> Result<bool> {
+        match self.objects.get_alt(hash) {
+            Some(object) => Ok(FileHash::of(object.path()).ok().as_ref() == Some(hash)),
+            None => Ok(false),
+        }
+    }
+}
+
+impl LinkStore for FileStore {
+    fn file(&self, hash: &FileHash) -> Option<&FileObject> {
+        self.objects.get_alt(hash)
+    }
+
+    fn link(&mut self, path: &Path) -> Result<FileHash> {
+        let ino = path.metadata().with_path(path)?.ino();
+        if let Some(object) = self.objects.get(&ino) {
+            return Ok(object.hash().clone());
+        }
+
+        let hash = FileHash::of(path)?;
+        if !self.has(&hash) {
+            let dst = self.path_of(&hash);
+            hoard::link(path, &dst)?;
+            self.insert(&dst)?;
+        }
+        Ok(hash)
+    }
+
+    fn take(&mut self, path: &Path, hash: &FileHash) -> Result<()> {
+        self.store(path, hash, true)
+    }
+
+    fn link_target(&self, hash: &FileHash) -> PathBuf {
+        let path = self.path_of(hash);
+        let relative = path.strip_prefix(&self.path).unwrap_or(&path);
+        let dir = self.path.file_name().unwrap_or_default();
+        Path::new("..").join(dir).join(relative)
+    }
+
+    fn size(&self, hash: &FileHash) -> Result<u64> {
+        match self.objects.get_alt(hash) {
+            Some(object) => Ok(object.path().metadata().with_path(object.path())?.len()),
+            None => Ok(0),
+        }
+    }
+}
+
+/// Opens the object store of the hoard at the given root.
+pub fn local(root: &Path) -> Result<Box<dyn LinkStore>> {
+    Ok(Box::new(ObjectStore::new(root)?))
+}
+
+/// Opens the object store of the hoard at the given root, or the encrypted
+/// store, asking for its passphrase.
+pub fn open(root: &Path) -> Result<Box<dyn Store>> {
+    if crypt::is_encrypted(root) {
+        Ok(Box::new(EncryptedStore::unlock(root)?))
+    } else {
+        Ok(Box::new(ObjectStore::new(root)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn store_layout() {
+        let arg1 = FileHash::from_str(
+            &"e80aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7".to_string(),
+        )
+        .unwrap();
+
+        let fanout = Layout::Fanout.relative(&arg1);
+        let flat = Layout::Flat.relative(&arg1);
+
+        assert_eq!(
+            fanout,
+            Path::new("e8/0aa9815d6bf64ae4404af3a5e98250c9997c5ae01cfc7fd4c439c3644efae7")
+        );
+        assert_eq!(parse(&Path::new("by-hash").join(&fanout)).unwrap(), arg1);
+        assert_eq!(parse(&Path::new("by-hash").join(&flat)).unwrap(), arg1);
+        assert!(parse(Path::new("by-hash/e8")).is_err());
+        assert!(parse(Path::new("e80aa98")).is_err());
+    }
+
+    #[test]
+    fn store_file_store() {
+        let dir = env::temp_dir().join(format!("hoard-file-store-{}", process::id()));
+        let arg1 = dir.join("file.txt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&arg1, "contents").unwrap();
+        let hash = FileHash::of(&arg1).unwrap();
+
+        for layout in [Layout::Fanout, Layout::Flat].iter() {
+            let path = dir.join(layout.name());
+            fs::create_dir_all(&path).unwrap();
+            let mut store = FileStore::new(path.clone(), *layout).unwrap();
+            assert!(!store.has(&hash));
+
+            store.put(&arg1, &hash).unwrap();
+            assert!(path.join(layout.relative(&hash)).is_file());
+            assert!(store.verify(&hash).unwrap());
+            assert_eq!(store.list().unwrap(), vec![hash.clone()]);
+            assert_eq!(
+                store.link_target(&hash),
+                Path::new("..")
+                    .join(layout.name())
+                    .join(layout.relative(&hash))
+            );
+
+            let store = FileStore::new(path.clone(), *layout).unwrap();
+            let copy = dir.join("copy.txt");
+            store.get(&hash, &copy).unwrap();
+            assert_eq!(fs::read_to_string(&copy).unwrap(), "contents");
+
+            let mut store = store;
+            store.remove(&hash).unwrap();
+            assert!(!store.has(&hash));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
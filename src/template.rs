@@ -0,0 +1,200 @@
+//! Path templates rendered from the attributes of an object.
+//!
+//! A template is a path containing `{field}` placeholders, such as
+//! `{author}/{series|Standalone}/{title}.epub`. A placeholder can list
+//! several alternatives separated by `|`, which are tried in order as
+//! the names of attributes. When none of them are present, the last one
+//! is used as text. A placeholder without alternatives must be present
+//! for the template to be rendered.
+//!
+//! Values are sanitized so that they always form a single, portable path
+//! component: `/` becomes `-`, characters that are not allowed in file
+//! names on common filesystems become `_`, and leading or trailing dots
+//! and whitespace are removed.
+//!
+use query::Attributes;
+use Result;
+
+/// The longest file name, in bytes, allowed by common filesystems.
+const MAX_COMPONENT: usize = 255;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = input;
+        while let Some(start) = rest.find(&['{', '}'][..]) {
+            if rest[start..].starts_with('}') {
+                bail!("unmatched '}}' in template '{}'", input);
+            }
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("unclosed '{{' in template '{}'", input),
+            };
+            let field = &rest[start + 1..end];
+            if field.contains('{') {
+                bail!("nested '{{' in template '{}'", input);
+            }
+            let alternatives: Vec<_> = field.split('|').map(|s| s.trim().to_string()).collect();
+            if alternatives[..alternatives.len() - 1]
+                .iter()
+                .chain(alternatives.first())
+                .any(|key| key.is_empty())
+            {
+                bail!("empty field in template '{}'", input);
+            }
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            parts.push(Part::Field(alternatives));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Renders the template, or returns `None` if a required field is
+    /// missing.
+    pub fn render(&self, attributes: &Attributes) -> Option<String> {
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match *part {
+                Part::Text(ref text) => out.push_str(text),
+                Part::Field(ref alternatives) => {
+                    let default = match alternatives.as_slice() {
+                        [_, .., last] => Some(sanitize(last)),
+                        _ => None,
+                    };
+                    let value = alternatives
+                        .iter()
+                        .filter_map(|key| attributes.first(key))
+                        .map(sanitize)
+                        .find(|value| !value.is_empty())
+                        .or(default)?;
+                    out.push_str(&value);
+                }
+            }
+        }
+        Some(truncate(&out))
+    }
+}
+
+/// Makes a value safe to use as a single path component.
+pub fn sanitize(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            '/' => '-',
+            '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    value
+        .trim_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string()
+}
+
+/// Shortens every component of a path to the longest allowed file name,
+/// keeping the extension of the last one.
+fn truncate(path: &str) -> String {
+    fn shorten(text: &str, len: usize) -> &str {
+        let mut end = len.min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        &text[..end]
+    }
+
+    let components: Vec<_> = path.split('/').collect();
+    let last = components.len() - 1;
+    let components: Vec<_> = components
+        .iter()
+        .enumerate()
+        .map(|(i, component)| {
+            if component.len() <= MAX_COMPONENT {
+                return component.to_string();
+            }
+            match component.rfind('.') {
+                Some(dot) if i == last && component.len() - dot < 16 => {
+                    let extension = &component[dot..];
+                    let stem = shorten(component, MAX_COMPONENT - extension.len());
+                    format!("{}{}", stem, extension)
+                }
+                _ => shorten(component, MAX_COMPONENT).to_string(),
+            }
+        })
+        .collect();
+    components.join("/")
+}
+
+/// Returns the path with ` (n)` inserted before the extension of its
+/// file name, to tell apart objects rendered to the same path.
+pub fn suffix(path: &str, n: usize) -> String {
+    let start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match path[start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let dot = start + dot;
+            format!("{} ({}){}", &path[..dot], n, &path[dot..])
+        }
+        _ => format!("{} ({})", path, n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_parse_invalid() {
+        assert!(Template::parse("{author").is_err());
+        assert!(Template::parse("author}").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{|Unknown}").is_err());
+    }
+
+    #[test]
+    fn template_render() {
+        let arg1 = Template::parse("{author}/{series|Standalone}/{title|name}.epub").unwrap();
+        let mut arg2 = Attributes::new();
+        arg2.insert("author", "J. R. R. Tolkien");
+        arg2.insert("name", "Either/Or: \"Quoted\"?");
+
+        let result = arg1.render(&arg2);
+
+        assert_eq!(
+            result.unwrap(),
+            "J. R. R. Tolkien/Standalone/Either-Or_ _Quoted__.epub"
+        );
+    }
+
+    #[test]
+    fn template_render_missing() {
+        let arg1 = Template::parse("{author}/{title}.epub").unwrap();
+        let mut arg2 = Attributes::new();
+        arg2.insert("title", "The Hobbit");
+
+        let result = arg1.render(&arg2);
+
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn template_suffix() {
+        assert_eq!(suffix("a/b.c/title.epub", 2), "a/b.c/title (2).epub");
+        assert_eq!(suffix("a/b.c/title", 3), "a/b.c/title (3)");
+        assert_eq!(suffix(".hidden", 2), ".hidden (2)");
+    }
+}
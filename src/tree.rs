@@ -0,0 +1,439 @@
+//! A compact manifest format laid out by directory.
+//!
+//! Directories end in `/` and contain the entries indented below them.
+//! An entry is the name of an object linked under its own name, or
+//! `file = name` when the link has a different file name:
+//!
+//! ```text
+//! # Comments start with '#'.
+//! path1/
+//!   item-name-1
+//!   item-name-2
+//! path2/
+//!   sub/
+//!     cover.jpg = item-name-1
+//! @rule unread/ tag:unread
+//! ```
+//!
+//! Rules are written as `@rule DIR QUERY` and views as `@view PATH QUERY`,
+//! where the directory or path template is quoted if it contains
+//! whitespace. Objects that should not be linked anywhere are written as
+//! `@unlinked NAME`, and other manifests are included with
+//! `@include PATH`. The schema version is given by `@version N`.
+//!
+//! File, directory and object names are quoted when they would be read
+//! as something else, such as when they start with `#`, `@` or `"`, or
+//! contain ` = `, and so are the paths of directives. Within quotes, `\`
+//! escapes the next character:
+//!
+//! ```text
+//! "#1.jpg" = "cover = front"
+//! ```
+//!
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use manifest::{Manifest, Rule, VERSION};
+
+type ParseResult<T> = ::std::result::Result<T, (usize, usize, String)>;
+
+/// An entry for an object, and the line it was read from.
+pub struct Entry {
+    pub line: usize,
+    pub name: String,
+    /// The file name as written and the path it gives, or none for
+    /// `@unlinked`.
+    pub file: Option<(String, PathBuf)>,
+}
+
+/// Parses a manifest, returning the line, column and message of the
+/// error on failure.
+pub fn parse(text: &str) -> ParseResult<Manifest> {
+    parse_entries(text).map(|(manifest, _)| manifest)
+}
+
+/// Parses a manifest, along with the entry of every object.
+pub fn parse_entries(text: &str) -> ParseResult<(Manifest, Vec<Entry>)> {
+    let mut manifest = Manifest::default();
+    let mut entries = Vec::new();
+    // The indentation and path of each enclosing directory.
+    let mut dirs: Vec<(usize, PathBuf)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let content = line.trim_start();
+        let indent = line.len() - content.len();
+        let content = content.trim_end();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rule) = directive(content, "@rule") {
+            let (dir, query) =
+                argument(rule.trim()).map_err(|message| (number, indent + 1, message))?;
+            if dir.is_empty() {
+                return Err((number, indent + 1, "missing directory for rule".to_string()));
+            }
+            manifest.rules.push(Rule {
+                dir,
+                query: query.to_string(),
+                ..Rule::default()
+            });
+            continue;
+        }
+
+        if let Some(view) = directive(content, "@view") {
+            let (path, query) =
+                argument(view.trim()).map_err(|message| (number, indent + 1, message))?;
+            if path.is_empty() {
+                return Err((number, indent + 1, "missing path for view".to_string()));
+            }
+            manifest.rules.push(Rule {
+                path,
+                query: query.to_string(),
+                ..Rule::default()
+            });
+            continue;
+        }
+
+        if let Some(version) = directive(content, "@version") {
+            manifest.version = match version.trim().parse() {
+                Ok(version) if version <= VERSION => Some(version),
+                _ => {
+                    let message = format!("unsupported manifest version '{}'", version.trim());
+                    return Err((number, indent + 1, message));
+                }
+            };
+            continue;
+        }
+
+        if let Some(include) = directive(content, "@include") {
+            let include = match include.trim() {
+                "" => return Err((number, indent + 1, "missing path to include".to_string())),
+                include => token(include).map_err(|message| (number, indent + 1, message))?,
+            };
+            manifest.include.push(PathBuf::from(include));
+            continue;
+        }
+
+        if let Some(name) = directive(content, "@unlinked") {
+            let name = match name.trim() {
+                "" => return Err((number, indent + 1, "missing object name".to_string())),
+                name => token(name).map_err(|message| (number, indent + 1, message))?,
+            };
+            manifest.objects.entry(name.to_string()).or_default();
+            entries.push(Entry {
+                line: number,
+                name: name.to_string(),
+                file: None,
+            });
+            continue;
+        }
+
+        while dirs.last().map(|&(i, _)| i >= indent).unwrap_or(false) {
+            dirs.pop();
+        }
+        let parent = dirs
+            .last()
+            .map(|(_, path)| path.clone())
+            .unwrap_or_default();
+
+        if let Some(dir) = content.strip_suffix('/') {
+            let dir = token(dir).map_err(|message| (number, indent + 1, message))?;
+            dirs.push((indent, parent.join(dir.trim_end_matches('/'))));
+            continue;
+        }
+
+        let (file, name) = split(content).map_err(|message| (number, indent + 1, message))?;
+
+        manifest
+            .objects
+            .entry(name.clone())
+            .or_default()
+            .insert(parent.join(&file));
+        entries.push(Entry {
+            line: number,
+            name,
+            file: Some((file.clone(), parent.join(file))),
+        });
+    }
+
+    Ok((manifest, entries))
+}
+
+/// Returns the rest of the line when it starts with the directive, which
+/// is followed by whitespace unless it is alone.
+fn directive<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    content
+        .strip_prefix(name)
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+/// Splits an entry into its file and object names.
+fn split(content: &str) -> ::std::result::Result<(String, String), String> {
+    let expected = || format!("expected 'file = name', found '{}'", content);
+    let padded = format!(" {} ", content);
+    let (file, rest) = if content.starts_with('"') {
+        let (file, rest) = unquote(content)?;
+        (file, format!(" {} ", rest))
+    } else {
+        // A quoted name starts at the first ` = "`, as a bare file name
+        // cannot contain ` = `.
+        let end = if content.ends_with('"') {
+            padded.find(" = \"")
+        } else {
+            padded.rfind(" = ")
+        };
+        match end {
+            Some(end) => (padded[..end].trim().to_string(), padded[end..].to_string()),
+            None => (content.to_string(), String::new()),
+        }
+    };
+
+    let rest = rest.trim();
+    let name = if rest.is_empty() {
+        file.clone()
+    } else {
+        match rest.strip_prefix('=') {
+            Some(name) if !name.trim().is_empty() => token(name.trim())?,
+            _ => return Err(expected()),
+        }
+    };
+    if file.is_empty() {
+        return Err(expected());
+    }
+    Ok((file, name))
+}
+
+/// Reads a name, which is the whole text unless it is quoted.
+fn token(text: &str) -> ::std::result::Result<String, String> {
+    if !text.starts_with('"') {
+        return Ok(text.to_string());
+    }
+    match unquote(text)? {
+        (token, "") => Ok(token),
+        (_, rest) => Err(format!("unexpected '{}' after quoted name", rest)),
+    }
+}
+
+/// Reads the first argument of a directive, which is quoted or ends at
+/// whitespace, returning it and the rest of the text.
+fn argument(text: &str) -> ::std::result::Result<(String, &str), String> {
+    if text.starts_with('"') {
+        return unquote(text);
+    }
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    Ok((text[..end].to_string(), text[end..].trim()))
+}
+
+/// Reads a quoted name from the start of the text, returning it and the
+/// rest of the text.
+fn unquote(text: &str) -> ::std::result::Result<(String, &str), String> {
+    let mut token = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => token.extend(chars.next().map(|(_, c)| c)),
+            '"' => return Ok((token, text[i + 1..].trim())),
+            c => token.push(c),
+        }
+    }
+    Err("unclosed '\"' in name".to_string())
+}
+
+/// Quotes a name when it would otherwise be read as something else.
+pub fn quote(name: &str) -> String {
+    let is_plain = !name.is_empty()
+        && !name.starts_with(['#', '@', '"'])
+        && !name.ends_with(['/', '"'])
+        && name.trim() == name
+        && !format!(" {} ", name).contains(" = ");
+    if is_plain {
+        return name.to_string();
+    }
+    escape(name)
+}
+
+/// Quotes the first argument of a directive when it would not be read
+/// back as one word.
+fn quote_argument(text: &str) -> String {
+    if text.is_empty() || text.starts_with('"') || text.contains(char::is_whitespace) {
+        escape(text)
+    } else {
+        text.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+/// Writes the entry linking an object to a file in its directory.
+pub fn entry(file: &str, name: &str) -> String {
+    if file == name {
+        quote(file)
+    } else {
+        format!("{} = {}", quote(file), quote(name))
+    }
+}
+
+#[derive(Default)]
+struct Dir<'a> {
+    files: BTreeMap<String, &'a str>,
+    dirs: BTreeMap<String, Dir<'a>>,
+}
+
+impl<'a> Dir<'a> {
+    fn insert(&mut self, path: &Path, name: &'a str) {
+        let mut dir = self;
+        if let Some(parent) = path.parent() {
+            for component in parent.iter() {
+                dir = dir
+                    .dirs
+                    .entry(component.to_string_lossy().to_string())
+                    .or_default();
+            }
+        }
+        if let Some(file) = path.file_name() {
+            dir.files.insert(file.to_string_lossy().to_string(), name);
+        }
+    }
+
+    fn write(&self, out: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth);
+        for (file, name) in self.files.iter() {
+            out.push_str(&format!("{}{}\n", indent, entry(file, name)));
+        }
+        for (name, dir) in self.dirs.iter() {
+            out.push_str(&format!("{}{}/\n", indent, quote(name)));
+            dir.write(out, depth + 1);
+        }
+    }
+}
+
+/// Writes the manifest as a directory tree.
+pub fn to_string(manifest: &Manifest) -> String {
+    let mut root = Dir::default();
+    let mut unlinked = Vec::new();
+    for (name, paths) in manifest.objects.iter() {
+        if paths.is_empty() {
+            unlinked.push(name);
+        }
+        for path in paths.iter() {
+            root.insert(path, name);
+        }
+    }
+
+    let mut out = String::new();
+    if let Some(version) = manifest.version {
+        out.push_str(&format!("@version {}\n", version));
+    }
+    for include in manifest.include.iter() {
+        out.push_str(&format!("@include {}\n", quote(&include.to_string_lossy())));
+    }
+    root.write(&mut out, 0);
+    for name in unlinked {
+        out.push_str(&format!("@unlinked {}\n", quote(name)));
+    }
+    for rule in manifest.rules.iter() {
+        let line = if rule.path.is_empty() {
+            format!("@rule {} {}", quote_argument(&rule.dir), rule.query)
+        } else {
+            format!("@view {} {}", quote_argument(&rule.path), rule.query)
+        };
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn tree_parse_success() {
+        let arg1 = fs::read_to_string("test/res/tree_parse/success/test1.tree").unwrap();
+
+        let result = parse(&arg1).unwrap();
+
+        assert_eq!(result.objects.len(), 4);
+        assert_eq!(result.objects["item1"].len(), 3);
+        assert!(result.objects["item1"].contains(Path::new("path1/nested/deeper/cover.jpg")));
+        assert!(result.objects["item3"].contains(Path::new("item3")));
+        assert!(result.objects["item4"].is_empty());
+        assert_eq!(result.rules[0].query, "tag:unread");
+        assert_eq!(result.rules[1].path, "library/{author}/{title|name}.epub");
+        assert_eq!(result.rules[1].query, "ebook");
+    }
+
+    #[test]
+    fn tree_parse_invalid() {
+        let arg1 = fs::read_to_string("test/res/tree_parse/invalid/test1.tree").unwrap();
+
+        let result = parse(&arg1);
+
+        assert_eq!(result.unwrap_err().0, 3);
+    }
+
+    #[test]
+    fn tree_parse_quoted() {
+        let arg1 = "@rulebook.txt = @rulebook\n\"#1.jpg\" = \"cover = \\\"front\\\"\"\n\"#dir\"/\n  x = \"#x\"\n";
+
+        let result = parse(arg1).unwrap();
+
+        assert!(result.rules.is_empty());
+        assert!(result.objects["@rulebook"].contains(Path::new("@rulebook.txt")));
+        assert!(result.objects["cover = \"front\""].contains(Path::new("#1.jpg")));
+        assert!(result.objects["#x"].contains(Path::new("#dir/x")));
+    }
+
+    #[test]
+    fn tree_to_string_roundtrip() {
+        let arg1 = fs::read_to_string("test/res/tree_parse/success/test1.tree").unwrap();
+        let arg1 = parse(&arg1).unwrap();
+
+        let result = parse(&to_string(&arg1)).unwrap();
+
+        assert_eq!(result, arg1);
+
+        let mut arg2 = Manifest::default();
+        let names = [
+            "#1",
+            "@rule",
+            "a = b",
+            " padded",
+            "\"quoted\"",
+            "back\\slash",
+            "x =",
+        ];
+        for name in names.iter() {
+            let paths = [format!("{}/{}", name, name), format!("d/{}.txt", name)];
+            arg2.objects
+                .insert(name.to_string(), paths.iter().map(PathBuf::from).collect());
+        }
+        arg2.objects
+            .insert("@unlinked".to_string(), Default::default());
+        let args = ["my books/", "\"quoted\"/", "back\\slash \"/", "plain/"];
+        for arg in args.iter() {
+            arg2.rules.push(Rule {
+                dir: arg.to_string(),
+                query: "tag:unread".to_string(),
+                ..Rule::default()
+            });
+            arg2.rules.push(Rule {
+                path: format!("{}{{name}}.epub", arg),
+                query: "ebook".to_string(),
+                ..Rule::default()
+            });
+            arg2.include.push(PathBuf::from(format!("{}more.tree", arg)));
+        }
+        arg2.include.push(PathBuf::from(" padded.tree "));
+
+        let result = parse(&to_string(&arg2)).unwrap();
+
+        assert_eq!(result, arg2);
+    }
+}
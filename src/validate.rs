@@ -0,0 +1,300 @@
+//! Checks a manifest for mistakes before it is applied.
+//!
+//! Every problem is reported as a `Diagnostic` pointing at the file,
+//! line and column it was found at, so that all of them can be fixed
+//! in one pass instead of one error at a time.
+//!
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use error::LocatedError;
+use manifest::{Format, Manifest, Source};
+use query::Query;
+use tree::{self, Entry};
+use Result;
+
+/// A problem found in a manifest file.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.message
+        )
+    }
+}
+
+/// Checks the manifest at the given path, along with everything it
+/// includes and the fragments in the working tree, against the names
+/// of the objects in the hoard.
+pub fn validate<P, R>(path: P, root: R, names: &HashSet<String>) -> Result<Vec<Diagnostic>>
+where
+    P: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let sources = Manifest::sources(path, root)?;
+    let mut diagnostics = Vec::new();
+    // Every normalized path, with the name, source and text claiming it.
+    let mut claims: BTreeMap<PathBuf, Vec<(&str, usize, &Path)>> = BTreeMap::new();
+    let mut texts = Vec::new();
+
+    for source in sources.iter() {
+        let text = fs::read_to_string(&source.path).unwrap_or_default();
+        let entries = match Format::from_path(&source.path) {
+            Ok(Format::Tree) => tree::parse_entries(&text)
+                .map(|(_, entries)| entries)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        texts.push(Text { text, entries });
+    }
+
+    for (i, source) in sources.iter().enumerate() {
+        let manifest = match source.manifest {
+            Ok(ref manifest) => manifest,
+            Err(ref e) => {
+                diagnostics.push(match e.downcast_ref::<LocatedError>() {
+                    Some(e) => Diagnostic {
+                        file: source.path.clone(),
+                        line: e.line,
+                        column: e.column,
+                        message: e.message.clone(),
+                    },
+                    None => diagnose(source, &texts[i].text, "", e.to_string()),
+                });
+                continue;
+            }
+        };
+
+        for (name, paths) in manifest.objects.iter() {
+            if !names.contains(name) {
+                let message = format!("no such object '{}'", name);
+                diagnostics.push(diagnose_entry(source, &texts[i], name, None, message));
+            }
+            for path in paths.iter() {
+                match check_path(&source.prefix, path) {
+                    Ok(normal) => claims.entry(normal).or_default().push((name, i, path)),
+                    Err(message) => {
+                        let diagnostic =
+                            diagnose_entry(source, &texts[i], name, Some(path), message);
+                        diagnostics.push(diagnostic);
+                    }
+                }
+            }
+        }
+
+        for rule in manifest.rules.iter() {
+            let target = rule.target();
+            if let Err(message) = check_path(&source.prefix, Path::new(target)) {
+                diagnostics.push(diagnose(source, &texts[i].text, target, message));
+            }
+            if let Err(e) = rule.template() {
+                diagnostics.push(diagnose(source, &texts[i].text, target, e.to_string()));
+            }
+            if let Err(e) = Query::parse(&rule.query) {
+                let message = e.to_string();
+                diagnostics.push(diagnose(source, &texts[i].text, &rule.query, message));
+            }
+        }
+    }
+
+    for (normal, claimants) in claims.iter() {
+        let names: BTreeSet<_> = claimants.iter().map(|&(name, _, _)| name).collect();
+        if names.len() < 2 {
+            continue;
+        }
+        let names: Vec<_> = names.into_iter().collect();
+        for &(name, i, path) in claimants.iter() {
+            let message = format!(
+                "duplicate path '{}' for entries: {}",
+                normal.display(),
+                names.join(", ")
+            );
+            let diagnostic = diagnose_entry(&sources[i], &texts[i], name, Some(path), message);
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    diagnostics.sort();
+    diagnostics.dedup();
+    Ok(diagnostics)
+}
+
+/// The contents of a manifest file.
+struct Text {
+    text: String,
+    /// The entries of a tree manifest, whose paths are spread over the
+    /// lines of their directories rather than written out in full.
+    entries: Vec<Entry>,
+}
+
+/// Points at the entry of the named object, or at the given path of it,
+/// which is searched for in the text unless the file is a tree manifest.
+fn diagnose_entry(
+    source: &Source,
+    text: &Text,
+    name: &str,
+    path: Option<&Path>,
+    message: String,
+) -> Diagnostic {
+    let entry = text.entries.iter().find(|entry| {
+        let file = entry.file.as_ref().map(|(_, file)| file.as_path());
+        entry.name == name && path.map(|path| file == Some(path)).unwrap_or(true)
+    });
+    let entry = match entry {
+        Some(entry) => entry,
+        None => {
+            let needle = path
+                .map(|path| path.to_string_lossy())
+                .unwrap_or(name.into());
+            return diagnose(source, &text.text, &needle, message);
+        }
+    };
+    let line = text.text.lines().nth(entry.line - 1).unwrap_or("");
+    let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+    Diagnostic {
+        file: source.path.clone(),
+        line: entry.line,
+        column: indent + 1,
+        message,
+    }
+}
+
+fn diagnose(source: &Source, text: &str, needle: &str, message: String) -> Diagnostic {
+    let (line, column) = locate(text, needle);
+    Diagnostic {
+        file: source.path.clone(),
+        line,
+        column,
+        message,
+    }
+}
+
+/// Checks that a path from a manifest stays inside the working tree,
+/// returning it relative to the root with `.` and `..` resolved.
+fn check_path(prefix: &Path, path: &Path) -> ::std::result::Result<PathBuf, String> {
+    if path.is_absolute() {
+        return Err(format!("absolute path '{}'", path.display()));
+    }
+
+    let mut normal = PathBuf::new();
+    for component in prefix.join(path).components() {
+        match component {
+            Component::Normal(part) => normal.push(part),
+            Component::ParentDir if !normal.pop() => {
+                return Err(format!("path '{}' escapes the hoard", path.display()));
+            }
+            _ => {}
+        }
+    }
+
+    if normal.starts_with(".hoard") {
+        return Err(format!("path '{}' is inside .hoard", path.display()));
+    }
+    Ok(normal)
+}
+
+/// Finds the 1-based line and column of the first occurrence of the
+/// needle that is not part of a longer word, falling back to the start
+/// of the file.
+fn locate(text: &str, needle: &str) -> (usize, usize) {
+    fn is_boundary(c: Option<char>) -> bool {
+        c.map(|c| c.is_whitespace() || "\"'[],:{}=".contains(c))
+            .unwrap_or(true)
+    }
+
+    if needle.is_empty() {
+        return (1, 1);
+    }
+
+    for (offset, _) in text.match_indices(needle) {
+        let before = text[..offset].chars().next_back();
+        let after = text[offset + needle.len()..].chars().next();
+        if is_boundary(before) && is_boundary(after) {
+            let line = text[..offset].matches('\n').count() + 1;
+            let start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+            return (line, text[start..offset].chars().count() + 1);
+        }
+    }
+    (1, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_success() {
+        let arg1 = "test/res/manifest_load/success/.hoard/manifest.json";
+        let arg2 = "test/res/manifest_load/success";
+        let arg3: HashSet<_> = ["item1", "item2", "item3"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        let result = validate(arg1, arg2, &arg3).unwrap();
+
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn validate_invalid() {
+        let arg1 = "test/res/validate/invalid/.hoard/manifest.json";
+        let arg2 = "test/res/validate/invalid";
+        let arg3: HashSet<_> = ["item1", "item2"].iter().map(|name| name.to_string()).collect();
+
+        let result = validate(arg1, arg2, &arg3).unwrap();
+
+        let result: Vec<_> = result
+            .iter()
+            .map(|d| (d.line, d.column, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            result,
+            vec![
+                (3, 4, "no such object 'item3'"),
+                (5, 6, "absolute path '/etc/item1'"),
+                (6, 6, "path '../item1' escapes the hoard"),
+                (7, 6, "path '.hoard/item1' is inside .hoard"),
+                (8, 6, "duplicate path 'path1/item' for entries: item1, item2"),
+                (11, 6, "duplicate path 'path1/item' for entries: item1, item2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_invalid_tree() {
+        let arg1 = "test/res/validate/invalid_tree/.hoard/manifest.tree";
+        let arg2 = "test/res/validate/invalid_tree";
+        let arg3: HashSet<_> = ["item1", "item2"].iter().map(|name| name.to_string()).collect();
+
+        let result = validate(arg1, arg2, &arg3).unwrap();
+
+        let result: Vec<_> = result
+            .iter()
+            .map(|d| (d.line, d.column, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            result,
+            vec![
+                (2, 1, "no such object 'item3'"),
+                (4, 3, "path '.hoard/item1' is inside .hoard"),
+                (6, 3, "duplicate path 'path1/item' for entries: item1, item2"),
+                (8, 3, "duplicate path 'path1/item' for entries: item1, item2"),
+            ]
+        );
+    }
+}
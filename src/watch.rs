@@ -0,0 +1,619 @@
+//! Follows the working tree of a hoard with inotify, for `watch`.
+//!
+//! New files are added once they are stable, which is when they have
+//! been closed after writing and then left alone for a quiet period.
+//! Files moved into the tree from elsewhere are new files as well, while
+//! moving or renaming a file linked to an object is recorded in the
+//! file of the manifest that lists it, as described in the `edit`
+//! module. Moving a directory records the moves of every object linked
+//! inside it. The manifest is then kept in the history described in the
+//! `history` module.
+//!
+//! Paths matching a pattern in `.hoardignore`, at the root of the hoard,
+//! are left alone, as are the `.hoard` directory and manifest fragments.
+//! In a pattern, `*` and `?` match within a name and `**` matches across
+//! directories. A pattern containing a `/` is relative to the root, and
+//! otherwise matches a name at any depth. A trailing `/` only matches
+//! directories, and lines starting with `#` are comments:
+//!
+//! ```text
+//! # downloads in progress
+//! *.part
+//! /incoming/
+//! ```
+//!
+//! Watching stops on SIGINT or SIGTERM, once the changes seen so far are
+//! recorded.
+//!
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use regex::{self, Regex};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use walkdir::WalkDir;
+
+use edit::{self, Owner};
+use error::ResultExt;
+use history;
+use hoard::Repository;
+use manifest::Manifest;
+use state::{Index, State};
+use Result;
+
+/// The number of seconds a new file has to be left alone before it is
+/// added.
+pub const QUIET: u64 = 2;
+
+/// How long to wait when there are no events, before checking for
+/// stable files again.
+const TICK: Duration = Duration::from_millis(200);
+
+/// The patterns of a `.hoardignore` file.
+#[derive(Debug, Default)]
+pub struct Ignore {
+    /// Every pattern, and whether it only matches directories.
+    patterns: Vec<(Regex, bool)>,
+}
+
+impl Ignore {
+    /// Reads the `.hoardignore` file at the root of a hoard, if any.
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join(".hoardignore");
+        if !path.exists() {
+            return Ok(Ignore::default());
+        }
+        Ignore::parse(&fs::read_to_string(&path).with_path(&path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let dir_only = line.ends_with('/');
+            let pattern = line.trim_end_matches('/');
+            let mut regex = if pattern.contains('/') {
+                "^".to_string()
+            } else {
+                "(^|/)".to_string()
+            };
+            let mut chars = pattern.trim_start_matches('/').chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '*' if chars.peek() == Some(&'*') => {
+                        chars.next();
+                        regex.push_str(".*");
+                    }
+                    '*' => regex.push_str("[^/]*"),
+                    '?' => regex.push_str("[^/]"),
+                    c => regex.push_str(&regex::escape(&c.to_string())),
+                }
+            }
+            regex.push('$');
+            patterns.push((Regex::new(&regex)?, dir_only));
+        }
+        Ok(Ignore { patterns })
+    }
+
+    /// Whether the path, relative to the root, or a directory it is in,
+    /// is ignored.
+    pub fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        let mut is_dir = is_dir;
+        for path in relative.ancestors() {
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => break,
+            };
+            if name == ".hoard" || name == ".hoardignore" || name.starts_with(".hoard.") {
+                return true;
+            }
+            let text = path.to_string_lossy();
+            let matched = self
+                .patterns
+                .iter()
+                .any(|(regex, dir_only)| (is_dir || !dir_only) && regex.is_match(&text));
+            if matched {
+                return true;
+            }
+            is_dir = true;
+        }
+        false
+    }
+}
+
+/// A change to the working tree that is recorded in the manifest.
+enum Record {
+    Added {
+        name: String,
+        path: PathBuf,
+    },
+    Moved {
+        name: String,
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+struct Watcher<'a> {
+    repo: &'a mut Repository,
+    root: PathBuf,
+    ignore: Ignore,
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    /// The files that aren't objects yet, by when they last changed.
+    pending: HashMap<PathBuf, Instant>,
+    /// The paths moved away from, by the cookie of the move, until the
+    /// path they were moved to is known.
+    moved: HashMap<u32, (PathBuf, bool, Instant)>,
+    /// The objects found in directories that weren't watched yet.
+    unseen: Vec<(String, PathBuf)>,
+    records: Vec<Record>,
+    index: Option<Index>,
+    quiet: Duration,
+}
+
+/// Watches the working tree of the hoard until SIGINT or SIGTERM, adding
+/// new files once they have been left alone for the quiet period, and
+/// recording moves in the manifest.
+pub fn watch(repo: &mut Repository, quiet: Duration) -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    flag::register(SIGINT, Arc::clone(&stop))?;
+    flag::register(SIGTERM, Arc::clone(&stop))?;
+
+    let path = repo.manifest_path();
+    if !path.exists() {
+        repo.snapshot()?.to_file(&path)?;
+    }
+    let root = repo.root().to_path_buf();
+    let mut watcher = Watcher {
+        ignore: Ignore::load(&root)?,
+        inotify: Inotify::init()?,
+        dirs: HashMap::new(),
+        pending: HashMap::new(),
+        moved: HashMap::new(),
+        unseen: Vec::new(),
+        records: Vec::new(),
+        index: None,
+        repo,
+        root,
+        quiet,
+    };
+    let root = watcher.root.clone();
+    watcher.watch_tree(&root, false)?;
+    println!("watching {}", root.display());
+
+    let mut buffer = [0u8; 16 * 1024];
+    while !stop.load(Ordering::SeqCst) {
+        let events = watcher.read(&mut buffer)?;
+        if events.is_empty() {
+            thread::sleep(TICK);
+        } else {
+            // Objects may have been added or linked by other commands.
+            watcher.index = None;
+        }
+        for (path, mask, cookie) in events {
+            watcher.handle(path, mask, cookie)?;
+        }
+        watcher.moved_unseen()?;
+        watcher.moved_away()?;
+        watcher.add_stable()?;
+        watcher.record()?;
+    }
+
+    if !watcher.pending.is_empty() {
+        eprintln!(
+            "warning: {} new file(s) were still changing, and were not added",
+            watcher.pending.len()
+        );
+    }
+    Ok(())
+}
+
+impl<'a> Watcher<'a> {
+    /// Watches the directory and every directory in it that isn't
+    /// ignored. If `scan` is set, the files already in them are new
+    /// files, as they were written before they could be watched.
+    fn watch_tree(&mut self, dir: &Path, scan: bool) -> Result<()> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        let root = &self.root;
+        let ignore = &self.ignore;
+        let entries = WalkDir::new(dir).into_iter().filter_entry(|entry| {
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            !ignore.matches(relative, entry.file_type().is_dir())
+        });
+        let mut found = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                let wd = self
+                    .inotify
+                    .watches()
+                    .add(entry.path(), mask)
+                    .with_path(entry.path())?;
+                self.dirs.insert(wd, entry.path().to_path_buf());
+            } else if scan {
+                found.push((entry.path().to_path_buf(), entry.file_type().is_file()));
+            }
+        }
+        for (path, is_file) in found {
+            match self.object_at(&path)? {
+                Some(name) => self.unseen.push((name, path)),
+                None if is_file => {
+                    self.pending.insert(path, Instant::now());
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Pairs the objects found in directories before they could be
+    /// watched with the paths the manifest gives them that they were
+    /// moved away from, as their moves into the directories went unseen.
+    fn moved_unseen(&mut self) -> Result<()> {
+        if self.unseen.is_empty() {
+            return Ok(());
+        }
+        let manifest = Manifest::from_file(self.repo.manifest_path())?;
+        for (name, to) in mem::take(&mut self.unseen) {
+            if let Some(paths) = manifest.objects.get(&name) {
+                self.moved_from(name, paths, to);
+            }
+        }
+        Ok(())
+    }
+
+    fn moved_from(&mut self, name: String, paths: &BTreeSet<PathBuf>, to: PathBuf) {
+        let root = &self.root;
+        let cookie = self
+            .moved
+            .iter()
+            .find(|&(_, &(ref from, is_dir, _))| {
+                !is_dir && paths.contains(from.strip_prefix(root).unwrap_or(from))
+            })
+            .map(|(cookie, _)| *cookie);
+        if let Some(cookie) = cookie {
+            let (from, _, _) = self.moved.remove(&cookie).unwrap();
+            self.records.push(Record::Moved { name, from, to });
+        }
+    }
+
+    /// Returns the path, kind and cookie of the events that happened
+    /// since the last read.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<Vec<(PathBuf, EventMask, u32)>> {
+        let events = match self.inotify.read_events(buffer) {
+            Ok(events) => events,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut read = Vec::new();
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                eprintln!("warning: too many changes at once, some were missed");
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                self.dirs.remove(&event.wd);
+                continue;
+            }
+            let path = match (self.dirs.get(&event.wd), event.name) {
+                (Some(dir), Some(name)) => dir.join(name),
+                _ => continue,
+            };
+            read.push((path, event.mask, event.cookie));
+        }
+        Ok(read)
+    }
+
+    fn handle(&mut self, path: PathBuf, mask: EventMask, cookie: u32) -> Result<()> {
+        let is_dir = mask.contains(EventMask::ISDIR);
+        let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+        if self.ignore.matches(relative, is_dir) {
+            return Ok(());
+        }
+
+        if mask.contains(EventMask::CREATE) && is_dir {
+            self.watch_tree(&path, true)?;
+        } else if mask.contains(EventMask::CLOSE_WRITE) {
+            self.pending.insert(path, Instant::now());
+        } else if mask.intersects(EventMask::CREATE | EventMask::MODIFY) {
+            if let Some(time) = self.pending.get_mut(&path) {
+                *time = Instant::now();
+            }
+        } else if mask.contains(EventMask::DELETE) {
+            self.pending.remove(&path);
+        } else if mask.contains(EventMask::MOVED_FROM) {
+            self.moved.insert(cookie, (path, is_dir, Instant::now()));
+        } else if mask.contains(EventMask::MOVED_TO) {
+            match self.moved.remove(&cookie) {
+                Some((from, _, _)) => self.moved_within(&from, &path, is_dir)?,
+                None if is_dir => self.watch_tree(&path, true)?,
+                None => {
+                    self.pending.insert(path, Instant::now());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the object linked at the path, if any.
+    fn object_at(&mut self, path: &Path) -> Result<Option<String>> {
+        if self.index.is_none() {
+            self.index = Some(Index::from(&self.root)?);
+        }
+        let index = self.index.as_ref().unwrap();
+        let object = self.repo.object(index, &path.to_string_lossy());
+        Ok(object.ok().map(|object| object.name().to_string()))
+    }
+
+    /// Follows a file or directory moved from one place in the working
+    /// tree to another.
+    fn moved_within(&mut self, from: &Path, to: &Path, is_dir: bool) -> Result<()> {
+        let rebase = |path: &Path| path.strip_prefix(from).ok().map(|rest| to.join(rest));
+        if !is_dir {
+            match self.pending.remove(from) {
+                Some(time) => {
+                    self.pending.insert(to.to_path_buf(), time);
+                }
+                None => match self.object_at(to)? {
+                    Some(name) => self.records.push(Record::Moved {
+                        name,
+                        from: from.to_path_buf(),
+                        to: to.to_path_buf(),
+                    }),
+                    None => {
+                        self.pending.insert(to.to_path_buf(), Instant::now());
+                    }
+                },
+            }
+            return Ok(());
+        }
+
+        for dir in self.dirs.values_mut() {
+            if let Some(path) = rebase(dir) {
+                *dir = path;
+            }
+        }
+        self.pending = self
+            .pending
+            .drain()
+            .map(|(path, time)| (rebase(&path).unwrap_or(path), time))
+            .collect();
+        for entry in WalkDir::new(to).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            if let Some(name) = self.object_at(entry.path())? {
+                let rest = entry.path().strip_prefix(to)?;
+                self.records.push(Record::Moved {
+                    name,
+                    from: from.join(rest),
+                    to: entry.path().to_path_buf(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the paths that were moved out of the working tree.
+    fn moved_away(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let cookies: Vec<u32> = self
+            .moved
+            .iter()
+            .filter(|(_, (_, _, time))| now.duration_since(*time) > TICK * 2)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        for cookie in cookies {
+            let (path, is_dir, _) = self.moved.remove(&cookie).unwrap();
+            self.pending
+                .retain(|pending, _| !pending.starts_with(&path));
+            if !is_dir {
+                continue;
+            }
+            let gone: Vec<WatchDescriptor> = self
+                .dirs
+                .iter()
+                .filter(|(_, dir)| dir.starts_with(&path))
+                .map(|(wd, _)| wd.clone())
+                .collect();
+            for wd in gone {
+                self.dirs.remove(&wd);
+                // The directory may already be gone, along with its watch.
+                let _ = self.inotify.watches().remove(wd);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the new files that have been left alone for long enough.
+    fn add_stable(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let mut stable: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|&(_, time)| now.duration_since(*time) >= self.quiet)
+            .map(|(path, _)| path.clone())
+            .collect();
+        stable.sort();
+
+        for path in stable {
+            self.pending.remove(&path);
+            let is_file = path
+                .symlink_metadata()
+                .map(|metadata| metadata.file_type().is_file())
+                .unwrap_or(false);
+            if !is_file || self.object_at(&path)?.is_some() {
+                continue;
+            }
+            if let Err(e) = self.repo.add(vec![&path], None) {
+                eprintln!("warning: {}: {}", path.display(), e);
+                continue;
+            }
+            self.index = None;
+            if let Some(name) = self.object_at(&path)? {
+                self.records.push(Record::Added { name, path });
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the changes seen so far to the files of the manifest that
+    /// list the objects, and keeps the manifest in the history.
+    fn record(&mut self) -> Result<()> {
+        if self.records.is_empty() {
+            return Ok(());
+        }
+        let path = self.repo.manifest_path();
+        let index = Index::from(&self.root)?;
+        let actual = State::from_path(&self.root, &index)?;
+
+        let root = &self.root;
+        let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_path_buf();
+        let mut messages = Vec::new();
+        let mut moved = Vec::new();
+        for record in self.records.drain(..) {
+            let (name, from, to) = match record {
+                Record::Added { ref name, ref path } => {
+                    messages.push(format!("added '{}' at {}", name, relative(path).display()));
+                    (name, None, relative(path))
+                }
+                Record::Moved {
+                    ref name,
+                    ref from,
+                    ref to,
+                } => {
+                    println!("move: {} -> {}", from.display(), to.display());
+                    messages.push(format!(
+                        "moved '{}' from {} to {}",
+                        name,
+                        relative(from).display(),
+                        relative(to).display()
+                    ));
+                    (name, Some(relative(from)), relative(to))
+                }
+            };
+
+            // An object missing from the manifest keeps the paths it has,
+            // which the manifest now has to list.
+            let mut owners = edit::owners(&path, root, name)?;
+            if owners.is_empty() {
+                owners.push(Owner {
+                    file: path.clone(),
+                    prefix: PathBuf::new(),
+                    paths: actual
+                        .inner
+                        .get(name)
+                        .map(|paths| paths.iter().map(|path| relative(path)).collect())
+                        .unwrap_or_default(),
+                });
+            }
+            let mut changed = BTreeSet::new();
+            if let Some(ref from) = from {
+                for (i, owner) in owners.iter_mut().enumerate() {
+                    if owner.paths.remove(from) {
+                        changed.insert(i);
+                    }
+                }
+                moved.push((name.clone(), from.clone()));
+            }
+
+            // The new path is given by the file that gave the old one when
+            // it can be, or else by the first that can, or the manifest.
+            let target = changed
+                .iter()
+                .cloned()
+                .find(|&i| to.starts_with(&owners[i].prefix))
+                .or_else(|| {
+                    owners
+                        .iter()
+                        .position(|owner| to.starts_with(&owner.prefix))
+                })
+                .unwrap_or_else(|| {
+                    owners.push(Owner {
+                        file: path.clone(),
+                        prefix: PathBuf::new(),
+                        paths: BTreeSet::new(),
+                    });
+                    owners.len() - 1
+                });
+            owners[target].paths.insert(to);
+            changed.insert(target);
+
+            for i in changed {
+                let owner = &owners[i];
+                // An entry left without paths is dropped, as the object
+                // is still listed where its new path is.
+                let new = if owner.paths.is_empty() && i != target {
+                    None
+                } else {
+                    Some((name.as_str(), &owner.paths))
+                };
+                if let Err(e) = edit::replace(&owner.file, &owner.prefix, name, new) {
+                    eprintln!("warning: {}", e);
+                }
+            }
+        }
+        let manifest = Manifest::from_file(&path)?;
+        history::record(&self.root, &manifest, &messages.join(", "))?;
+
+        // A path moved away from is still desired when a rule or a
+        // fragment gives it, and `apply` would link the object there again.
+        let catalog = index.catalog(&self.repo.metadata())?;
+        let desired = State::from_manifest(&Manifest::load(&path, &self.root)?, &catalog)?;
+        for (name, from) in moved {
+            if desired
+                .inner
+                .get(&name)
+                .is_some_and(|paths| paths.contains(&from))
+            {
+                eprintln!(
+                    "warning: '{}' is still given {} by a rule or fragment",
+                    name,
+                    from.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watch_ignore_matches() {
+        let arg1 = "# downloads\n*.part\n/incoming/\ncache/\nsrc/**/*.tmp\n\n";
+        let result = Ignore::parse(arg1).unwrap();
+
+        assert!(result.matches(Path::new("a/b/movie.part"), false));
+        assert!(result.matches(Path::new("incoming/new.jpg"), false));
+        assert!(!result.matches(Path::new("a/incoming/new.jpg"), false));
+        assert!(result.matches(Path::new("a/cache"), true));
+        assert!(!result.matches(Path::new("a/cache"), false));
+        assert!(result.matches(Path::new("a/cache/page.png"), false));
+        assert!(result.matches(Path::new("src/a/b/x.tmp"), false));
+        assert!(!result.matches(Path::new("a/src/x.tmp"), false));
+        assert!(!result.matches(Path::new("a/movie.mkv"), false));
+        assert!(result.matches(Path::new(".hoard/objects"), true));
+        assert!(result.matches(Path::new("a/.hoard.json"), false));
+        assert!(Ignore::default().matches(Path::new(".hoardignore"), false));
+    }
+}